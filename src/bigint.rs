@@ -0,0 +1,279 @@
+// 多倍長整数による中間表現 (逆ポーランド記法) の評価
+//
+// f64 では正確に表現できない大きな整数 (e.g. `Factorial(30)`) を、誤差なく計算するために使用する。
+// 整数のみを扱うため、割り切れない除算や整数以外の値を含む数式はエラーとなる。
+
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, One, Signed, ToPrimitive, Zero};
+
+use crate::parser::Value;
+
+#[derive(Debug, PartialEq)]
+pub struct BigIntError {
+    pub msg: String,
+}
+
+impl BigIntError {
+    fn new(msg: &str) -> BigIntError {
+        BigIntError {
+            msg: msg.to_string(),
+        }
+    }
+}
+
+/// 逆ポーランド記法に変換された数式を多倍長整数で評価する
+///
+/// 変数の値は `resolve` で取得する。使用できる関数は `Add`, `Sub`, `Mul`, `Mod`, `Factorial` のみである
+pub fn execute(
+    values: &[Value],
+    resolve: impl Fn(&str) -> Option<f64>,
+) -> Result<BigInt, BigIntError> {
+    let mut stack: Vec<BigInt> = vec![];
+
+    for value in values {
+        match value {
+            Value::Number(num) => stack.push(to_bigint(*num)?),
+            Value::Variable(v) => match resolve(v) {
+                Some(num) => stack.push(to_bigint(num)?),
+                None => {
+                    return Err(BigIntError::new(&format!(
+                        "error: unknown variable, {:?}",
+                        v
+                    )))
+                }
+            },
+            Value::Function(f, arity) => {
+                if stack.len() < *arity {
+                    return Err(BigIntError::new("error: syntax error"));
+                }
+                let args = stack.split_off(stack.len() - arity);
+                stack.push(call(f, args)?);
+            }
+            Value::Str(string) => {
+                return Err(BigIntError::new(&format!(
+                    "error: string is not supported in integer mode, {:?}",
+                    string
+                )))
+            }
+            Value::KeywordFunction(f, _) => {
+                return Err(BigIntError::new(&format!(
+                    "error: function is not supported in integer mode, {:?}",
+                    f
+                )))
+            }
+            Value::Negate => {
+                let v = stack.pop().ok_or(BigIntError::new("error: syntax error"))?;
+                stack.push(-v);
+            }
+            _ => {
+                let v2 = stack.pop().ok_or(BigIntError::new("error: syntax error"))?;
+                let v1 = stack.pop().ok_or(BigIntError::new("error: syntax error"))?;
+                stack.push(calc(v1, v2, value)?);
+            }
+        }
+    }
+
+    if stack.len() == 1 {
+        Ok(stack.pop().unwrap())
+    } else {
+        Err(BigIntError::new("error: syntax error"))
+    }
+}
+
+fn to_bigint(num: f64) -> Result<BigInt, BigIntError> {
+    if num.fract() != 0.0 {
+        return Err(BigIntError::new(&format!(
+            "error: not an integer, {:?}",
+            num
+        )));
+    }
+
+    BigInt::from_f64(num)
+        .ok_or_else(|| BigIntError::new(&format!("error: not an integer, {:?}", num)))
+}
+
+fn from_bool(b: bool) -> BigInt {
+    if b {
+        BigInt::one()
+    } else {
+        BigInt::zero()
+    }
+}
+
+fn calc(v1: BigInt, v2: BigInt, operator: &Value) -> Result<BigInt, BigIntError> {
+    match operator {
+        Value::Plus => Ok(v1 + v2),
+        Value::Minus => Ok(v1 - v2),
+        Value::Asterisk => Ok(v1 * v2),
+        // 割り切れる場合のみ許可する
+        Value::Slash => {
+            if v2.is_zero() {
+                Err(BigIntError::new("error: division by zero"))
+            } else if !(&v1 % &v2).is_zero() {
+                Err(BigIntError::new(&format!(
+                    "error: non-integer result, {} / {}",
+                    v1, v2
+                )))
+            } else {
+                Ok(v1 / v2)
+            }
+        }
+        Value::Percent => {
+            if v2.is_zero() {
+                Err(BigIntError::new("error: division by zero"))
+            } else {
+                Ok(v1 % v2)
+            }
+        }
+        // 負の無限大方向に丸める (f64 での評価と同じ)
+        Value::Backslash => {
+            if v2.is_zero() {
+                Err(BigIntError::new("error: division by zero"))
+            } else {
+                let remainder = &v1 % &v2;
+                let quotient = v1 / &v2;
+                if !remainder.is_zero() && remainder.is_negative() != v2.is_negative() {
+                    Ok(quotient - 1)
+                } else {
+                    Ok(quotient)
+                }
+            }
+        }
+        Value::Power => match v2.to_u32() {
+            Some(exp) => Ok(v1.pow(exp)),
+            None => Err(BigIntError::new(&format!(
+                "error: exponent must be a non-negative integer within u32, but provide {}",
+                v2
+            ))),
+        },
+        Value::Equal => Ok(from_bool(v1 == v2)),
+        Value::NotEqual => Ok(from_bool(v1 != v2)),
+        Value::GreaterThan => Ok(from_bool(v1 > v2)),
+        Value::GreaterThanOrEqual => Ok(from_bool(v1 >= v2)),
+        Value::LessThan => Ok(from_bool(v1 < v2)),
+        Value::LessThanOrEqual => Ok(from_bool(v1 <= v2)),
+        Value::LogicalXor => Ok(from_bool(v1.is_zero() != v2.is_zero())),
+        Value::LogicalAnd => Ok(from_bool(!v1.is_zero() && !v2.is_zero())),
+        Value::LogicalOr => Ok(from_bool(!v1.is_zero() || !v2.is_zero())),
+        _ => Err(BigIntError::new(&format!(
+            "error: unexpected token, {:?}",
+            operator
+        ))),
+    }
+}
+
+fn call(name: &str, args: Vec<BigInt>) -> Result<BigInt, BigIntError> {
+    let expected = match name {
+        "Add" | "Sub" | "Mul" | "Mod" => 2,
+        "Factorial" => 1,
+        _ => {
+            return Err(BigIntError::new(&format!(
+                "error: function is not supported in integer mode, {:?}",
+                name
+            )))
+        }
+    };
+    if args.len() != expected {
+        return Err(BigIntError::new(&format!(
+            "error: args count of {:?} is invalid, expected {:?} but provide {:?}",
+            name,
+            expected,
+            args.len()
+        )));
+    }
+
+    let mut args = args.into_iter();
+    let a1 = args.next().unwrap();
+    match name {
+        "Add" => Ok(a1 + args.next().unwrap()),
+        "Sub" => Ok(a1 - args.next().unwrap()),
+        "Mul" => Ok(a1 * args.next().unwrap()),
+        "Mod" => calc(a1, args.next().unwrap(), &Value::Percent),
+        _ => factorial(&a1),
+    }
+}
+
+fn factorial(n: &BigInt) -> Result<BigInt, BigIntError> {
+    match n.to_u32() {
+        Some(n) => Ok((1..=n).fold(BigInt::one(), |acc, i| acc * i)),
+        None if n.is_negative() => Err(BigIntError::new(&format!(
+            "error: argument of \"Factorial\" must be non-negative, but provide {}",
+            n
+        ))),
+        None => Err(BigIntError::new(&format!(
+            "error: argument of \"Factorial\" is too large, {}",
+            n
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execute() {
+        let success_data = [
+            // 2 ** 100
+            (
+                vec![Value::Number(2.0), Value::Number(100.0), Value::Power],
+                "1267650600228229401496703205376",
+            ),
+            // Factorial(25) / Factorial(24)
+            (
+                vec![
+                    Value::Number(25.0),
+                    Value::Function("Factorial".to_string(), 1),
+                    Value::Number(24.0),
+                    Value::Function("Factorial".to_string(), 1),
+                    Value::Slash,
+                ],
+                "25",
+            ),
+            // -7 \ 2
+            (
+                vec![Value::Number(-7.0), Value::Number(2.0), Value::Backslash],
+                "-4",
+            ),
+            // 6 \ -3
+            (
+                vec![Value::Number(6.0), Value::Number(-3.0), Value::Backslash],
+                "-2",
+            ),
+            // x - 10
+            (
+                vec![
+                    Value::Variable("x".to_string()),
+                    Value::Number(10.0),
+                    Value::Minus,
+                ],
+                "-7",
+            ),
+        ];
+        let resolve = |name: &str| if name == "x" { Some(3.0) } else { None };
+        for (values, expected) in success_data {
+            assert_eq!(
+                execute(&values, resolve).map(|n| n.to_string()),
+                Ok(expected.to_string())
+            );
+        }
+
+        let failure_data = [
+            vec![Value::Number(1.5)],
+            vec![Value::Variable("y".to_string())],
+            vec![Value::Number(1.0), Value::Number(2.0), Value::Slash],
+            vec![Value::Number(1.0), Value::Number(0.0), Value::Percent],
+            vec![Value::Number(1.0), Value::Number(0.0), Value::Backslash],
+            vec![Value::Number(2.0), Value::Number(-1.0), Value::Power],
+            vec![
+                Value::Number(-1.0),
+                Value::Function("Factorial".to_string(), 1),
+            ],
+            vec![Value::Number(1.0), Value::Function("Sin".to_string(), 1)],
+            vec![Value::Number(1.0), Value::Plus],
+        ];
+        for values in failure_data {
+            assert!(execute(&values, resolve).is_err(), "{:?}", values);
+        }
+    }
+}
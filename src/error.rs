@@ -0,0 +1,459 @@
+// エラーの原因を表すコードと、コードからのエラーメッセージの生成
+
+use std::fmt;
+
+use crate::lexer::Token;
+use crate::parser::Value;
+
+/// エラーの原因を表すコード
+///
+/// 数値・文字などの `Copy` な値のみを持ち、メモリを確保しない。
+/// 名前などの数式中の文字列は持たず、メッセージを生成する際にエラーの位置 (入力文字列・トークン・中間表現) から取り出す
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// 数式の構造が正しくない
+    Syntax,
+    /// 登録した関数・演算子が返したエラー (メッセージはそのまま使用する)
+    Custom,
+
+    // 字句解析
+    /// 引数の区切り文字として使用できない文字
+    InvalidArgumentSeparator(char),
+    /// 置くことのできない位置にある空白 (空白の位置)
+    UnexpectedWhitespace(usize),
+    UnexpectedChar(char),
+    UnexpectedEndOfLine,
+    UnexpectedCharAfterCurrencySymbol(char),
+    UnexpectedCharAfterFirstArgument(char),
+    UnexpectedCharAfterProperty(char),
+    UnexpectedCharAfterEqual(char),
+    /// 閉じられていない文字列 (開始位置)
+    UnterminatedStringLiteral(usize),
+    /// 閉じられていないブロックコメント (開始位置)
+    UnterminatedBlockComment(usize),
+    /// 識別子が長すぎる (最大文字数)
+    IdentifierTooLong(usize),
+    EmptyProperty,
+    /// 指数部に数字がない (数値の開始位置)
+    ExponentHasNoDigits {
+        start: usize,
+    },
+    InvalidNumericString,
+    /// 数値として解釈できない (数値の終了位置)
+    InvalidFloatLiteral {
+        end: usize,
+    },
+
+    // 構文解析
+    StringInExpressionTree,
+    ColonWithoutQuestion,
+    QuestionWithoutColon,
+    UnmatchedParenthesis,
+    /// 関数呼び出しにキーワード引数と位置引数が混在している
+    MixedKeywordArguments,
+    KeywordArgumentOutsideCall,
+    UnexpectedProperty,
+    UnexpectedToken,
+
+    // 評価
+    UnknownVariable,
+    UnknownFunction,
+    /// 引数の数が異なる (期待する数と渡された数)
+    ArgsCount {
+        expected: usize,
+        provided: usize,
+    },
+    KeywordArgumentsNotAccepted,
+    /// キーワード引数の重複 (引数の位置)
+    DuplicateKeywordArgument(usize),
+    /// 存在しないキーワード引数 (引数の位置)
+    UnknownKeywordArgument(usize),
+    /// 条件分岐の関数が範囲外の引数を選んだ (選んだ位置)
+    OutOfRangeArgument(usize),
+    /// `Processor` が直接評価する関数が、それ以外の方法で実行された
+    NotEvaluatedByProcessor,
+    /// 数値が必要な位置に文字列がある
+    ExpectedNumber,
+    /// 評価結果 (最終的な値) が NaN または無限大
+    NonFiniteResult(f64),
+    /// 関数の結果が NaN または無限大
+    NonFiniteFunctionResult(f64),
+    MapSumFunctionName,
+    /// `Eval` の引数が文字列でない (渡された値の型名)
+    EvalArgument(&'static str),
+    EvalBudgetExhausted,
+    ComputedVariableBudgetExhausted,
+    ComputedVariableFailed,
+    /// 被演算子の型 (左辺・右辺の型名) に対して演算子が定義されていない
+    OperatorNotDefined {
+        lhs: &'static str,
+        rhs: &'static str,
+    },
+    /// `strict_types` が有効な場合の、真理値の算術演算
+    BoolInStrictMode(bool),
+    NanOperand(f64, f64),
+    DivisionByZero(f64, f64),
+    Overflow,
+    /// 二項演算の結果が NaN または無限大
+    NonFiniteOperation(f64, f64),
+
+    // 予約関数
+    /// 引数の数が条件を満たさない
+    InvalidArgsCount {
+        function: &'static str,
+        requirement: &'static str,
+        provided: usize,
+    },
+    /// 引数の値が条件を満たさない (`argument` は引数の呼び方)
+    InvalidArgument {
+        function: &'static str,
+        argument: &'static str,
+        requirement: &'static str,
+        provided: f64,
+    },
+    /// 2 つの引数の値の組が条件を満たさない
+    InvalidArguments {
+        function: &'static str,
+        argument: &'static str,
+        requirement: &'static str,
+        provided: (f64, f64),
+    },
+    /// 位置を表す引数が 1 から `max` の整数でない
+    IndexOutOfRange {
+        function: &'static str,
+        max: usize,
+        provided: f64,
+    },
+    NoConditionMatched {
+        function: &'static str,
+    },
+    FunctionDivisionByZero {
+        function: &'static str,
+        provided: (f64, f64),
+    },
+    FactorialTooLarge(f64),
+    ZeroToThePowerOfZero {
+        function: &'static str,
+    },
+
+    // 木構造の変形
+    OperatorNotDifferentiable,
+    FunctionNotDifferentiable,
+
+    // テンプレートの展開
+    UnclosedPlaceholder,
+    UnknownPlaceholder,
+
+    // 関数・変数の登録、その他
+    /// 登録できる数の上限を超えた (登録するものの種類と上限)
+    TooMany {
+        kind: &'static str,
+        limit: usize,
+    },
+    UnknownArray,
+    /// 列の長さが出力の長さと異なる
+    ColumnLength {
+        length: usize,
+        expected: usize,
+    },
+}
+
+impl ErrorCode {
+    /// `subject` から名前などを取り出して、エラーメッセージを生成する
+    pub(crate) fn message(self, subject: Subject<'_>) -> String {
+        Message {
+            code: self,
+            subject,
+        }
+        .to_string()
+    }
+}
+
+/// エラーメッセージに含める、エラーの位置にあるもの
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Subject<'s> {
+    None,
+    /// 名前などの文字列 (`ErrorCode::Custom` の場合はメッセージ)
+    Text(&'s str),
+    Token(&'s Token),
+    Value(&'s Value),
+}
+
+impl<'s> Subject<'s> {
+    /// 変数名・関数名など (ない場合は空文字列)
+    fn name(self) -> &'s str {
+        match self {
+            Subject::Text(name) => name,
+            Subject::Token(Token::Property(name))
+            | Subject::Value(
+                Value::Variable(name) | Value::Function(name, _) | Value::KeywordFunction(name, _),
+            ) => name,
+            _ => "",
+        }
+    }
+
+    /// キーワード引数の関数の、`index` 番目の引数名 (ない場合は空文字列)
+    fn keyword(self, index: usize) -> &'s str {
+        match self {
+            Subject::Value(Value::KeywordFunction(_, names)) => {
+                names.get(index).map_or("", |name| name.as_str())
+            }
+            _ => "",
+        }
+    }
+}
+
+/// トークン・中間表現の値はそのまま、文字列は名前として書き込む
+struct Operand<'s>(Subject<'s>);
+
+impl fmt::Debug for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Subject::None => Ok(()),
+            Subject::Text(text) => write!(f, "{:?}", text),
+            Subject::Token(token) => write!(f, "{:?}", token),
+            Subject::Value(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+struct Message<'s> {
+    code: ErrorCode,
+    subject: Subject<'s>,
+}
+
+impl fmt::Display for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let subject = self.subject;
+        let (name, operand) = (subject.name(), Operand(subject));
+        match self.code {
+            ErrorCode::Syntax => write!(f, "error: syntax error"),
+            ErrorCode::Custom => write!(f, "{}", name),
+
+            ErrorCode::InvalidArgumentSeparator(c) => {
+                write!(f, "error: invalid argument separator, {:?}", c)
+            }
+            ErrorCode::UnexpectedWhitespace(pos) => {
+                write!(f, "error: unexpected whitespace, at {:?}", pos)
+            }
+            ErrorCode::UnexpectedChar(c) => write!(f, "error: unexpected char, {:?}", c),
+            ErrorCode::UnexpectedEndOfLine => write!(f, "error: unexpected end of line"),
+            ErrorCode::UnexpectedCharAfterCurrencySymbol(c) => {
+                write!(f, "error: unexpected char after currency symbol, {:?}", c)
+            }
+            ErrorCode::UnexpectedCharAfterFirstArgument(c) => {
+                write!(f, "error: unexpected char after first argument, {:?}", c)
+            }
+            ErrorCode::UnexpectedCharAfterProperty(c) => {
+                write!(f, "error: unexpected char after property, {:?}", c)
+            }
+            ErrorCode::UnexpectedCharAfterEqual(c) => {
+                write!(f, "error: unexpected char after equal, {:?}", c)
+            }
+            ErrorCode::UnterminatedStringLiteral(start) => {
+                write!(f, "error: unterminated string literal, at {:?}", start)
+            }
+            ErrorCode::UnterminatedBlockComment(start) => {
+                write!(f, "error: unterminated block comment, at {:?}", start)
+            }
+            ErrorCode::IdentifierTooLong(max) => {
+                write!(f, "error: identifier exceeds max length, {:?}", max)
+            }
+            ErrorCode::EmptyProperty => write!(f, "error: property is empty"),
+            ErrorCode::ExponentHasNoDigits { .. } => {
+                write!(f, "error: exponent has no digits, {:?}", name)
+            }
+            ErrorCode::InvalidNumericString => write!(f, "error: invalid numeric string"),
+            // 数値の文字列を解析し直して、その理由をメッセージとする
+            ErrorCode::InvalidFloatLiteral { .. } => match name.parse::<f64>() {
+                Err(e) => write!(f, "error: {}", e),
+                Ok(_) => write!(f, "error: invalid float literal"),
+            },
+
+            ErrorCode::StringInExpressionTree => {
+                write!(f, "error: string is not supported in expression tree")
+            }
+            ErrorCode::ColonWithoutQuestion => {
+                write!(f, "error: ':' without matching '?' in ternary operator")
+            }
+            ErrorCode::QuestionWithoutColon => {
+                write!(f, "error: '?' without matching ':' in ternary operator")
+            }
+            ErrorCode::UnmatchedParenthesis => write!(f, "error: parenthesis is not matched"),
+            ErrorCode::MixedKeywordArguments => write!(
+                f,
+                "error: keyword and positional arguments are mixed, {:?}",
+                name
+            ),
+            ErrorCode::KeywordArgumentOutsideCall => {
+                write!(f, "error: keyword argument outside of function call")
+            }
+            ErrorCode::UnexpectedProperty => {
+                write!(f, "error: unexpected property, token: {:?}", operand)
+            }
+            ErrorCode::UnexpectedToken => write!(f, "error: unexpected token, {:?}", operand),
+
+            ErrorCode::UnknownVariable => write!(f, "error: unknown variable, {:?}", name),
+            ErrorCode::UnknownFunction => write!(f, "error: unknown function, {:?}", name),
+            ErrorCode::ArgsCount { expected, provided } => write!(
+                f,
+                "error: args count of {:?} expects {:?}, but provide {:?}",
+                name, expected, provided
+            ),
+            ErrorCode::KeywordArgumentsNotAccepted => {
+                write!(f, "error: {:?} does not accept keyword arguments", name)
+            }
+            ErrorCode::DuplicateKeywordArgument(index) => write!(
+                f,
+                "error: keyword argument of {:?} is duplicated, {:?}",
+                name,
+                subject.keyword(index)
+            ),
+            ErrorCode::UnknownKeywordArgument(index) => write!(
+                f,
+                "error: unknown keyword argument of {:?}, {:?}",
+                name,
+                subject.keyword(index)
+            ),
+            ErrorCode::OutOfRangeArgument(index) => write!(
+                f,
+                "error: {:?} selected out of range argument, {:?}",
+                name, index
+            ),
+            ErrorCode::NotEvaluatedByProcessor => {
+                write!(f, "error: {:?} must be evaluated by the processor", name)
+            }
+            ErrorCode::ExpectedNumber => {
+                write!(f, "error: expected number, but provide string {:?}", name)
+            }
+            ErrorCode::NonFiniteResult(num) => write!(
+                f,
+                "error: non-finite result, {:?} produced by {:?}",
+                num, operand
+            ),
+            ErrorCode::NonFiniteFunctionResult(num) => {
+                write!(f, "error: non-finite result of {:?}, {:?}", name, num)
+            }
+            ErrorCode::MapSumFunctionName => write!(
+                f,
+                "error: first argument of \"MapSum\" must be a function name string"
+            ),
+            ErrorCode::EvalArgument(type_name) => write!(
+                f,
+                "error: argument of \"Eval\" must be a string, but provide {}",
+                type_name
+            ),
+            ErrorCode::EvalBudgetExhausted => write!(
+                f,
+                "error: recursion budget of \"Eval\" is exhausted, {:?}",
+                name
+            ),
+            ErrorCode::ComputedVariableBudgetExhausted => write!(
+                f,
+                "error: recursion budget of computed variable is exhausted, {:?}",
+                name
+            ),
+            ErrorCode::ComputedVariableFailed => {
+                write!(f, "error: failed to compute variable, {:?}", name)
+            }
+            ErrorCode::OperatorNotDefined { lhs, rhs } => write!(
+                f,
+                "error: operator {:?} is not defined for {} and {}",
+                operand, lhs, rhs
+            ),
+            ErrorCode::BoolInStrictMode(b) => write!(
+                f,
+                "error: operator {:?} is not defined for bool in strict mode, {:?}",
+                operand, b
+            ),
+            ErrorCode::NanOperand(v1, v2) => {
+                write!(f, "error: NaN operand, {:?} {:?} {:?}", v1, operand, v2)
+            }
+            ErrorCode::DivisionByZero(v1, v2) => {
+                write!(f, "error: division by zero, {:?} {:?} {:?}", v1, operand, v2)
+            }
+            ErrorCode::Overflow => write!(f, "error: arithmetic overflow"),
+            ErrorCode::NonFiniteOperation(v1, v2) => write!(
+                f,
+                "error: non-finite result, {:?} {:?} {:?}",
+                v1, operand, v2
+            ),
+
+            ErrorCode::InvalidArgsCount {
+                function,
+                requirement,
+                provided,
+            } => write!(
+                f,
+                "error: args count of {:?} must be {}, but provide {:?}",
+                function, requirement, provided
+            ),
+            ErrorCode::InvalidArgument {
+                function,
+                argument,
+                requirement,
+                provided,
+            } => write!(
+                f,
+                "error: {} of {:?} {}, but provide {:?}",
+                argument, function, requirement, provided
+            ),
+            ErrorCode::InvalidArguments {
+                function,
+                argument,
+                requirement,
+                provided: (v1, v2),
+            } => write!(
+                f,
+                "error: {} of {:?} {}, but provide {:?} and {:?}",
+                argument, function, requirement, v1, v2
+            ),
+            ErrorCode::IndexOutOfRange {
+                function,
+                max,
+                provided,
+            } => write!(
+                f,
+                "error: index of {:?} must be an integer from 1 to {:?}, but provide {:?}",
+                function, max, provided
+            ),
+            ErrorCode::NoConditionMatched { function } => {
+                write!(f, "error: no condition of {:?} matched", function)
+            }
+            ErrorCode::FunctionDivisionByZero {
+                function,
+                provided: (v1, v2),
+            } => write!(f, "error: division by zero, {}({:?}, {:?})", function, v1, v2),
+            ErrorCode::FactorialTooLarge(n) => write!(
+                f,
+                "error: argument of \"Factorial\" is too large, {:?} (use Context::evaluate_bigint)",
+                n
+            ),
+            ErrorCode::ZeroToThePowerOfZero { function } => {
+                write!(f, "error: {:?} of 0 to the power of 0 is undefined", function)
+            }
+
+            ErrorCode::OperatorNotDifferentiable => {
+                write!(f, "error: operator is not differentiable, {:?}", operand)
+            }
+            ErrorCode::FunctionNotDifferentiable => {
+                write!(f, "error: function is not differentiable, {:?}", name)
+            }
+
+            ErrorCode::UnclosedPlaceholder => write!(f, "error: unclosed placeholder"),
+            ErrorCode::UnknownPlaceholder => {
+                write!(f, "error: placeholder is not found, {:?}", name)
+            }
+
+            ErrorCode::TooMany { kind, limit } => {
+                write!(f, "error: too many {}, the limit is {:?}", kind, limit)
+            }
+            ErrorCode::UnknownArray => write!(f, "error: unknown array, {:?}", name),
+            ErrorCode::ColumnLength { length, expected } => write!(
+                f,
+                "error: length of column {:?} is {:?}, but output length is {:?}",
+                name, length, expected
+            ),
+        }
+    }
+}
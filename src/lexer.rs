@@ -1,8 +1,15 @@
+use std::ops::Range;
+
+use crate::error::{ErrorCode, Subject};
+
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Token {
     WhiteSpace,
     Number(f64),
     Property(String),
+    /// "..." で囲まれた文字列 (MapSum に渡す関数名)
+    StringLiteral(String),
 
     // Operators
     Plus,               // +
@@ -10,99 +17,314 @@ pub enum Token {
     Asterisk,           // *
     Slash,              // /
     Percent,            // %
+    Backslash,          // \ (切り捨て除算)
+    DoubleAsterisk,     // **
+    Caret,              // ^ (べき乗。** と同じ)
     Equal,              // ==
     NotEqual,           // !=
+    ApproxEqual,        // ~= (許容誤差の範囲で等しい)
     LessThan,           // <
     GreaterThan,        // >
     LessThanOrEqual,    // <=
     GreaterThanOrEqual, // >=
+    CaretCaret,         // ^^ (論理 XOR)
+    And,                // && (論理 AND)
+    Or,                 // || (論理 OR)
+    Negate,             // - (符号反転。数字が続かない前置の '-')
+    Question,           // ? (三項演算子 cond ? a : b)
+    Colon,              // : (三項演算子 cond ? a : b)
+    Assign,             // = (キーワード引数)
 
     // Other Symbols
     LeftParenthesis,  // (
     RightParenthesis, // )
-    Comma,            // ,
+    Comma,            // , (引数の区切り文字。LexerOptions::argument_separator で変更できる)
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct LexerError {
-    pub msg: String,
+    pub code: ErrorCode,
+    /// エラーの原因となった文字の、入力文字列中のバイト位置 (入力の途中で終わった場合は入力の末尾)
+    pub pos: usize,
 }
 
 impl LexerError {
-    fn new(msg: &str) -> LexerError {
-        LexerError {
-            msg: msg.to_string(),
+    fn new(code: ErrorCode, pos: usize) -> LexerError {
+        LexerError { code, pos }
+    }
+
+    /// 字句解析した入力文字列 `input` から、エラーメッセージを生成する
+    pub fn message(&self, input: &str) -> String {
+        // 数値のエラーは、入力中の数値の文字列をメッセージに含める
+        let text = match self.code {
+            ErrorCode::ExponentHasNoDigits { start } => input.get(start..self.pos),
+            ErrorCode::InvalidFloatLiteral { end } => input.get(self.pos..end),
+            _ => None,
+        };
+        self.code.message(text.map_or(Subject::None, Subject::Text))
+    }
+
+    /// `input` が途中で終わっていることによるエラーか (続きを入力すれば解消しうるか)
+    ///
+    /// 入力の末尾で発生したエラーと、閉じられていない文字列・コメントのエラーが該当する
+    pub fn is_incomplete(&self, input: &str) -> bool {
+        self.pos == input.len()
+            || matches!(
+                self.code,
+                ErrorCode::UnterminatedStringLiteral(_) | ErrorCode::UnterminatedBlockComment(_)
+            )
+    }
+}
+
+/// 識別子を関数と変数のどちらとして扱うかの規則
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IdentifierConvention {
+    /// 大文字から始まる識別子は関数、小文字から始まる識別子は変数とする
+    #[default]
+    Case,
+    /// 大文字・小文字に関わらず、直後に '(' が続く識別子は関数、それ以外は変数とする (e.g. `foo(1)` は関数、`Foo` は変数)
+    Parenthesis,
+}
+
+/// 字句解析の挙動を変更するオプション
+#[derive(Debug, Clone)]
+pub struct LexerOptions {
+    /// 大文字から始まる識別子の後に '(' が続かない場合、関数ではなく変数として扱う
+    pub uppercase_variables: bool,
+    /// 識別子 (関数名・変数名) の最大文字数
+    pub max_identifier_length: usize,
+    /// 空白を二項演算子・キーワード引数の '=' の前後と ',' の後にのみ許可する
+    ///
+    /// 数式の先頭・末尾の空白は許可する
+    pub strict_whitespace: bool,
+    /// 数値の直前に置くことができる通貨記号 (e.g. '$', '¥')
+    ///
+    /// 通貨記号は読み飛ばされ、数値のみがトークンとなる
+    pub currency_symbols: Vec<char>,
+    /// 関数呼び出しの ')' の直前のカンマ (e.g. `Add(1, 2,)`) を許可する
+    ///
+    /// 末尾のカンマは無視され、引数の数には影響しない
+    pub trailing_commas: bool,
+    /// 関数呼び出しの引数の区切り文字 (e.g. ';' を指定すると `Add(1; 2)` と書ける)
+    ///
+    /// 区切り文字は Token::Comma として扱われる。`,` と `;` 以外を指定した場合はエラーとなる
+    pub argument_separator: char,
+    /// 識別子を関数と変数のどちらとして扱うかの規則
+    pub identifier_convention: IdentifierConvention,
+}
+
+impl Default for LexerOptions {
+    fn default() -> LexerOptions {
+        LexerOptions {
+            uppercase_variables: false,
+            max_identifier_length: 256,
+            strict_whitespace: false,
+            currency_symbols: vec![],
+            trailing_commas: false,
+            argument_separator: ',',
+            identifier_convention: IdentifierConvention::default(),
         }
     }
 }
 
+/// 関数呼び出しの引数の区切り文字として指定できる文字 (他の用途に使われていないもの)
+const ARGUMENT_SEPARATORS: [char; 2] = [',', ';'];
+
+/// トークンと、入力文字列中でそのトークンが占めるバイト位置の範囲
+type Spanned = (Token, Range<usize>);
+
 pub struct Lexer<'a> {
-    /// 読込中の先頭文字列を指す
-    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// 読込中の先頭文字列を指す (バイト位置と文字の組)
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    /// 入力文字列のバイト長 (末尾の位置として使用する)
+    len: usize,
+    options: LexerOptions,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(input: &str) -> Lexer {
+    pub fn new(input: &str) -> Lexer<'_> {
+        Lexer::with_options(input, LexerOptions::default())
+    }
+
+    pub fn with_options(input: &str, options: LexerOptions) -> Lexer<'_> {
         Lexer {
-            chars: input.chars().peekable(),
+            chars: input.char_indices().peekable(),
+            len: input.len(),
+            options,
         }
     }
 
+    /// 別の入力文字列を字句解析できるよう、読込位置を初期化する (オプションは引き継ぐ)
+    ///
+    /// 字句解析の結果のトークン列は呼び出しごとに新しく確保されるため、Lexer 自体が保持するバッファはない
+    pub fn reset(&mut self, input: &'a str) {
+        self.chars = input.char_indices().peekable();
+        self.len = input.len();
+    }
+
     /// 数式の字句解析
     ///
     /// サポートしている数式は以下の通りである
     ///
-    /// - <expr>   ::= <term> [ ('+'|'-'|'%'|'=='|'>'|'<'|'>='|'<=') <term> ]*
-    /// - <term>   ::= <factor> [ ('*'|'/') <factor> ]*
-    /// - <factor> ::= <number> | '(' <expr> ')' | <function> | <variable>
-    /// - <function> :== <property> '(' <expr>, [',' <expr> ]* ')' ← ただし、 property の1文字目は [A-Z]
-    /// - <variable> := <property> ← ただし、1文字目は [a-z]
-    /// - <number> :== ('+'|'-')[0-9]
-    /// - <property> := [a-zA-Z]+
+    /// - <expr>   ::= <term> [ ('+'|'-'|'%'|'=='|'>'|'<'|'>='|'<='|'^^'|'&&'|'||'|'?'|':') <term> ]*
+    /// - <term>   ::= <factor> [ ('*'|'/'|'\'|'**') <factor> ]*
+    /// - <factor> ::= <number> | <string> | '(' <expr> ')' | <function> | <variable> | '-' <factor>
+    /// - <function> :== <property> '(' [ <argument> [',' <argument> ]* ] ')' ← ただし、 property の1文字目は [A-Z] (IdentifierConvention::Parenthesis の場合は任意)
+    /// - <argument> ::= [ <property> '=' ] <expr>
+    /// - <variable> := <property> ← ただし、1文字目は [a-z] (uppercase_variables が有効な場合、または IdentifierConvention::Parenthesis の場合は '(' が続かない [A-Z] も可。組み込み定数 PI, E, TAU は常に可)
+    /// - <number> :== ('+'|'-')[0-9] [ ('e'|'E') ('+'|'-')[0-9]+ ]
+    /// - <string> ::= '"' [^"]* '"'
+    /// - <property> := [a-zA-Z][a-zA-Z0-9_]*
+    ///
+    /// 空白を置ける位置には、コメント (`// ...`, `/* ... */`) も置くことができる
     pub fn tokenize(&mut self) -> Result<Vec<Token>, LexerError> {
-        print!("tokenize");
-        let mut tokens = vec![];
-        for t in self.expr()? {
-            // Whitespace は捨てる
-            if t != Token::WhiteSpace {
-                tokens.push(t);
-            }
+        Ok(self
+            .tokenize_with_spans()?
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect())
+    }
+
+    /// 数式を字句解析し、各トークンを入力文字列中のバイト位置の範囲とともに返す
+    pub fn tokenize_with_spans(&mut self) -> Result<Vec<Spanned>, LexerError> {
+        let separator = self.options.argument_separator;
+        if !ARGUMENT_SEPARATORS.contains(&separator) {
+            return Err(LexerError::new(
+                ErrorCode::InvalidArgumentSeparator(separator),
+                0,
+            ));
         }
 
-        if self.chars.peek().is_some() {
+        let all_tokens = self.expr()?;
+
+        if self.peek().is_some() {
             // 探索が終わっていなければなにかがおかしいので解析エラーとする
             // FIXME: expr 内での判定がおそらく良くないので、修正したい
-            Err(LexerError::new("error: syntax error"))
-        } else {
-            Ok(tokens)
+            return Err(LexerError::new(ErrorCode::Syntax, self.pos()));
         }
+
+        if self.options.strict_whitespace {
+            Lexer::check_whitespace(&all_tokens)?;
+        }
+
+        // Whitespace は捨てる
+        Ok(all_tokens
+            .into_iter()
+            .filter(|t| t.0 != Token::WhiteSpace)
+            .collect())
+    }
+
+    /// 空白が二項演算子の前後・',' の後・数式の先頭と末尾以外にあればエラーとする
+    fn check_whitespace(tokens: &[Spanned]) -> Result<(), LexerError> {
+        for (i, (token, span)) in tokens.iter().enumerate() {
+            if *token != Token::WhiteSpace {
+                continue;
+            }
+
+            let prev = tokens[..i].iter().rev().find(|t| t.0 != Token::WhiteSpace);
+            let next = tokens[i + 1..].iter().find(|t| t.0 != Token::WhiteSpace);
+            let allowed = match (prev, next) {
+                (None, _) | (_, None) => true,
+                (Some((p, _)), Some((n, _))) => {
+                    Lexer::is_binary_operator(p)
+                        || *p == Token::Comma
+                        || Lexer::is_binary_operator(n)
+                }
+            };
+
+            if !allowed {
+                return Err(LexerError::new(
+                    ErrorCode::UnexpectedWhitespace(span.start),
+                    span.start,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 前後に空白を置くことができる演算子 (二項演算子とキーワード引数の '=')
+    fn is_binary_operator(token: &Token) -> bool {
+        matches!(
+            token,
+            Token::Plus
+                | Token::Minus
+                | Token::Asterisk
+                | Token::Slash
+                | Token::Percent
+                | Token::Backslash
+                | Token::DoubleAsterisk
+                | Token::Caret
+                | Token::Equal
+                | Token::NotEqual
+                | Token::ApproxEqual
+                | Token::LessThan
+                | Token::GreaterThan
+                | Token::LessThanOrEqual
+                | Token::GreaterThanOrEqual
+                | Token::CaretCaret
+                | Token::And
+                | Token::Or
+                | Token::Question
+                | Token::Colon
+                | Token::Assign
+        )
     }
 
     /// 数式の解析
     /// <expr> ::= <term> [ ('+'|'-') <term> ]*
-    fn expr(&mut self) -> Result<Vec<Token>, LexerError> {
-        print!("expr");
-
+    fn expr(&mut self) -> Result<Vec<Spanned>, LexerError> {
         let mut tokens = self.term()?;
 
         loop {
-            let w = self.read_whitespace_tokens();
+            let w = self.read_whitespace_tokens()?;
             tokens = Lexer::add_tokens(tokens, w);
 
-            // self.chars.peek(), self.chars.next() あたりで怒られるので仕方なく
-            let mut chars = self.chars.clone();
-            let cc = chars.peek();
-            match cc {
+            let start = self.pos();
+            match self.peek() {
                 Some(c) => match c {
-                    '>' | '<' | '=' | '!' => {
-                        self.chars.next();
-                        let token = self.read_comparison_operator(&c)?;
-                        tokens.push(token);
+                    '>' | '<' | '=' | '!' | '~' => {
+                        self.next();
+                        let token = self.read_comparison_operator(&c, start)?;
+                        tokens.push((token, start..self.pos()));
                         tokens = Lexer::add_tokens(tokens, self.term()?);
                     }
                     '+' | '-' => {
-                        tokens.push(Lexer::operator_to_token(&c.to_string())?);
-                        self.chars.next();
+                        self.next();
+                        tokens.push((
+                            Lexer::operator_to_token(&c.to_string(), start)?,
+                            start..self.pos(),
+                        ));
+                        tokens = Lexer::add_tokens(tokens, self.term()?);
+                    }
+                    '^' => {
+                        self.next();
+                        if self.next() != Some('^') {
+                            return Err(LexerError::new(ErrorCode::UnexpectedChar('^'), start));
+                        }
+                        tokens.push((Token::CaretCaret, start..self.pos()));
+                        tokens = Lexer::add_tokens(tokens, self.term()?);
+                    }
+                    '&' | '|' => {
+                        // 同じ文字が 2 つ続く場合のみ演算子とする ('&', '|' 単独はエラー)
+                        self.next();
+                        if self.next() != Some(c) {
+                            return Err(LexerError::new(ErrorCode::UnexpectedChar(c), start));
+                        }
+                        tokens.push((
+                            Lexer::operator_to_token(&c.to_string().repeat(2), start)?,
+                            start..self.pos(),
+                        ));
+                        tokens = Lexer::add_tokens(tokens, self.term()?);
+                    }
+                    '?' | ':' => {
+                        // 三項演算子 (cond ? a : b)。'?' と ':' の対応は構文解析で確認する
+                        let token = if c == '?' {
+                            Token::Question
+                        } else {
+                            Token::Colon
+                        };
+                        tokens.push(self.read_symbol(token));
                         tokens = Lexer::add_tokens(tokens, self.term()?);
                     }
                     _ => {
@@ -119,20 +341,43 @@ impl<'a> Lexer<'a> {
     }
 
     /// 項の解析
-    /// <term> ::= <factor> [ ('*'|'/') <factor> ]*
-    fn term(&mut self) -> Result<Vec<Token>, LexerError> {
-        print!("term");
-
+    /// <term> ::= <factor> [ ('*'|'/'|'\'|'**'|'^') <factor> ]*
+    fn term(&mut self) -> Result<Vec<Spanned>, LexerError> {
         let mut tokens = self.factor()?;
 
         loop {
-            tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens());
+            tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens()?);
 
-            match self.chars.peek() {
+            let start = self.pos();
+            match self.peek() {
                 Some(c) => match c {
-                    '*' | '/' | '%' => {
-                        tokens.push(Lexer::operator_to_token(&c.to_string())?);
-                        self.chars.next();
+                    '*' => {
+                        self.next();
+
+                        // 間に空白を挟まずに '*' が続く場合はべき乗とする
+                        let token = if self.peek() == Some('*') {
+                            self.next();
+                            Token::DoubleAsterisk
+                        } else {
+                            Token::Asterisk
+                        };
+                        tokens.push((token, start..self.pos()));
+
+                        tokens = Lexer::add_tokens(tokens, self.factor()?);
+                    }
+                    '/' | '%' | '\\' => {
+                        self.next();
+                        tokens.push((
+                            Lexer::operator_to_token(&c.to_string(), start)?,
+                            start..self.pos(),
+                        ));
+
+                        tokens = Lexer::add_tokens(tokens, self.factor()?);
+                    }
+                    // '^^' (論理 XOR) は expr で読み込む
+                    '^' if self.peek_second() != Some('^') => {
+                        self.next();
+                        tokens.push((Token::Caret, start..self.pos()));
 
                         tokens = Lexer::add_tokens(tokens, self.factor()?);
                     }
@@ -146,42 +391,82 @@ impl<'a> Lexer<'a> {
     }
 
     /// 因数の解析
-    /// <factor> ::= <number> | '(' <expr> ')' | <function> | <variable>
-    fn factor(&mut self) -> Result<Vec<Token>, LexerError> {
-        print!("factor");
-
-        let mut tokens = self.read_whitespace_tokens();
+    /// <factor> ::= <number> | '(' <expr> ')' | <function> | <variable> | '-' <factor>
+    fn factor(&mut self) -> Result<Vec<Spanned>, LexerError> {
+        let mut tokens = self.read_whitespace_tokens()?;
 
-        match self.chars.peek() {
+        match self.peek() {
             Some(c) => match c {
                 '(' => {
                     // '(' <expr> ')'
-                    tokens.push(Token::LeftParenthesis);
-                    self.chars.next();
+                    tokens.push(self.read_symbol(Token::LeftParenthesis));
 
                     tokens = Lexer::add_tokens(tokens, self.expr()?);
 
-                    tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens());
+                    tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens()?);
 
-                    match self.chars.peek() {
+                    match self.peek() {
                         Some(c) => {
-                            if *c == ')' {
-                                self.chars.next();
-                                tokens.push(Token::RightParenthesis);
+                            if c == ')' {
+                                tokens.push(self.read_symbol(Token::RightParenthesis));
 
                                 Ok(tokens)
                             } else {
-                                Err(LexerError::new(&format!(
-                                    "error: unexpected chars, {:?}",
-                                    c
-                                )))
+                                Err(LexerError::new(ErrorCode::UnexpectedChar(c), self.pos()))
                             }
                         }
-                        None => Err(LexerError::new("error: unexpected end of line")),
+                        None => Err(LexerError::new(ErrorCode::UnexpectedEndOfLine, self.pos())),
                     }
                 }
+                '-' if !matches!(self.peek_second(), Some(cc) if cc.is_numeric() || cc == '.') => {
+                    // 数字が続かない '-' は符号反転とする (e.g. `-(1 + 2)`, `-x`)
+                    tokens.push(self.read_symbol(Token::Negate));
+                    tokens = Lexer::add_tokens(tokens, self.factor()?);
+                    Ok(tokens)
+                }
                 c if c.is_numeric() || matches!(c, '+' | '-') => {
-                    tokens = Lexer::add_tokens(tokens, self.number()?);
+                    let mut number = self.number()?;
+                    // べき乗の左辺の負の数は符号反転とする (`-x ** 2` と同じく、`-2 ** 2` は `-(2 ** 2)`)
+                    if let Some((Token::Number(num), span)) = number.last_mut() {
+                        if c == '-' && num.is_sign_negative() && self.is_power_operator_next() {
+                            let negate = (Token::Negate, span.start..span.start + 1);
+                            *num = -*num;
+                            span.start += 1;
+                            number.insert(number.len() - 1, negate);
+                        }
+                    }
+                    tokens = Lexer::add_tokens(tokens, number);
+                    Ok(tokens)
+                }
+                '"' => {
+                    tokens.push(self.string_literal()?);
+                    Ok(tokens)
+                }
+                c if self.options.currency_symbols.contains(&c) => {
+                    // 通貨記号の直後には数字が続かなければならない
+                    let start = self.pos();
+                    self.next();
+                    match self.peek() {
+                        Some(cc) if cc.is_numeric() => {
+                            let mut number = self.number()?;
+                            // トークンの範囲には通貨記号を含める
+                            if let Some((_, span)) = number.last_mut() {
+                                span.start = start;
+                            }
+                            tokens = Lexer::add_tokens(tokens, number);
+                            Ok(tokens)
+                        }
+                        Some(cc) => Err(LexerError::new(
+                            ErrorCode::UnexpectedCharAfterCurrencySymbol(cc),
+                            self.pos(),
+                        )),
+                        None => Err(LexerError::new(ErrorCode::UnexpectedEndOfLine, self.pos())),
+                    }
+                }
+                c if c.is_alphabetic()
+                    && self.options.identifier_convention == IdentifierConvention::Parenthesis =>
+                {
+                    tokens = Lexer::add_tokens(tokens, self.function()?);
                     Ok(tokens)
                 }
                 c if c.is_uppercase() => {
@@ -192,185 +477,426 @@ impl<'a> Lexer<'a> {
                     tokens = Lexer::add_tokens(tokens, self.variable()?);
                     Ok(tokens)
                 }
-                _ => Err(LexerError::new(&format!("error: unexpected char, {:?}", c))),
+                _ => Err(LexerError::new(ErrorCode::UnexpectedChar(c), self.pos())),
             },
-            None => Err(LexerError::new(&format!("error: unexpected end of line"))),
+            None => Err(LexerError::new(ErrorCode::UnexpectedEndOfLine, self.pos())),
         }
     }
 
     /// 関数の解析
-    /// <function> :== <property> '(' <expr>, [',' <expr> ]* ')' ← ただし、 property の1文字目は [A-Z]
-    fn function(&mut self) -> Result<Vec<Token>, LexerError> {
+    /// <function> :== <property> '(' [ <argument> [',' <argument> ]* ] ')' ← ただし、 property の1文字目は [A-Z]
+    fn function(&mut self) -> Result<Vec<Spanned>, LexerError> {
         let mut tokens = self.property()?;
+        // 組み込み定数 (e.g. PI) は、'(' が続かない場合は常に変数として扱う
+        let is_constant = matches!(
+            tokens.last(),
+            Some((Token::Property(name), _)) if crate::reserved_constant(name).is_some()
+        );
 
-        match self.chars.peek() {
-            Some(&c) => {
+        match self.peek() {
+            Some(c) => {
                 if c == '(' {
-                    tokens.push(Token::LeftParenthesis);
-                    self.chars.next();
+                    tokens.push(self.read_symbol(Token::LeftParenthesis));
 
-                    tokens = Lexer::add_tokens(tokens, self.expr()?);
-                    tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens());
+                    // 引数のない関数呼び出し
+                    tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens()?);
+                    if self.peek() == Some(')') {
+                        tokens.push(self.read_symbol(Token::RightParenthesis));
+
+                        return Ok(tokens);
+                    }
+
+                    tokens = Lexer::add_tokens(tokens, self.argument()?);
+                    tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens()?);
 
-                    while let Some(cc) = self.chars.peek() {
+                    while let Some(cc) = self.peek() {
                         match cc {
-                            ',' => {
-                                tokens.push(Token::Comma);
-                                self.chars.next();
+                            c if c == self.options.argument_separator => {
+                                let comma = self.read_symbol(Token::Comma);
 
-                                tokens = Lexer::add_tokens(tokens, self.expr()?);
-                                tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens());
+                                // ')' の直前のカンマは、許可されている場合は読み飛ばす
+                                if self.options.trailing_commas {
+                                    let whitespaces = self.read_whitespace_tokens()?;
+                                    if self.peek() == Some(')') {
+                                        tokens = Lexer::add_tokens(tokens, whitespaces);
+                                        continue;
+                                    }
+                                    tokens.push(comma);
+                                    tokens = Lexer::add_tokens(tokens, whitespaces);
+                                } else {
+                                    tokens.push(comma);
+                                }
+
+                                tokens = Lexer::add_tokens(tokens, self.argument()?);
+                                tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens()?);
                             }
                             ')' => {
-                                tokens.push(Token::RightParenthesis);
-                                self.chars.next();
+                                tokens.push(self.read_symbol(Token::RightParenthesis));
 
                                 break;
                             }
                             _ => {
-                                return Err(LexerError::new(&format!(
-                                    "error: unexpected char after first argument, {:?}",
-                                    cc
-                                )));
+                                return Err(LexerError::new(
+                                    ErrorCode::UnexpectedCharAfterFirstArgument(cc),
+                                    self.pos(),
+                                ));
                             }
                         }
                     }
-                } else {
-                    return Err(LexerError::new(&format!(
-                        "error: unexpected char after property, {:?}",
-                        c
-                    )));
+                } else if !(is_constant || self.allows_property_as_variable()) {
+                    return Err(LexerError::new(
+                        ErrorCode::UnexpectedCharAfterProperty(c),
+                        self.pos(),
+                    ));
+                }
+            }
+            None => {
+                if !(is_constant || self.allows_property_as_variable()) {
+                    return Err(LexerError::new(ErrorCode::UnexpectedEndOfLine, self.pos()));
                 }
             }
-            None => return Err(LexerError::new("error: unexpected end of line")),
         }
 
         Ok(tokens)
     }
 
+    /// 関数として読み込んだ識別子の後に '(' が続かない場合、変数として扱うか
+    fn allows_property_as_variable(&self) -> bool {
+        self.options.uppercase_variables
+            || self.options.identifier_convention == IdentifierConvention::Parenthesis
+    }
+
+    /// 関数の引数の解析
+    /// <argument> ::= [ <property> '=' ] <expr>
+    fn argument(&mut self) -> Result<Vec<Spanned>, LexerError> {
+        let mut tokens = vec![];
+
+        // 識別子の直後に '=' が続き (空白は可)、'==' ではない場合はキーワード引数とする
+        let mut chars = self.chars.clone();
+        let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::CharIndices>| {
+            while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        };
+        skip_whitespace(&mut chars);
+        let mut is_keyword = false;
+        if chars
+            .next_if(|&(_, c)| Lexer::is_identifier_char(c, true))
+            .is_some()
+        {
+            while chars
+                .next_if(|&(_, c)| Lexer::is_identifier_char(c, false))
+                .is_some()
+            {}
+            skip_whitespace(&mut chars);
+            is_keyword = chars.next_if(|(_, c)| *c == '=').is_some()
+                && chars.peek().map(|(_, c)| *c) != Some('=');
+        }
+
+        if is_keyword {
+            tokens = Lexer::add_tokens(tokens, self.property()?);
+            tokens = Lexer::add_tokens(tokens, self.read_whitespace_tokens()?);
+            tokens.push(self.read_symbol(Token::Assign));
+        }
+
+        Ok(Lexer::add_tokens(tokens, self.expr()?))
+    }
+
+    /// 文字列の解析 (エスケープには対応しない)
+    /// <string> ::= '"' [^"]* '"'
+    fn string_literal(&mut self) -> Result<Spanned, LexerError> {
+        let start = self.pos();
+        self.next();
+
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => break,
+                Some(c) => string.push(c),
+                None => {
+                    return Err(LexerError::new(
+                        ErrorCode::UnterminatedStringLiteral(start),
+                        start,
+                    ))
+                }
+            }
+        }
+
+        Ok((Token::StringLiteral(string), start..self.pos()))
+    }
+
     /// 変数の解析
     /// <variable> := <property> ← ただし、1文字目は [a-z]
-    fn variable(&mut self) -> Result<Vec<Token>, LexerError> {
+    fn variable(&mut self) -> Result<Vec<Spanned>, LexerError> {
         self.property()
     }
 
-    /// <property> := [a-zA-Z]+
-    fn property(&mut self) -> Result<Vec<Token>, LexerError> {
-        let mut tokens = self.read_whitespace_tokens();
+    /// 識別子に使用できる文字か (`is_first` は 1 文字目か)
+    ///
+    /// 2 文字目以降には数字と '_' も使用できる (e.g. Dist2, x1, tax_rate)
+    fn is_identifier_char(c: char, is_first: bool) -> bool {
+        c.is_alphabetic() || (!is_first && (c.is_ascii_digit() || c == '_'))
+    }
+
+    /// <property> := [a-zA-Z][a-zA-Z0-9_]*
+    fn property(&mut self) -> Result<Vec<Spanned>, LexerError> {
+        let mut tokens = self.read_whitespace_tokens()?;
 
+        let start = self.pos();
         let mut property_str = String::new();
-        while let Some(&c) = self.chars.peek() {
-            if c.is_alphabetic() {
-                self.chars.next();
+        let mut length = 0;
+        while let Some(c) = self.peek() {
+            if Lexer::is_identifier_char(c, property_str.is_empty()) {
+                // 入力が信頼できない場合に備え、長すぎる識別子は読み込む前にエラーとする
+                if length >= self.options.max_identifier_length {
+                    let pos = self.pos();
+                    return Err(LexerError::new(
+                        ErrorCode::IdentifierTooLong(self.options.max_identifier_length),
+                        pos,
+                    ));
+                }
+
+                self.next();
                 property_str.push(c);
+                length += 1;
             } else {
                 break;
             }
         }
 
         if property_str.is_empty() {
-            return Err(LexerError::new("error: property is empty"));
+            return Err(LexerError::new(ErrorCode::EmptyProperty, self.pos()));
         }
 
-        tokens.push(Token::Property(property_str));
+        tokens.push((Token::Property(property_str), start..self.pos()));
         Ok(tokens)
     }
 
-    /// <number> :== ('+'|'-')[0-9]
-    fn number(&mut self) -> Result<Vec<Token>, LexerError> {
-        print!("number");
-
-        let mut tokens = self.read_whitespace_tokens();
+    /// <number> :== ('+'|'-')[0-9] [ ('e'|'E') ('+'|'-')[0-9]+ ]
+    fn number(&mut self) -> Result<Vec<Spanned>, LexerError> {
+        let mut tokens = self.read_whitespace_tokens()?;
 
+        let start = self.pos();
         let mut number_str = String::new();
-        while let Some(&c) = self.chars.peek() {
+        while let Some(c) = self.peek() {
             // 数字に使われる可能性がある文字は読み込み、そうではない文字の場合は読み込みを終了する
             if c.is_numeric() | matches!(c, '.') | (number_str.is_empty() && matches!(c, '+' | '-'))
             {
-                self.chars.next();
+                self.next();
                 number_str.push(c);
             } else {
                 break;
             }
         }
 
-        // 0xx のパターンが parse 時に panic を起こすので除去 (0.xx はOK)
+        // 指数部 (e.g. 1.5e3, 2E-4)。'e' の後には符号と 1 桁以上の数字が続かなければならない
+        if let Some(e @ ('e' | 'E')) = self.peek() {
+            self.next();
+            number_str.push(e);
+            if let Some(sign @ ('+' | '-')) = self.peek() {
+                self.next();
+                number_str.push(sign);
+            }
+            let mut has_digits = false;
+            while let Some(c) = self.peek().filter(|c| c.is_ascii_digit()) {
+                self.next();
+                number_str.push(c);
+                has_digits = true;
+            }
+            if !has_digits {
+                return Err(LexerError::new(
+                    ErrorCode::ExponentHasNoDigits { start },
+                    self.pos(),
+                ));
+            }
+        }
+
+        // 0xx のパターンが parse 時に panic を起こすので除去 (0.xx, 0e1 はOK)
         if number_str.len() > 1
             && number_str.chars().nth(0).unwrap() == '0'
-            && number_str.chars().nth(1).unwrap() != '.'
+            && number_str.chars().nth(1).unwrap().is_numeric()
         {
-            return Err(LexerError::new("error: invalid numeric string"));
+            return Err(LexerError::new(ErrorCode::InvalidNumericString, start));
         }
 
         // 読み込んだ文字列がParseできた場合はTokenを返す
         match number_str.parse::<f64>() {
+            // 百分率 (e.g. 50% は 0.5)
+            Ok(number) if self.is_percent_sign() => {
+                self.next();
+                tokens.push((Token::Number(number / 100.0), start..self.pos()));
+                Ok(tokens)
+            }
             Ok(number) => {
-                tokens.push(Token::Number(number));
+                tokens.push((Token::Number(number), start..self.pos()));
                 Ok(tokens)
             }
-            Err(e) => Err(LexerError::new(&format!("error: {}", e.to_string()))),
+            Err(_) => Err(LexerError::new(
+                ErrorCode::InvalidFloatLiteral { end: self.pos() },
+                start,
+            )),
+        }
+    }
+
+    /// 数値の直後の '%' が百分率の記号か
+    ///
+    /// '%' の後に (空白を挟んで) 被演算子が続く場合は剰余の演算子とする (e.g. `10%3`, `10% x`)。
+    /// 符号の付いた被演算子も同様であり、`10%-3`, `10% +3` は `10 % -3`, `10 % 3` となる (`10% + 3` は `0.1 + 3`)
+    fn is_percent_sign(&self) -> bool {
+        let mut chars = self.chars.clone().map(|(_, c)| c).peekable();
+        if chars.next() != Some('%') {
+            return false;
+        }
+
+        // 符号は直後に被演算子が続く場合のみ、被演算子の一部とみなす
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        while chars.next_if(|c| matches!(c, '+' | '-')).is_some() {}
+        match chars.next() {
+            Some(c) => {
+                !(c.is_alphanumeric()
+                    || matches!(c, '.' | '(' | '"' | '_')
+                    || self.options.currency_symbols.contains(&c))
+            }
+            None => true,
         }
     }
 
-    fn read_whitespace_tokens(&mut self) -> Vec<Token> {
+    /// 空白とコメントを読み込む
+    ///
+    /// コメントは `//` または `#` から行末まで、または `/*` から `*/` までであり、1 つの WhiteSpace として扱う。
+    /// `//` は常にコメントとなるため、除算の直後に '/' を続けることはできない
+    fn read_whitespace_tokens(&mut self) -> Result<Vec<Spanned>, LexerError> {
         let mut tokens = vec![];
-        while let Some(c) = self.chars.peek() {
+        while let Some(c) = self.peek() {
             if c.is_whitespace() {
-                self.chars.next();
-                tokens.push(Token::WhiteSpace);
+                tokens.push(self.read_symbol(Token::WhiteSpace));
+            } else if c == '#' || (c == '/' && matches!(self.peek_second(), Some('/' | '*'))) {
+                tokens.push(self.read_comment()?);
             } else {
                 break;
             }
         }
 
-        tokens
+        Ok(tokens)
+    }
+
+    /// コメントを読み込む (閉じられていないブロックコメントはエラーとする)
+    fn read_comment(&mut self) -> Result<Spanned, LexerError> {
+        let start = self.pos();
+        // '#' は 1 文字で行コメントとなる
+        if self.next() == Some('#') || self.next() == Some('/') {
+            // 改行は空白として読み込む
+            while self.chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else {
+            let mut prev = None;
+            loop {
+                match self.next() {
+                    Some('/') if prev == Some('*') => break,
+                    Some(c) => prev = Some(c),
+                    None => {
+                        return Err(LexerError::new(
+                            ErrorCode::UnterminatedBlockComment(start),
+                            start,
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok((Token::WhiteSpace, start..self.pos()))
+    }
+
+    /// 次に読み込む文字の、さらに次の文字
+    /// (空白を挟んで) べき乗の演算子 (`**`, `^`) が続くか
+    fn is_power_operator_next(&self) -> bool {
+        let mut chars = self
+            .chars
+            .clone()
+            .map(|(_, c)| c)
+            .skip_while(|c| c.is_whitespace());
+        match chars.next() {
+            Some('*') => chars.next() == Some('*'),
+            Some('^') => chars.next() != Some('^'),
+            _ => false,
+        }
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        let mut chars = self.chars.clone();
+        chars.next();
+        chars.peek().map(|&(_, c)| c)
+    }
+
+    /// 1 文字を読み込み、その位置の範囲とともに `token` を返す
+    fn read_symbol(&mut self, token: Token) -> Spanned {
+        let start = self.pos();
+        self.next();
+        (token, start..self.pos())
     }
 
-    fn read_comparison_operator(&mut self, first_char: &char) -> Result<Token, LexerError> {
+    /// `start` は 1 文字目 (読み込み済み) の位置
+    fn read_comparison_operator(
+        &mut self,
+        first_char: &char,
+        start: usize,
+    ) -> Result<Token, LexerError> {
         match first_char {
-            '>' | '<' => match self.chars.peek() {
+            '>' | '<' => match self.peek() {
                 // 次が、
                 // '=' の場合は (Greater|Less)ThanOrEqual
                 // 違う場合は (Greater|Less)Than
                 Some(cc) => match cc {
                     '=' => {
                         let token = Lexer::operator_to_token(
-                            vec![*first_char, *cc].iter().collect::<String>().as_str(),
+                            [*first_char, cc].iter().collect::<String>().as_str(),
+                            start,
                         )?;
-                        self.chars.next();
+                        self.next();
                         Ok(token)
                     }
-                    _ => Lexer::operator_to_token(first_char.to_string().as_str()),
+                    _ => Lexer::operator_to_token(first_char.to_string().as_str(), start),
                 },
-                None => Err(LexerError::new("error: unexpected end of line")),
+                None => Err(LexerError::new(ErrorCode::UnexpectedEndOfLine, self.pos())),
             },
-            '=' | '!' => match self.chars.peek() {
+            '=' | '!' | '~' => match self.peek() {
                 // 次が、
-                // '=' の場合は (Equal|NotEqual)
+                // '=' の場合は (Equal|NotEqual|ApproxEqual)
                 // 違う場合はエラー
                 Some(cc) => match cc {
                     '=' => {
                         let token = Lexer::operator_to_token(
-                            vec![*first_char, *cc].iter().collect::<String>().as_str(),
+                            [*first_char, cc].iter().collect::<String>().as_str(),
+                            start,
                         )?;
-                        self.chars.next();
+                        self.next();
                         Ok(token)
                     }
-                    _ => Err(LexerError::new(&format!(
-                        "error: unexpected char after equal, {:?}",
-                        cc
-                    ))),
+                    _ => Err(LexerError::new(
+                        ErrorCode::UnexpectedCharAfterEqual(cc),
+                        self.pos(),
+                    )),
                 },
-                None => Err(LexerError::new("error: unexpected end of line")),
+                None => Err(LexerError::new(ErrorCode::UnexpectedEndOfLine, self.pos())),
             },
-            _ => Err(LexerError::new(&format!(
-                "error: unexpected char, {:?}",
-                first_char
-            ))),
+            _ => Err(LexerError::new(
+                ErrorCode::UnexpectedChar(*first_char),
+                start,
+            )),
         }
     }
 
-    fn add_tokens(mut tokens: Vec<Token>, added_tokens: Vec<Token>) -> Vec<Token> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.chars.next().map(|(_, c)| c)
+    }
+
+    /// 次に読み込む文字のバイト位置 (読み終えている場合は入力の末尾)
+    fn pos(&mut self) -> usize {
+        self.chars.peek().map_or(self.len, |&(i, _)| i)
+    }
+
+    fn add_tokens(mut tokens: Vec<Spanned>, added_tokens: Vec<Spanned>) -> Vec<Spanned> {
         for t in added_tokens {
             tokens.push(t);
         }
@@ -378,20 +904,73 @@ impl<'a> Lexer<'a> {
         tokens
     }
 
-    fn operator_to_token(c: &str) -> Result<Token, LexerError> {
+    /// `pos` は演算子の先頭の位置 (エラーの位置として使用する)
+    fn operator_to_token(c: &str, pos: usize) -> Result<Token, LexerError> {
         match c {
             "+" => Ok(Token::Plus),
             "-" => Ok(Token::Minus),
             "*" => Ok(Token::Asterisk),
             "/" => Ok(Token::Slash),
             "%" => Ok(Token::Percent),
+            "\\" => Ok(Token::Backslash),
+            "**" => Ok(Token::DoubleAsterisk),
+            "^" => Ok(Token::Caret),
             ">" => Ok(Token::GreaterThan),
             "<" => Ok(Token::LessThan),
             ">=" => Ok(Token::GreaterThanOrEqual),
             "<=" => Ok(Token::LessThanOrEqual),
             "==" => Ok(Token::Equal),
             "!=" => Ok(Token::NotEqual),
-            _ => Err(LexerError::new(&format!("error: unexpected char, {:?}", c))),
+            "~=" => Ok(Token::ApproxEqual),
+            "^^" => Ok(Token::CaretCaret),
+            "&&" => Ok(Token::And),
+            "||" => Ok(Token::Or),
+            _ => Err(LexerError::new(
+                ErrorCode::UnexpectedChar(c.chars().next().unwrap_or_default()),
+                pos,
+            )),
+        }
+    }
+}
+
+/// 字句解析の結果を、トークンとそのバイト位置の範囲の組として 1 つずつ返すイテレータ
+///
+/// 字句解析に失敗した場合はエラーを 1 度だけ返して終了する
+///
+/// 例
+///
+/// - `Tokenizer::new("12 + x")` → `(Number(12.0), 0..2)`, `(Plus, 3..4)`, `(Property("x"), 5..6)`
+pub struct Tokenizer {
+    tokens: std::vec::IntoIter<Spanned>,
+    error: Option<LexerError>,
+}
+
+impl Tokenizer {
+    pub fn new(input: &str) -> Tokenizer {
+        Tokenizer::with_options(input, LexerOptions::default())
+    }
+
+    pub fn with_options(input: &str, options: LexerOptions) -> Tokenizer {
+        match Lexer::with_options(input, options).tokenize_with_spans() {
+            Ok(tokens) => Tokenizer {
+                tokens: tokens.into_iter(),
+                error: None,
+            },
+            Err(e) => Tokenizer {
+                tokens: vec![].into_iter(),
+                error: Some(e),
+            },
+        }
+    }
+}
+
+impl Iterator for Tokenizer {
+    type Item = Result<Spanned, LexerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.error.take() {
+            Some(e) => Some(Err(e)),
+            None => self.tokens.next().map(Ok),
         }
     }
 }
@@ -404,21 +983,30 @@ mod tests {
     fn test_read_whitespace_tokens() {
         assert_eq!(
             Lexer::new("  +30").read_whitespace_tokens(),
-            vec![Token::WhiteSpace, Token::WhiteSpace]
+            Ok(vec![(Token::WhiteSpace, 0..1), (Token::WhiteSpace, 1..2)])
         );
     }
 
     #[test]
     fn test_number() {
-        assert_eq!(Lexer::new("30").number(), Ok(vec![Token::Number(30.0)]));
-        assert_eq!(Lexer::new("-30").number(), Ok(vec![Token::Number(-30.0)]));
+        assert_eq!(
+            Lexer::new("30").number(),
+            Ok(vec![(Token::Number(30.0), 0..2)])
+        );
+        assert_eq!(
+            Lexer::new("-30").number(),
+            Ok(vec![(Token::Number(-30.0), 0..3)])
+        );
         assert_eq!(
             Lexer::new(" -30 ").number(),
-            Ok(vec![Token::WhiteSpace, Token::Number(-30.0)])
+            Ok(vec![
+                (Token::WhiteSpace, 0..1),
+                (Token::Number(-30.0), 1..4)
+            ])
         );
         assert_eq!(
             Lexer::new("30 - 20").number(),
-            Ok(vec![Token::Number(30.0)])
+            Ok(vec![(Token::Number(30.0), 0..2)])
         );
     }
 
@@ -426,7 +1014,7 @@ mod tests {
     fn test_property() {
         assert_eq!(
             Lexer::new("Add(30, 20)").property(),
-            Ok(vec![Token::Property("Add".to_string())])
+            Ok(vec![(Token::Property("Add".to_string()), 0..3)])
         );
     }
 
@@ -435,6 +1023,61 @@ mod tests {
         let success_data = [
             ("30", vec![Token::Number(30.0)]),
             ("-30", vec![Token::Number(-30.0)]),
+            // べき乗の左辺の負の数は符号反転とする
+            (
+                "-2 ** 2",
+                vec![
+                    Token::Negate,
+                    Token::Number(2.0),
+                    Token::DoubleAsterisk,
+                    Token::Number(2.0),
+                ],
+            ),
+            (
+                "-2^2",
+                vec![
+                    Token::Negate,
+                    Token::Number(2.0),
+                    Token::Caret,
+                    Token::Number(2.0),
+                ],
+            ),
+            (
+                "-2 ^^ 2",
+                vec![Token::Number(-2.0), Token::CaretCaret, Token::Number(2.0)],
+            ),
+            (
+                "-(3+2)",
+                vec![
+                    Token::Negate,
+                    Token::LeftParenthesis,
+                    Token::Number(3.0),
+                    Token::Plus,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            (
+                "x>0?1:-1",
+                vec![
+                    Token::Property("x".to_string()),
+                    Token::GreaterThan,
+                    Token::Number(0.0),
+                    Token::Question,
+                    Token::Number(1.0),
+                    Token::Colon,
+                    Token::Number(-1.0),
+                ],
+            ),
+            (
+                "3 - -hoge",
+                vec![
+                    Token::Number(3.0),
+                    Token::Minus,
+                    Token::Negate,
+                    Token::Property("hoge".to_string()),
+                ],
+            ),
             (
                 "1+(-1)",
                 vec![
@@ -445,6 +1088,16 @@ mod tests {
                     Token::RightParenthesis,
                 ],
             ),
+            (
+                "-7 \\ 2*3",
+                vec![
+                    Token::Number(-7.0),
+                    Token::Backslash,
+                    Token::Number(2.0),
+                    Token::Asterisk,
+                    Token::Number(3.0),
+                ],
+            ),
             (
                 "30/10+(10+20)",
                 vec![
@@ -530,6 +1183,56 @@ mod tests {
                     Token::RightParenthesis,
                 ],
             ),
+            (
+                "Dist2(x1, 2)",
+                vec![
+                    Token::Property("Dist2".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Property("x1".to_string()),
+                    Token::Comma,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            ("1.5e3", vec![Token::Number(1500.0)]),
+            ("2E-4", vec![Token::Number(0.0002)]),
+            (
+                "-1e+2 * 0e5",
+                vec![Token::Number(-100.0), Token::Asterisk, Token::Number(0.0)],
+            ),
+            (
+                "Max2(1, 2) * tax_rate",
+                vec![
+                    Token::Property("Max2".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Number(1.0),
+                    Token::Comma,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                    Token::Asterisk,
+                    Token::Property("tax_rate".to_string()),
+                ],
+            ),
+            (
+                "Now( ) + 1",
+                vec![
+                    Token::Property("Now".to_string()),
+                    Token::LeftParenthesis,
+                    Token::RightParenthesis,
+                    Token::Plus,
+                    Token::Number(1.0),
+                ],
+            ),
+            (
+                "2 ** 3 * 2",
+                vec![
+                    Token::Number(2.0),
+                    Token::DoubleAsterisk,
+                    Token::Number(3.0),
+                    Token::Asterisk,
+                    Token::Number(2.0),
+                ],
+            ),
             (
                 "(hoge - (2 * 3)) / (4 + 5)",
                 vec![
@@ -550,18 +1253,750 @@ mod tests {
                     Token::RightParenthesis,
                 ],
             ),
-        ];
-
-        success_data.map(|(input, expected)| {
-            assert_eq!(Lexer::new(input).tokenize(), Ok(expected));
-        });
-
-        let failure_data = ["2(3 + 2)", "Add()", "add(3)"];
-        failure_data.map(|input| {
-            assert_eq!(
-                (Lexer::new(input).tokenize().is_err(), input),
+            (
+                "1 ^^ 0",
+                vec![Token::Number(1.0), Token::CaretCaret, Token::Number(0.0)],
+            ),
+            (
+                "1 && 0",
+                vec![Token::Number(1.0), Token::And, Token::Number(0.0)],
+            ),
+            (
+                "(2 > 1)||(3 < 1)",
+                vec![
+                    Token::LeftParenthesis,
+                    Token::Number(2.0),
+                    Token::GreaterThan,
+                    Token::Number(1.0),
+                    Token::RightParenthesis,
+                    Token::Or,
+                    Token::LeftParenthesis,
+                    Token::Number(3.0),
+                    Token::LessThan,
+                    Token::Number(1.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            (
+                "2 ^ 3 * x^2",
+                vec![
+                    Token::Number(2.0),
+                    Token::Caret,
+                    Token::Number(3.0),
+                    Token::Asterisk,
+                    Token::Property("x".to_string()),
+                    Token::Caret,
+                    Token::Number(2.0),
+                ],
+            ),
+            (
+                "x ~= 0.3",
+                vec![
+                    Token::Property("x".to_string()),
+                    Token::ApproxEqual,
+                    Token::Number(0.3),
+                ],
+            ),
+            (
+                "2^3^^0",
+                vec![
+                    Token::Number(2.0),
+                    Token::Caret,
+                    Token::Number(3.0),
+                    Token::CaretCaret,
+                    Token::Number(0.0),
+                ],
+            ),
+        ];
+
+        for (input, expected) in success_data {
+            assert_eq!(Lexer::new(input).tokenize(), Ok(expected));
+        }
+
+        let failure_data = [
+            "2(3 + 2)", "Add(,)", "add(3)", "2 * *3", "2 ***3", "2x", "1 ^ ^ 0", "1 ^", "1 & 0",
+            "1 | 0", "1 & & 0", "1 &&", "1var", "_x", "x + _y", "1e", "1e+", "1E-", "1e +2",
+            "1.5e3.5", "? 1 : 2", "1 ? : 2", "1 ?", "1 ~ 2", "1 ~",
+        ];
+        for input in failure_data {
+            assert_eq!(
+                (Lexer::new(input).tokenize().is_err(), input),
                 (true, input)
             );
-        });
+        }
+    }
+
+    #[test]
+    fn test_error_position() {
+        let failure_data = [
+            ("2(3 + 2)", 1),
+            ("1 + 2 = 3", 7),
+            ("1 + $", 4),
+            ("Add(1 2)", 6),
+            ("(1 + 2", 6),
+            ("1 ^ ^ 2", 4),
+            ("あ + 1", 0),
+            ("1 + あ", 4),
+            ("007", 0),
+            ("1 + 2e", 6),
+            ("1e+ 2", 3),
+        ];
+        for (input, pos) in failure_data {
+            assert_eq!(
+                (Lexer::new(input).tokenize().map_err(|e| e.pos), input),
+                (Err(pos), input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_percent_literals() {
+        let success_data = [
+            ("50%", vec![Token::Number(0.5)]),
+            (
+                "10 % 3",
+                vec![Token::Number(10.0), Token::Percent, Token::Number(3.0)],
+            ),
+            (
+                "10%3",
+                vec![Token::Number(10.0), Token::Percent, Token::Number(3.0)],
+            ),
+            (
+                "50% + 10%",
+                vec![Token::Number(0.5), Token::Plus, Token::Number(0.1)],
+            ),
+            (
+                "50% * 2",
+                vec![Token::Number(0.5), Token::Asterisk, Token::Number(2.0)],
+            ),
+            (
+                "x * 8%",
+                vec![
+                    Token::Property("x".to_string()),
+                    Token::Asterisk,
+                    Token::Number(0.08),
+                ],
+            ),
+            (
+                "(-25%)%x",
+                vec![
+                    Token::LeftParenthesis,
+                    Token::Number(-0.25),
+                    Token::RightParenthesis,
+                    Token::Percent,
+                    Token::Property("x".to_string()),
+                ],
+            ),
+            (
+                "10% (2)",
+                vec![
+                    Token::Number(10.0),
+                    Token::Percent,
+                    Token::LeftParenthesis,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            // 符号の付いた被演算子が続く場合は剰余の演算子
+            (
+                "10%-3",
+                vec![Token::Number(10.0), Token::Percent, Token::Number(-3.0)],
+            ),
+            (
+                "10% -3",
+                vec![Token::Number(10.0), Token::Percent, Token::Number(-3.0)],
+            ),
+            (
+                "10% + 3",
+                vec![Token::Number(0.1), Token::Plus, Token::Number(3.0)],
+            ),
+            ("5% // note", vec![Token::Number(0.05)]),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((Lexer::new(input).tokenize(), input), (Ok(expected), input));
+        }
+
+        // 百分率の記号の位置は数値に含める
+        assert_eq!(
+            Lexer::new("1 + 50%").tokenize_with_spans(),
+            Ok(vec![
+                (Token::Number(1.0), 0..1),
+                (Token::Plus, 2..3),
+                (Token::Number(0.5), 4..7),
+            ])
+        );
+
+        for input in ["50%%", "50 %", "% 50"] {
+            assert!(Lexer::new(input).tokenize().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_comments() {
+        let success_data = [
+            (
+                "1 + 2 // note",
+                vec![Token::Number(1.0), Token::Plus, Token::Number(2.0)],
+            ),
+            (
+                "1 /* x */ + 2",
+                vec![Token::Number(1.0), Token::Plus, Token::Number(2.0)],
+            ),
+            (
+                "// header\n1 /* a\nb */ /\n/* c */ 2 // trailing",
+                vec![Token::Number(1.0), Token::Slash, Token::Number(2.0)],
+            ),
+            (
+                "Add(1, /* x */ 2)//",
+                vec![
+                    Token::Property("Add".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Number(1.0),
+                    Token::Comma,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            (
+                "1 + 2 # note\n+ 3 #",
+                vec![
+                    Token::Number(1.0),
+                    Token::Plus,
+                    Token::Number(2.0),
+                    Token::Plus,
+                    Token::Number(3.0),
+                ],
+            ),
+            (
+                "# header\nMapSum(\"#\", 1)",
+                vec![
+                    Token::Property("MapSum".to_string()),
+                    Token::LeftParenthesis,
+                    Token::StringLiteral("#".to_string()),
+                    Token::Comma,
+                    Token::Number(1.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            (
+                "4/*/ */+2",
+                vec![Token::Number(4.0), Token::Plus, Token::Number(2.0)],
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((Lexer::new(input).tokenize(), input), (Ok(expected), input));
+        }
+
+        // コメントは 1 つの空白として扱う
+        assert_eq!(Lexer::new("1 /* x */").read_whitespace_tokens(), Ok(vec![]));
+        assert_eq!(
+            Lexer::new(" /* x */1").read_whitespace_tokens(),
+            Ok(vec![(Token::WhiteSpace, 0..1), (Token::WhiteSpace, 1..8)])
+        );
+
+        let failure_data = [
+            "1 /* x",
+            "1 + /* x *",
+            "/* x */",
+            "1 / / 2",
+            "# x",
+            "1 + # x\n",
+        ];
+        for input in failure_data {
+            assert!(Lexer::new(input).tokenize().is_err(), "{}", input);
+        }
+        assert_eq!(
+            Lexer::new("1 + /* x").tokenize(),
+            Err(LexerError::new(ErrorCode::UnterminatedBlockComment(4), 4))
+        );
+    }
+
+    #[test]
+    fn test_string_literal() {
+        assert_eq!(
+            Lexer::new("MapSum(\"Square\", 1)").tokenize_with_spans(),
+            Ok(vec![
+                (Token::Property("MapSum".to_string()), 0..6),
+                (Token::LeftParenthesis, 6..7),
+                (Token::StringLiteral("Square".to_string()), 7..15),
+                (Token::Comma, 15..16),
+                (Token::Number(1.0), 17..18),
+                (Token::RightParenthesis, 18..19),
+            ])
+        );
+        assert_eq!(
+            Lexer::new("\"\"").tokenize(),
+            Ok(vec![Token::StringLiteral("".to_string())])
+        );
+        assert_eq!(
+            Lexer::new("MapSum(\"Square, 1)").tokenize(),
+            Err(LexerError::new(ErrorCode::UnterminatedStringLiteral(7), 7))
+        );
+    }
+
+    #[test]
+    fn test_uppercase_variables() {
+        let options = LexerOptions {
+            uppercase_variables: true,
+            ..LexerOptions::default()
+        };
+        assert_eq!(
+            Lexer::with_options("MAX + 1", options.clone()).tokenize(),
+            Ok(vec![
+                Token::Property("MAX".to_string()),
+                Token::Plus,
+                Token::Number(1.0),
+            ])
+        );
+        assert_eq!(
+            Lexer::with_options("Add(MAX, 1)", options).tokenize(),
+            Ok(vec![
+                Token::Property("Add".to_string()),
+                Token::LeftParenthesis,
+                Token::Property("MAX".to_string()),
+                Token::Comma,
+                Token::Number(1.0),
+                Token::RightParenthesis,
+            ])
+        );
+
+        assert!(Lexer::new("MAX + 1").tokenize().is_err());
+
+        // 組み込み定数は、オプションに関わらず変数として扱う
+        assert_eq!(
+            Lexer::new("PI * 2").tokenize(),
+            Ok(vec![
+                Token::Property("PI".to_string()),
+                Token::Asterisk,
+                Token::Number(2.0),
+            ])
+        );
+        assert!(Lexer::new("PIE * 2").tokenize().is_err());
+    }
+
+    #[test]
+    fn test_identifier_convention() {
+        let options = LexerOptions {
+            identifier_convention: IdentifierConvention::Parenthesis,
+            ..LexerOptions::default()
+        };
+        assert_eq!(
+            Lexer::with_options("foo(1) + Foo", options.clone()).tokenize(),
+            Ok(vec![
+                Token::Property("foo".to_string()),
+                Token::LeftParenthesis,
+                Token::Number(1.0),
+                Token::RightParenthesis,
+                Token::Plus,
+                Token::Property("Foo".to_string()),
+            ])
+        );
+        assert_eq!(
+            Lexer::with_options("max(x, Y)", options.clone()).tokenize(),
+            Ok(vec![
+                Token::Property("max".to_string()),
+                Token::LeftParenthesis,
+                Token::Property("x".to_string()),
+                Token::Comma,
+                Token::Property("Y".to_string()),
+                Token::RightParenthesis,
+            ])
+        );
+
+        // 既定の規則では、小文字から始まる関数呼び出しと大文字から始まる変数はエラー
+        assert!(Lexer::new("foo(1)").tokenize().is_err());
+        assert!(Lexer::new("Foo + 1").tokenize().is_err());
+    }
+
+    #[test]
+    fn test_max_identifier_length() {
+        let options = LexerOptions {
+            max_identifier_length: 4,
+            ..LexerOptions::default()
+        };
+        assert_eq!(
+            Lexer::with_options("hoge + 1", options.clone()).tokenize(),
+            Ok(vec![
+                Token::Property("hoge".to_string()),
+                Token::Plus,
+                Token::Number(1.0),
+            ])
+        );
+        assert!(Lexer::with_options("hogee + 1", options.clone())
+            .tokenize()
+            .is_err());
+        assert!(Lexer::with_options("Hogee(1)", options).tokenize().is_err());
+
+        // デフォルトは 256 文字
+        assert!(Lexer::new(&"a".repeat(256)).tokenize().is_ok());
+        assert!(Lexer::new(&"a".repeat(257)).tokenize().is_err());
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let success_data = [
+            (
+                "12 + x",
+                vec![
+                    (Token::Number(12.0), 0..2),
+                    (Token::Plus, 3..4),
+                    (Token::Property("x".to_string()), 5..6),
+                ],
+            ),
+            (
+                "Add(1,x)>=2",
+                vec![
+                    (Token::Property("Add".to_string()), 0..3),
+                    (Token::LeftParenthesis, 3..4),
+                    (Token::Number(1.0), 4..5),
+                    (Token::Comma, 5..6),
+                    (Token::Property("x".to_string()), 6..7),
+                    (Token::RightParenthesis, 7..8),
+                    (Token::GreaterThanOrEqual, 8..10),
+                    (Token::Number(2.0), 10..11),
+                ],
+            ),
+            (
+                // 範囲は文字数ではなくバイト位置で表す
+                "\u{3000}2 ** x",
+                vec![
+                    (Token::Number(2.0), 3..4),
+                    (Token::DoubleAsterisk, 5..7),
+                    (Token::Property("x".to_string()), 8..9),
+                ],
+            ),
+        ];
+
+        for (input, expected) in success_data {
+            assert_eq!(
+                Tokenizer::new(input).collect::<Result<Vec<_>, _>>(),
+                Ok(expected)
+            );
+        }
+
+        let mut tokenizer = Tokenizer::new("2(3 + 2)");
+        assert!(tokenizer.next().unwrap().is_err());
+        assert!(tokenizer.next().is_none());
+    }
+
+    #[test]
+    fn test_strict_whitespace() {
+        let options = LexerOptions {
+            strict_whitespace: true,
+            ..LexerOptions::default()
+        };
+
+        let success_data = [
+            "1 + 2",
+            "1+2",
+            "  1 + 2  ",
+            "2 ** 3 >= 1",
+            "Add(1, 2)",
+            "Add(1,2) * -3",
+            "(hoge - 1) / 2",
+        ];
+        for input in success_data {
+            assert!(
+                Lexer::with_options(input, options.clone())
+                    .tokenize()
+                    .is_ok(),
+                "{} should be accepted",
+                input
+            );
+        }
+
+        let failure_data = [
+            // 関数名と '(' の間 (strict でなくても不可)
+            "Add (1, 2)",
+            // 数値・識別子の途中 (strict でなくても不可)
+            "1 2 + 3",
+            "ho ge + 1",
+            "- 3",
+            // 括弧の内側
+            "( 1 + 2)",
+            "(1 + 2 )",
+            "Add( 1, 2)",
+            "Now( )",
+            // ',' の前
+            "Add(1 , 2)",
+        ];
+        for input in failure_data {
+            assert!(
+                Lexer::with_options(input, options.clone())
+                    .tokenize()
+                    .is_err(),
+                "{} should be rejected",
+                input
+            );
+        }
+
+        // strict でない場合は括弧の内側や ',' の前の空白も許可する
+        for input in ["( 1 + 2)", "Add(1 , 2)", "Now( )"] {
+            assert!(Lexer::new(input).tokenize().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_currency_symbols() {
+        let options = LexerOptions {
+            currency_symbols: vec!['$', '¥'],
+            ..LexerOptions::default()
+        };
+
+        let success_data = [
+            (
+                "$100 + $50",
+                vec![
+                    (Token::Number(100.0), 0..4),
+                    (Token::Plus, 5..6),
+                    (Token::Number(50.0), 7..10),
+                ],
+            ),
+            (
+                "¥1000 * 2",
+                vec![
+                    (Token::Number(1000.0), 0..6),
+                    (Token::Asterisk, 7..8),
+                    (Token::Number(2.0), 9..10),
+                ],
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                Lexer::with_options(input, options.clone()).tokenize_with_spans(),
+                Ok(expected)
+            );
+        }
+
+        let failure_data = ["$ 100", "$-100", "$hoge", "100$", "$"];
+        for input in failure_data {
+            assert!(
+                Lexer::with_options(input, options.clone())
+                    .tokenize()
+                    .is_err(),
+                "{} should be rejected",
+                input
+            );
+        }
+
+        // 通貨記号を指定しない場合はエラーとなる
+        assert!(Lexer::new("$100").tokenize().is_err());
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        let success_data = [
+            (
+                "Pay(rate=0.05, n = 12)",
+                vec![
+                    Token::Property("Pay".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Property("rate".to_string()),
+                    Token::Assign,
+                    Token::Number(0.05),
+                    Token::Comma,
+                    Token::Property("n".to_string()),
+                    Token::Assign,
+                    Token::Number(12.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            (
+                "F(tax_rate=1, b2 = x_1)",
+                vec![
+                    Token::Property("F".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Property("tax_rate".to_string()),
+                    Token::Assign,
+                    Token::Number(1.0),
+                    Token::Comma,
+                    Token::Property("b2".to_string()),
+                    Token::Assign,
+                    Token::Property("x_1".to_string()),
+                    Token::RightParenthesis,
+                ],
+            ),
+            (
+                // '==' は比較演算子として扱う
+                "If(x == 1, 2, 3)",
+                vec![
+                    Token::Property("If".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Property("x".to_string()),
+                    Token::Equal,
+                    Token::Number(1.0),
+                    Token::Comma,
+                    Token::Number(2.0),
+                    Token::Comma,
+                    Token::Number(3.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(Lexer::new(input).tokenize(), Ok(expected));
+        }
+
+        // 関数の引数以外では '=' は使用できない
+        let failure_data = ["x = 1", "(x = 1)", "Add(1 = 1)", "Add(x =)"];
+        for input in failure_data {
+            assert!(Lexer::new(input).tokenize().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_trailing_commas() {
+        let options = LexerOptions {
+            trailing_commas: true,
+            ..LexerOptions::default()
+        };
+        let expected = vec![
+            Token::Property("Add".to_string()),
+            Token::LeftParenthesis,
+            Token::Number(1.0),
+            Token::Comma,
+            Token::Number(2.0),
+            Token::RightParenthesis,
+        ];
+        for input in ["Add(1, 2,)", "Add(1, 2 , )", "Add(1, 2)"] {
+            assert_eq!(
+                Lexer::with_options(input, options.clone()).tokenize(),
+                Ok(expected.clone())
+            );
+        }
+
+        for input in ["Add(1, 2,,)", "Add(,)", "Now(,)", "(1,)"] {
+            assert!(
+                Lexer::with_options(input, options.clone())
+                    .tokenize()
+                    .is_err(),
+                "{}",
+                input
+            );
+        }
+
+        // デフォルトでは許可しない
+        assert!(Lexer::new("Add(1, 2,)").tokenize().is_err());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut lexer = Lexer::with_options(
+            "Add(1; 2)",
+            LexerOptions {
+                argument_separator: ';',
+                ..LexerOptions::default()
+            },
+        );
+        assert_eq!(
+            lexer.tokenize(),
+            Ok(vec![
+                Token::Property("Add".to_string()),
+                Token::LeftParenthesis,
+                Token::Number(1.0),
+                Token::Comma,
+                Token::Number(2.0),
+                Token::RightParenthesis,
+            ])
+        );
+
+        // オプションは引き継がれ、バイト位置は新しい入力の先頭から数える
+        lexer.reset("x * Sub(3; 4)");
+        assert_eq!(
+            lexer.tokenize_with_spans(),
+            Ok(vec![
+                (Token::Property("x".to_string()), 0..1),
+                (Token::Asterisk, 2..3),
+                (Token::Property("Sub".to_string()), 4..7),
+                (Token::LeftParenthesis, 7..8),
+                (Token::Number(3.0), 8..9),
+                (Token::Comma, 9..10),
+                (Token::Number(4.0), 11..12),
+                (Token::RightParenthesis, 12..13),
+            ])
+        );
+
+        // 途中で失敗した場合も、reset 後は最初から解析できる
+        lexer.reset("1 +");
+        assert!(lexer.tokenize().is_err());
+        lexer.reset("1 + 2");
+        assert_eq!(
+            lexer.tokenize(),
+            Ok(vec![Token::Number(1.0), Token::Plus, Token::Number(2.0)])
+        );
+    }
+
+    #[test]
+    fn test_argument_separator() {
+        let options = LexerOptions {
+            argument_separator: ';',
+            ..LexerOptions::default()
+        };
+        let success_data = [
+            (
+                "Add(1; 2)",
+                vec![
+                    Token::Property("Add".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Number(1.0),
+                    Token::Comma,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+            (
+                "Max(a = 1;b = 2)",
+                vec![
+                    Token::Property("Max".to_string()),
+                    Token::LeftParenthesis,
+                    Token::Property("a".to_string()),
+                    Token::Assign,
+                    Token::Number(1.0),
+                    Token::Comma,
+                    Token::Property("b".to_string()),
+                    Token::Assign,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                ],
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                Lexer::with_options(input, options.clone()).tokenize(),
+                Ok(expected)
+            );
+        }
+
+        let failure_data = ["Add(1, 2)", "Add(1; )", "1; 2"];
+        for input in failure_data {
+            assert!(
+                Lexer::with_options(input, options.clone())
+                    .tokenize()
+                    .is_err(),
+                "{}",
+                input
+            );
+        }
+
+        // 他の用途に使われている文字 (識別子の文字・通貨記号を含む) は区切り文字にできない
+        for separator in ['+', '(', 'a', ' ', '"', '\\', '~', '#', '_', '|', '$'] {
+            let options = LexerOptions {
+                argument_separator: separator,
+                currency_symbols: vec!['$'],
+                ..LexerOptions::default()
+            };
+            let input = format!("Add(1{}2)", separator);
+            assert_eq!(
+                Lexer::with_options(&input, options)
+                    .tokenize()
+                    .map_err(|e| e.message(&input)),
+                Err(format!(
+                    "error: invalid argument separator, {:?}",
+                    separator
+                )),
+                "{}",
+                input
+            );
+        }
     }
 }
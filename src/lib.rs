@@ -1,138 +1,4465 @@
-use lexer::Lexer;
-use parser::Parser;
-use processor::{Function, Processor, Variable};
+// ライブラリとして標準出力・標準エラー出力を汚さないよう、print! などの使用を禁止する
+#![deny(clippy::print_stdout, clippy::print_stderr)]
 
-mod lexer;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error::Subject;
+use parser::{Parser, ParserError, PartialParse};
+use processor::{Processor, ProcessorError};
+
+pub use error::ErrorCode;
+pub use lexer::{IdentifierConvention, Lexer, LexerError, LexerOptions, Token, Tokenizer};
+pub use parser::{Expr, Value};
+pub use processor::{
+    BranchTrace, ConditionalArgs, EvalResult, Evaluated, Function, NanPolicy, OperatorTable,
+    ProcessorOptions, TypedResult, Variable, VariableResolver, VariableType, Warning, WarningKind,
+};
+pub use symbolic::simplify;
+
+#[cfg(feature = "bigint")]
+pub use num_bigint::BigInt;
+
+#[cfg(feature = "bigint")]
+mod bigint;
+mod error;
+pub mod lexer;
+mod math;
 mod parser;
 mod processor;
+mod symbolic;
 
-#[derive(Debug, PartialEq)]
-enum ErrorType {
+/// エラーが発生した処理の種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// 字句解析
     Lexer,
+    /// 構文解析
     Parser,
+    /// 評価
     Processor,
+    /// 木構造の変形 (微分など)
+    Symbolic,
+    /// テンプレートの展開
+    Template,
+    /// 関数・変数の登録
+    Registration,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct FormulaError {
     msg: String,
-    error_type: ErrorType,
+    error_type: ErrorKind,
 }
 
-/// 数式を解析する
+impl FormulaError {
+    /// エラーメッセージ
+    pub fn message(&self) -> &str {
+        &self.msg
+    }
+
+    /// エラーが発生した処理の種類
+    pub fn kind(&self) -> ErrorKind {
+        self.error_type
+    }
+}
+
+impl std::fmt::Display for FormulaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for FormulaError {}
+
+/// メッセージを持たない、`Copy` なエラー
 ///
-/// 例
+/// `Context::evaluate_code` などで、エラーの種類のみを調べる場合に使用する (エラーメッセージの文字列を生成しない)。
+/// メッセージが必要になった場合は、エラーの原因を表すコードと位置から `to_error` で生成できる
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactError {
+    kind: ErrorKind,
+    code: ErrorCode,
+    pos: Option<usize>,
+    at: Option<usize>,
+    /// コードと位置のみからメッセージを生成できるか
+    reproducible: bool,
+}
+
+impl CompactError {
+    /// エラーが発生した処理の種類
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// エラーの原因を表すコード
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// エラーの原因となった、入力文字列中のバイト位置 (字句解析のエラーのみ)
+    pub fn pos(&self) -> Option<usize> {
+        self.pos
+    }
+
+    /// エラーの原因となった、トークン列 (構文解析のエラー) または中間表現 (評価のエラー) 中の位置
+    pub fn index(&self) -> Option<usize> {
+        self.at
+    }
+
+    /// コードと位置から、エラーメッセージを含むエラーを生成する
+    ///
+    /// `context`, `input` には評価したときと同じものを指定する (`parse_formula_code` の場合は `Context::new()`)。
+    /// 数式の字句解析・構文解析のみを行い、評価はしないため、評価のたびに結果が変わる関数 (`Now` など) を含む数式でも元のエラーとなる。
+    /// 登録した関数が返したメッセージなど、コードと位置から生成できない場合は None を返す
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_code("1 + $").unwrap_err().to_error(&context, "1 + $") // → Some(FormulaError { msg: "error: unexpected char, '$'", .. })`
+    pub fn to_error(&self, context: &Context, input: &str) -> Option<FormulaError> {
+        if !self.reproducible {
+            return None;
+        }
+        let msg = match self.kind {
+            ErrorKind::Lexer => LexerError {
+                code: self.code,
+                pos: self.pos?,
+            }
+            .message(input),
+            ErrorKind::Parser => ParserError {
+                code: self.code,
+                at: self.at,
+            }
+            .message(context.parser(input).ok()?.tokens()),
+            _ => ProcessorError::with_index(self.code, self.at)
+                .into_message(&context.compile(input).ok()?),
+        };
+        Some(FormulaError {
+            msg,
+            error_type: self.kind,
+        })
+    }
+}
+
+/// `Round` の丸め方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    /// 0.5 は 0 から遠い方向に丸める (四捨五入)
+    HalfUp,
+    /// 0.5 は偶数の方向に丸める (銀行丸め)
+    HalfEven,
+    /// 負の無限大方向に丸める
+    Floor,
+    /// 正の無限大方向に丸める
+    Ceil,
+}
+
+impl RoundingMode {
+    fn round(self, x: f64) -> f64 {
+        match self {
+            RoundingMode::HalfUp => x.round(),
+            RoundingMode::HalfEven => x.round_ties_even(),
+            RoundingMode::Floor => x.floor(),
+            RoundingMode::Ceil => x.ceil(),
+        }
+    }
+}
+
+/// `Context` に登録できる関数・変数の数の上限 (None の場合は無制限)
 ///
-/// - `parse_formula("(1 + 2) * 3", vec![], vec![]) // → 9`
-/// - `parse_formula(
-///  "Pow(2, 3) + 3",
-///  vec![Function::new("Pow", 2, |args| args[0].powf(args[1]))],
-///  vec![]
-/// ) // → 11.0`
-pub fn parse_formula(
-    input: &str,
+/// 予約関数は関数の数に含まない
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegistrationLimits {
+    pub max_functions: Option<usize>,
+    pub max_variables: Option<usize>,
+}
+
+/// 数式中で参照されなかった、登録済みの変数・関数の名前 (登録順)
+///
+/// 予約関数は含まない
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Unused {
+    pub variables: Vec<String>,
+    pub functions: Vec<String>,
+}
+
+/// 入力途中の数式を評価した結果
+#[derive(Debug, PartialEq)]
+pub enum PartialEvaluation {
+    /// 完全な数式であり、評価した結果
+    Complete(f64),
+    /// 誤りはないが、数式が途中で終わっている (e.g. `1 +`, `(1 + 2`)
+    Incomplete,
+}
+
+/// 数式の評価に使用する関数・変数・定数をまとめたもの
+///
+/// 生成時点で予約関数 (`Add`, `If` など) が登録されている
+pub struct Context {
     functions: Vec<Function>,
     variables: Vec<Variable>,
-) -> Result<f64, FormulaError> {
-    let reserved_functions = vec![
-        Function::new("Add", 2, |args| args[0] + args[1]),
-        Function::new("Sub", 2, |args| args[0] - args[1]),
-        Function::new("Mul", 2, |args| args[0] * args[1]),
-        Function::new("Div", 2, |args| args[0] / args[1]),
-        Function::new("Mod", 2, |args| args[0] % args[1]),
-        Function::new(
-            "If",
-            3,
-            |args| if args[0] == 0.0 { args[2] } else { args[1] },
-        ),
-    ];
+    constants: HashMap<String, f64>,
+    arrays: HashMap<String, Vec<f64>>,
+    lexer_options: LexerOptions,
+    processor_options: ProcessorOptions,
+    operators: OperatorTable,
+    limits: RegistrationLimits,
+    /// 予約関数を除いた、登録済みの関数の数
+    registered_functions: usize,
+    /// `Eval` を入れ子で呼び出せる深さ
+    eval_budget: usize,
+    /// `~=` の許容誤差
+    approx_tolerance: f64,
+}
 
-    let mut all_functions = reserved_functions;
-    for f in functions {
-        all_functions.push(f);
+impl Context {
+    pub fn new() -> Context {
+        Context {
+            functions: reserved_functions(),
+            variables: vec![],
+            constants: HashMap::new(),
+            arrays: HashMap::new(),
+            lexer_options: LexerOptions::default(),
+            processor_options: ProcessorOptions::default(),
+            operators: OperatorTable::default(),
+            limits: RegistrationLimits::default(),
+            registered_functions: 0,
+            eval_budget: processor::DEFAULT_EVAL_BUDGET,
+            approx_tolerance: processor::DEFAULT_APPROX_TOLERANCE,
+        }
+    }
+
+    /// 関数を登録する
+    ///
+    /// # Panics
+    ///
+    /// `set_registration_limits` で設定した上限を超える場合 (上限を設定する場合は `try_add_function` を使用する)
+    pub fn add_function(&mut self, function: Function) {
+        if let Err(e) = self.try_add_function(function) {
+            panic!("{}", e);
+        }
+    }
+
+    /// 変数を登録する
+    ///
+    /// # Panics
+    ///
+    /// `set_registration_limits` で設定した上限を超える場合 (上限を設定する場合は `try_add_variable` を使用する)
+    pub fn add_variable(&mut self, variable: Variable) {
+        if let Err(e) = self.try_add_variable(variable) {
+            panic!("{}", e);
+        }
+    }
+
+    /// 関数を登録する (上限を超える場合はエラーとなり、登録されない)
+    pub fn try_add_function(&mut self, function: Function) -> Result<(), FormulaError> {
+        Context::check_limit(
+            "functions",
+            self.registered_functions,
+            self.limits.max_functions,
+        )?;
+        self.functions.push(function);
+        self.registered_functions += 1;
+        Ok(())
+    }
+
+    /// 変数を登録する (上限を超える場合はエラーとなり、登録されない)
+    pub fn try_add_variable(&mut self, variable: Variable) -> Result<(), FormulaError> {
+        Context::check_limit("variables", self.variables.len(), self.limits.max_variables)?;
+        self.variables.push(variable);
+        Ok(())
+    }
+
+    /// 登録できる関数・変数の数の上限を設定する
+    ///
+    /// すでに登録されているものは削除されず、以降の登録のみが制限される
+    pub fn set_registration_limits(&mut self, limits: RegistrationLimits) {
+        self.limits = limits;
+    }
+
+    /// 定数を登録する
+    ///
+    /// 定数は変数よりも優先して解決されるため、同名の変数を登録しても上書きされることはない
+    pub fn add_constant(&mut self, name: &str, value: f64) {
+        self.constants.insert(name.to_string(), value);
+    }
+
+    /// 配列変数を登録する (同名の配列がある場合は上書きする)
+    ///
+    /// 配列変数は `sum_over` で参照する
+    pub fn add_array(&mut self, name: &str, values: Vec<f64>) {
+        self.arrays.insert(name.to_string(), values);
+    }
+
+    pub fn set_lexer_options(&mut self, options: LexerOptions) {
+        self.lexer_options = options;
+    }
+
+    /// 評価のオプションを指定する (`division_by_zero_error` は、関数 `Div`, `Mod` にも適用される)
+    pub fn set_processor_options(&mut self, options: ProcessorOptions) {
+        for function in division_functions(options.division_by_zero_error) {
+            self.replace_function(function);
+        }
+        self.processor_options = options;
+    }
+
+    /// 二項演算子の、被演算子の型ごとの挙動を登録する
+    ///
+    /// 型名は `Evaluated::type_name` の値 (`"number"`, `"string"`) を指定する。
+    /// 登録済みの組 (文字列の `+` など) を指定した場合は上書きする
+    ///
+    /// 例
+    ///
+    /// - `context.add_operator(Value::Asterisk, "string", "number", repeat)` → `"ab" * 2` を評価できる
+    pub fn add_operator(
+        &mut self,
+        operator: Value,
+        lhs: &str,
+        rhs: &str,
+        handler: impl Fn(&Evaluated, &Evaluated) -> Result<Evaluated, String> + 'static,
+    ) {
+        self.operators.register(operator, lhs, rhs, handler);
+    }
+
+    /// `Now()` が返す時刻 (UNIX エポックからの秒数) を固定する
+    ///
+    /// テストなど、評価結果の再現性が必要な場合に使用する
+    pub fn set_now(&mut self, now: f64) {
+        self.replace_function(Function::new("Now", 0, move |_| now).impure());
+    }
+
+    /// `Eval` を入れ子で呼び出せる深さを変更する (デフォルトは 16。0 の場合は `Eval` を使用できない)
+    ///
+    /// 深さを超えて呼び出した場合 (自身を評価し続ける数式など) はエラーとなる
+    pub fn set_eval_budget(&mut self, budget: usize) {
+        self.eval_budget = budget;
+    }
+
+    /// `a ~= b` の許容誤差を変更する (デフォルトは 1e-9)
+    ///
+    /// `a` と `b` の差の絶対値が許容誤差以下であれば 1、そうでなければ 0 となる (`==` は常に厳密に比較する)
+    pub fn set_approx_tolerance(&mut self, tolerance: f64) {
+        self.approx_tolerance = tolerance;
+    }
+
+    /// `Round`, `FixDecimals` の丸め方を変更する (デフォルトは `RoundingMode::HalfUp`)
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.replace_function(Function::new("Round", 1, move |args| mode.round(args[0])));
+        self.replace_function(fix_decimals(mode));
+    }
+
+    /// 初等関数 (`Sin`, `Cos`, `Exp`) を、環境によらず同じ結果を返すソフトウェア実装に切り替える
+    ///
+    /// 標準ライブラリの実装は環境によって最後の 1 ビットが異なることがあるため、
+    /// 結果をビット単位で一致させたい場合に使用する
+    pub fn set_deterministic_math(&mut self, enabled: bool) {
+        for function in transcendental_functions(enabled) {
+            self.replace_function(function);
+        }
+    }
+
+    /// `PowStrict(0, 0)` をエラーとするか (デフォルトは 1 を返す)
+    pub fn set_zero_pow_zero_error(&mut self, enabled: bool) {
+        self.replace_function(pow_strict(enabled));
+    }
+
+    /// 登録されている関数名の一覧 (予約関数を含む)
+    pub fn function_names(&self) -> Vec<&str> {
+        self.functions.iter().map(|f| f.name()).collect()
+    }
+
+    /// 登録されている変数名の一覧 (定数は含まない)
+    pub fn variable_names(&self) -> Vec<&str> {
+        self.variables.iter().map(|v| v.name()).collect()
+    }
+
+    /// 登録されている定数名の一覧 (名前順)
+    pub fn constant_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.constants.keys().map(|k| k.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// 数式を解析し、登録されている関数・変数・定数を用いて評価する
+    pub fn evaluate(&self, input: &str) -> Result<f64, FormulaError> {
+        let values = self.compile(input)?;
+        self.execute(&values, &self.variables)
+    }
+
+    /// 数式を評価する (エラーの場合は、メッセージを生成せずに `CompactError` を返す)
+    ///
+    /// 多くの数式を検証するループなど、エラーの種類のみを調べる場合に使用する。
+    /// メッセージが必要になった場合は `CompactError::to_error` で生成できる
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_code("1 + $") // → Err(CompactError { kind: ErrorKind::Lexer, code: ErrorCode::UnexpectedChar('$'), pos: Some(4), .. })`
+    pub fn evaluate_code(&self, input: &str) -> Result<f64, CompactError> {
+        self.execute_code(input, &self.variables, None)
+    }
+
+    /// 数式を解析し、変数の値を `resolver` から取得して評価する
+    ///
+    /// `resolver` は登録されている変数よりも優先して使用される (定数よりは優先されない)
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_with_resolver("x * 2", &HashMap::from([("x".to_string(), 3.0)])) // → 6.0`
+    pub fn evaluate_with_resolver(
+        &self,
+        input: &str,
+        resolver: &dyn VariableResolver,
+    ) -> Result<f64, FormulaError> {
+        let values = self.compile(input)?;
+        Context::map_processor_error(
+            &values,
+            self.processor(&values, &self.variables)
+                .with_resolver(resolver)
+                .execute(),
+        )
+    }
+
+    /// 数式を評価し、結果とともに評価中に検出した警告 (桁落ちなど) を返す
+    ///
+    /// 警告は評価結果に影響しない
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_checked("(10000000000000000 + 1) - 10000000000000000") // → (0.0, [Warning { kind: PrecisionLoss, .. }])`
+    pub fn evaluate_checked(&self, input: &str) -> Result<(f64, Vec<Warning>), FormulaError> {
+        let values = self.compile(input)?;
+        let mut processor = self.processor(&values, &self.variables);
+        let result = Context::map_processor_error(&values, processor.execute())?;
+        Ok((result, processor.take_warnings()))
+    }
+
+    /// 数式を評価せずに、未定義の変数・関数をすべて検出する
+    ///
+    /// 字句解析・構文解析に失敗した場合は、そのエラーのみを返す
+    ///
+    /// 例
+    ///
+    /// - `context.validate("Foo(a) + b") // → Err([unknown variable "a", unknown function "Foo", unknown variable "b"])`
+    pub fn validate(&self, input: &str) -> Result<(), Vec<FormulaError>> {
+        let values = self.compile(input).map_err(|e| vec![e])?;
+        self.processor(&values, &self.variables)
+            .validate()
+            .map_err(|errors| {
+                errors
+                    .into_iter()
+                    .map(|e| FormulaError {
+                        msg: e.into_message(&values),
+                        error_type: ErrorKind::Processor,
+                    })
+                    .collect()
+            })
+    }
+
+    /// 入力途中の数式を評価する
+    ///
+    /// 数式が途中で終わっているだけの場合は `PartialEvaluation::Incomplete` を返し、
+    /// 続きを入力しても解消しない誤りを含む場合はエラーとする (入力しながら評価するエディタなどで使用する)
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_partial("1 + 2") // → PartialEvaluation::Complete(3.0)`
+    /// - `context.evaluate_partial("1 +") // → PartialEvaluation::Incomplete`
+    /// - `context.evaluate_partial("1 + * 2") // → Err(..)`
+    pub fn evaluate_partial(&self, input: &str) -> Result<PartialEvaluation, FormulaError> {
+        let tokens = match Lexer::with_options(input, self.lexer_options.clone()).tokenize() {
+            Ok(tokens) => tokens,
+            Err(e) if e.is_incomplete(input) => return Ok(PartialEvaluation::Incomplete),
+            Err(e) => {
+                return Err(FormulaError {
+                    msg: e.message(input),
+                    error_type: ErrorKind::Lexer,
+                })
+            }
+        };
+
+        let mut parser = Parser::new(tokens);
+        match parser.parse_partial() {
+            Ok(PartialParse::Complete(values)) => self
+                .execute(&values, &self.variables)
+                .map(PartialEvaluation::Complete),
+            Ok(PartialParse::Incomplete) => Ok(PartialEvaluation::Incomplete),
+            Err(e) => Err(FormulaError {
+                msg: e.message(parser.tokens()),
+                error_type: ErrorKind::Parser,
+            }),
+        }
     }
 
-    Lexer::new(input)
-        .tokenize()
+    /// 数式を評価し、結果とともに数式中で参照されなかった登録済みの変数・関数 (予約関数を除く) を返す
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_with_unused("x + y") // → (3.0, Unused { variables: ["z"], functions: [] })` (`x`, `y`, `z` を登録している場合)
+    pub fn evaluate_with_unused(&self, input: &str) -> Result<(f64, Unused), FormulaError> {
+        let values = self.compile(input)?;
+        let result = self.execute(&values, &self.variables)?;
+
+        let mut referenced_variables = HashSet::new();
+        let mut referenced_functions = HashSet::new();
+        for value in &values {
+            match value {
+                Value::Variable(name) => {
+                    referenced_variables.insert(name.as_str());
+                }
+                // MapSum などに関数名として渡した文字列も、関数の参照とみなす
+                Value::Function(name, _) | Value::KeywordFunction(name, _) | Value::Str(name) => {
+                    referenced_functions.insert(name.as_str());
+                }
+                _ => {}
+            }
+        }
+
+        let mut unused = Unused::default();
+        for name in self.variable_names() {
+            if !referenced_variables.contains(name) && !unused.variables.iter().any(|v| v == name) {
+                unused.variables.push(name.to_string());
+            }
+        }
+        // 予約関数の後ろに、登録した順で関数が並んでいる
+        let registered = &self.functions[self.functions.len() - self.registered_functions..];
+        for name in registered.iter().map(|f| f.name()) {
+            if !referenced_functions.contains(name) && !unused.functions.iter().any(|f| f == name) {
+                unused.functions.push(name.to_string());
+            }
+        }
+
+        Ok((result, unused))
+    }
+
+    /// 数式を評価し、結果とともに評価に関する情報 (評価した要素の数など) を返す
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_detailed("1 + 2") // → EvalResult { value: 3.0, steps: 3, is_finite: true, .. }`
+    pub fn evaluate_detailed(&self, input: &str) -> Result<EvalResult, FormulaError> {
+        let values = self.compile(input)?;
+        Context::map_processor_error(
+            &values,
+            self.processor(&values, &self.variables).execute_detailed(),
+        )
+    }
+
+    /// 数式を評価し、結果とともに条件分岐の関数 (`If`, `Cond` など) で選ばれた分岐を評価順に返す
+    ///
+    /// 評価に失敗した関数呼び出しの分岐は含まれない
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_with_branch_trace("If(1 > 0, 5, 9)") // → (5.0, [BranchTrace { function: "If", branch: 0 }])`
+    pub fn evaluate_with_branch_trace(
+        &self,
+        input: &str,
+    ) -> Result<(f64, Vec<BranchTrace>), FormulaError> {
+        let values = self.compile(input)?;
+        Context::map_processor_error(
+            &values,
+            self.processor(&values, &self.variables)
+                .execute_with_branch_trace(),
+        )
+    }
+
+    /// 数式を評価し、結果を型付きの値 (数値・文字列) として返す
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_value("1 + 2") // → Evaluated::Number(3.0)`
+    /// - `context.evaluate_value("\"a\" + \"b\"") // → Evaluated::Str("ab")`
+    pub fn evaluate_value(&self, input: &str) -> Result<Evaluated, FormulaError> {
+        let values = self.compile(input)?;
+        Context::map_processor_error(
+            &values,
+            self.processor(&values, &self.variables).execute_evaluated(),
+        )
+    }
+
+    /// 数式を評価し、結果が整数であれば `TypedResult::Integer`、そうでなければ `TypedResult::Float` を返す
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_typed("2 * 2") // → TypedResult::Integer(4)`
+    pub fn evaluate_typed(&self, input: &str) -> Result<TypedResult, FormulaError> {
+        self.evaluate(input).map(TypedResult::from)
+    }
+
+    /// 数式を多倍長整数で評価する (`bigint` フィーチャーが必要)
+    ///
+    /// 整数の演算 (`+`, `-`, `*`, `%`, `**`, 割り切れる `/`, 比較演算子) と、
+    /// 関数 `Add`, `Sub`, `Mul`, `Mod`, `Factorial` のみを使用できる。
+    /// 整数でない値・割り切れない除算を含む場合はエラーとなる
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_bigint("2 ** 100") // → 1267650600228229401496703205376`
+    #[cfg(feature = "bigint")]
+    pub fn evaluate_bigint(&self, input: &str) -> Result<BigInt, FormulaError> {
+        let values = self.compile(input)?;
+        bigint::execute(&values, |name| {
+            self.constants
+                .get(name)
+                .copied()
+                .or_else(|| {
+                    self.variables
+                        .iter()
+                        .find(|v| v.name() == name)
+                        .map(|v| v.value())
+                })
+                .or_else(|| reserved_constant(name))
+        })
         .map_err(|e| FormulaError {
             msg: e.msg,
-            error_type: ErrorType::Lexer,
+            error_type: ErrorKind::Processor,
+        })
+    }
+
+    /// 数式を一度だけ解析し、変数 `name` に `indices` の値を順に束縛して評価するイテレータを返す
+    ///
+    /// 評価は値が要求された時点で行われるため、無限に続く `indices` を渡すこともできる
+    ///
+    /// 例
+    ///
+    /// - `context.evaluate_sequence("n * 2", "n", (1..).map(|n| n as f64))?.take(3) // → [2.0, 4.0, 6.0]`
+    pub fn evaluate_sequence<'a, I>(
+        &'a self,
+        input: &str,
+        name: &str,
+        indices: I,
+    ) -> Result<impl Iterator<Item = Result<f64, FormulaError>> + 'a, FormulaError>
+    where
+        I: IntoIterator<Item = f64>,
+        I::IntoIter: 'a,
+    {
+        let values = self.compile(input)?;
+        let name = name.to_string();
+
+        Ok(indices.into_iter().map(move |index| {
+            // 変数の一覧を複製しないよう、束縛する変数は登録されている変数より優先される resolver として渡す
+            let binding = Binding {
+                name: &name,
+                value: index,
+            };
+            Context::map_processor_error(
+                &values,
+                self.processor(&values, &self.variables)
+                    .with_resolver(&binding)
+                    .execute(),
+            )
+        }))
+    }
+
+    /// 配列変数 `array` の各要素を変数 `name` に束縛して `body` を評価し、その総和を返す
+    ///
+    /// `body` の解析は一度だけ行われる。配列が空の場合は 0 を返す
+    ///
+    /// 例
+    ///
+    /// - `arr = [1, 2, 3]` の場合、`context.sum_over("arr", "i", "i * i") // → 14.0`
+    pub fn sum_over(&self, array: &str, name: &str, body: &str) -> Result<f64, FormulaError> {
+        let values = self.arrays.get(array).ok_or_else(|| FormulaError {
+            msg: ErrorCode::UnknownArray.message(Subject::Text(array)),
+            error_type: ErrorKind::Processor,
+        })?;
+
+        self.evaluate_sequence(body, name, values.iter().copied())?
+            .sum()
+    }
+
+    /// 数式を解析し、木構造 (抽象構文木) に変換する
+    pub fn parse_to_ast(&self, input: &str) -> Result<Expr, FormulaError> {
+        let mut parser = self.parser(input)?;
+        parser.parse_ast().map_err(|e| FormulaError {
+            msg: e.message(parser.tokens()),
+            error_type: ErrorKind::Parser,
+        })
+    }
+
+    /// 中間表現 (逆ポーランド記法) を、登録されている関数・変数・定数を用いて評価する
+    ///
+    /// `CompiledFormula::values` で取得したものや、`serde` フィーチャーで保存・復元したものを評価できる
+    pub fn eval_values(&self, values: &[Value]) -> Result<f64, FormulaError> {
+        self.execute(values, &self.variables)
+    }
+
+    /// 木構造 (抽象構文木) を、登録されている関数・変数・定数を用いて評価する
+    pub fn eval_ast(&self, expr: &Expr) -> Result<f64, FormulaError> {
+        self.execute(&expr.to_values(), &self.variables)
+    }
+
+    /// 数式の評価に必要となるスタックの最大の深さを、評価を行わずに返す
+    ///
+    /// 例
+    ///
+    /// - `1 + 2` → 2
+    /// - `1 + (2 + (3 + 4))` → 4
+    pub fn max_stack_depth(&self, input: &str) -> Result<usize, FormulaError> {
+        self.compile(input)
+            .map(|values| parser::max_stack_depth(&values))
+    }
+
+    /// 数式を中間表現 (逆ポーランド記法) に変換し、空白区切りの後置記法の文字列とともに返す
+    ///
+    /// 演算子は記号、関数は関数名、符号反転は `neg` で表す。解析結果の確認や、操車場アルゴリズムの学習に使用する
+    ///
+    /// 例
+    ///
+    /// - `context.parse_with_postfix("(2 + 3) * 4") // → ([2, 3, Plus, 4, Asterisk], "2 3 + 4 *")`
+    pub fn parse_with_postfix(&self, input: &str) -> Result<(Vec<Value>, String), FormulaError> {
+        let mut parser = self.parser(input)?;
+        parser.parse_with_postfix().map_err(|e| FormulaError {
+            msg: e.message(parser.tokens()),
+            error_type: ErrorKind::Parser,
+        })
+    }
+
+    /// 同名の関数を置き換える (存在しない場合は追加する)
+    fn replace_function(&mut self, function: Function) {
+        match self
+            .functions
+            .iter_mut()
+            .find(|f| f.name() == function.name())
+        {
+            Some(f) => *f = function,
+            None => self.functions.push(function),
+        }
+    }
+
+    /// 数式を字句解析・構文解析し、中間表現 (逆ポーランド記法) に変換する
+    fn compile(&self, input: &str) -> Result<Vec<Value>, FormulaError> {
+        let mut parser = self.parser(input)?;
+        parser.parse().map_err(|e| FormulaError {
+            msg: e.message(parser.tokens()),
+            error_type: ErrorKind::Parser,
         })
-        .and_then(|t| {
-            Parser::new(t).parse().map_err(|e| FormulaError {
-                msg: e.msg,
-                error_type: ErrorType::Parser,
+    }
+
+    /// 数式を字句解析し、トークンを解析する Parser を生成する
+    fn parser(&self, input: &str) -> Result<Parser, FormulaError> {
+        Lexer::with_options(input, self.lexer_options.clone())
+            .tokenize()
+            .map(Parser::new)
+            .map_err(|e| FormulaError {
+                msg: e.message(input),
+                error_type: ErrorKind::Lexer,
             })
+    }
+
+    /// 中間表現を評価する
+    fn execute(&self, values: &[Value], variables: &[Variable]) -> Result<f64, FormulaError> {
+        Context::map_processor_error(values, self.processor(values, variables).execute())
+    }
+
+    /// 数式を字句解析・構文解析・評価し、エラーを `CompactError` として返す
+    fn execute_code(
+        &self,
+        input: &str,
+        variables: &[Variable],
+        resolver: Option<&dyn VariableResolver>,
+    ) -> Result<f64, CompactError> {
+        let tokens = Lexer::with_options(input, self.lexer_options.clone())
+            .tokenize()
+            .map_err(|e| CompactError {
+                kind: ErrorKind::Lexer,
+                code: e.code,
+                pos: Some(e.pos),
+                at: None,
+                reproducible: true,
+            })?;
+        let values = Parser::new(tokens).parse().map_err(|e| CompactError {
+            kind: ErrorKind::Parser,
+            code: e.code,
+            pos: None,
+            at: e.at,
+            reproducible: true,
+        })?;
+        let mut processor = self.processor(&values, variables);
+        match resolver {
+            Some(resolver) => processor.with_resolver(resolver).execute(),
+            None => processor.execute(),
+        }
+        .map_err(|e| CompactError {
+            kind: ErrorKind::Processor,
+            code: e.code(),
+            pos: None,
+            at: e.index(),
+            reproducible: e.is_reproducible(),
         })
-        .and_then(|v| {
-            Processor::new(v, all_functions, variables)
-                .execute()
-                .map_err(|e| FormulaError {
-                    msg: e.msg,
-                    error_type: ErrorType::Processor,
-                })
+    }
+
+    /// 登録されている関数・定数・オプションを指定した Processor を生成する
+    fn processor<'a>(&'a self, values: &'a [Value], variables: &'a [Variable]) -> Processor<'a> {
+        Processor::new(values, &self.functions, variables)
+            .with_constants(&self.constants)
+            .with_options(self.processor_options.clone())
+            .with_operators(&self.operators)
+            .with_arrays(&self.arrays)
+            .with_eval_budget(self.eval_budget)
+            .with_lexer_options(&self.lexer_options)
+            .with_approx_tolerance(self.approx_tolerance)
+    }
+
+    fn check_limit(
+        kind: &'static str,
+        count: usize,
+        max: Option<usize>,
+    ) -> Result<(), FormulaError> {
+        match max {
+            Some(limit) if count >= limit => Err(FormulaError {
+                msg: ErrorCode::TooMany { kind, limit }.message(Subject::None),
+                error_type: ErrorKind::Registration,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// 評価のエラーを、評価した中間表現 `values` から生成したメッセージのエラーに変換する
+    fn map_processor_error<T>(
+        values: &[Value],
+        result: Result<T, ProcessorError>,
+    ) -> Result<T, FormulaError> {
+        result.map_err(|e| FormulaError {
+            msg: e.into_message(values),
+            error_type: ErrorKind::Processor,
         })
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// 複数の数式の評価で共有する関数・変数の表
+///
+/// 変数は名前から値を引けるように生成時に一度だけ構築されるため、
+/// 同じ変数を使って多くの数式を評価する場合に `parse_formula` より効率がよい
+pub struct Environment {
+    context: Context,
+    variables: HashMap<String, f64>,
+    /// 計算される変数 (表には含めず、参照のたびに計算する)
+    computed: Vec<Variable>,
+}
 
-    #[test]
-    fn test_execute() {
-        let success_data = [
-            ("4", 4.0),
-            ("-4", -4.0),
-            ("5 - 4 - (1)", 0.0),
-            ("4 - 5", -1.0),
-            ("(1 - 3) * 3", -6.0),
-            ("(-1 + 3) * 3", 6.0),
-            ("(3 - 5) % 3", -2.0),
-            ("1+2*(3*(4+5)+6)*(7+8)+9==1000<10!=1", 0.0),
-            ("1 == 2 * 3 < 1", 1.0),
-            ("5 < 2 * 3", 1.0),
-            ("5 > 2 * 3", 0.0),
-            ("5 != 2 * 3", 1.0),
-            ("5 == 2 * 3", 0.0),
-            ("Add((2 + 3) + 4, 5) + Sub(2, 3)", 13.0),
-            ("If(1 == (2 - 1), 3, 1)", 3.0),
-            ("If(1 != (2 - 1), 3, 1)", 1.0),
-            ("(1 - (2 * 3)) * (4 + 5)", -45.0),
-            ("hoge + fuga * 3 - Add(1, 2)", 11.0),
-            ("Pow(2, 3)", 8.0),
-        ];
-        success_data.map(|(input, expected)| {
-            assert_eq!(
-                parse_formula(
-                    input,
-                    vec![Function::new("Pow", 2, |args| args[0].powf(args[1]))],
-                    vec![Variable::new("hoge", 2.0,), Variable::new("fuga", 4.0,)]
-                ),
-                Ok(expected)
-            );
-        });
+impl Environment {
+    /// 予約関数に `functions` を加えた関数と、`variables` から構築した変数の表を生成する
+    ///
+    /// 同名の変数がある場合は、`parse_formula` と同様に先に指定されたものを使用する
+    pub fn new(functions: Vec<Function>, variables: Vec<Variable>) -> Environment {
+        let mut context = Context::new();
+        for f in functions {
+            context.add_function(f);
+        }
 
-        let failure_data = [
-            "2(3 + 2)",
-            "1 ! 3",
-            "1 ==",
-            "== 3",
-            "=",
-            "2+=3",
-            "2 - 3 (()) + 3)",
-            "()",
-            "",
-            "hello world",
-            "add(2, 3)",
-            "Add(2)",
-            "add + 2 / 3",
-        ];
+        Environment::with_context(context, variables)
+    }
 
-        failure_data.map(|input| {
-            assert_eq!(
-                (parse_formula(input, vec![], vec![]).is_err(), input),
-                (true, input)
-            );
-        });
+    /// `context` の関数・定数・オプションを使用する
+    pub fn with_context(context: Context, variables: Vec<Variable>) -> Environment {
+        let (variables, computed) = variable_map(variables);
+        Environment {
+            context,
+            variables,
+            computed,
+        }
+    }
+
+    /// 数式を解析し、共有している関数・変数を用いて評価する
+    pub fn evaluate(&self, input: &str) -> Result<f64, FormulaError> {
+        let values = self.context.compile(input)?;
+        Context::map_processor_error(
+            &values,
+            self.context
+                .processor(&values, &self.computed)
+                .with_variable_map(&self.variables)
+                .execute(),
+        )
+    }
+}
+
+/// 数式の文字列をキーとして、コンパイル済みの中間表現を保持するキャッシュ
+///
+/// 同じ数式が繰り返し評価される場合に、字句解析・構文解析を省略する。
+/// 保持する数式の数が `capacity` を超える場合は、最後に使われてから最も時間が経ったもの (LRU) を破棄する
+pub struct FormulaCache {
+    context: Context,
+    capacity: usize,
+    /// 数式と、その中間表現・最後に使われた時刻 (`clock` の値) の組
+    entries: HashMap<String, (Vec<Value>, u64)>,
+    clock: u64,
+    compile_count: usize,
+}
+
+impl FormulaCache {
+    /// 予約関数に `functions` を加えた関数を使用するキャッシュを生成する
+    pub fn new(capacity: usize, functions: Vec<Function>) -> FormulaCache {
+        let mut context = Context::new();
+        for f in functions {
+            context.add_function(f);
+        }
+        FormulaCache::with_context(capacity, context)
+    }
+
+    /// `context` の関数・定数・オプションを使用する (`context` の変数は使用しない)
+    pub fn with_context(capacity: usize, context: Context) -> FormulaCache {
+        FormulaCache {
+            context,
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            clock: 0,
+            compile_count: 0,
+        }
+    }
+
+    /// 数式を評価する (キャッシュにない場合のみ字句解析・構文解析を行う)
+    pub fn eval(&mut self, input: &str, variables: &[Variable]) -> Result<f64, FormulaError> {
+        self.clock += 1;
+
+        if let Some((values, last_used)) = self.entries.get_mut(input) {
+            *last_used = self.clock;
+            return self.context.execute(values, variables);
+        }
+
+        let values = self.context.compile(input)?;
+        self.compile_count += 1;
+        let result = self.context.execute(&values, variables);
+
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                self.evict();
+            }
+            self.entries.insert(input.to_string(), (values, self.clock));
+        }
+
+        result
+    }
+
+    /// 字句解析・構文解析を行った回数
+    pub fn compile_count(&self) -> usize {
+        self.compile_count
+    }
+
+    /// 保持している数式の数
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 最後に使われてから最も時間が経った数式を破棄する
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(input, _)| input.clone());
+        if let Some(input) = oldest {
+            self.entries.remove(&input);
+        }
+    }
+}
+
+/// 字句解析・構文解析を済ませた数式
+///
+/// `compile_formula` で生成し、変数の値を変えて何度でも評価できる
+pub struct CompiledFormula {
+    context: Context,
+    values: Vec<Value>,
+}
+
+impl CompiledFormula {
+    /// 数式が定数 (`is_constant` を参照) であれば評価し、中間表現をその結果の数値 1 つに置き換える
+    ///
+    /// 定数でない場合や、評価に失敗する場合 (`Sqrt(-1)` など) は何もしない (失敗する場合は `evaluate` でエラーとなる)
+    ///
+    /// 例
+    ///
+    /// - `compile_formula("2 * 3 + 1", vec![])?.precompute().values() // → [Value::Number(7.0)]`
+    pub fn precompute(mut self) -> CompiledFormula {
+        if let Ok(true) = is_constant_values(&self.values, self.context.functions.iter()) {
+            if let Ok(num) = self.context.execute(&self.values, &[]) {
+                self.values = vec![Value::Number(num)];
+            }
+        }
+        self
+    }
+
+    /// 中間表現 (逆ポーランド記法)
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// 解析済みの数式を `variables` を用いて評価する (字句解析・構文解析は行わない)
+    ///
+    /// 同名の変数がある場合は、`parse_formula` と同様に先に指定されたものを使用する
+    pub fn evaluate(&self, variables: Vec<Variable>) -> Result<f64, FormulaError> {
+        self.context.execute(&self.values, &variables)
+    }
+
+    /// 列 (変数名と値の配列) の各行を変数に束縛して評価し、結果を `out` の同じ行に書き込む
+    ///
+    /// 結果の配列を確保しないため、`out` を使い回すことでバッチごとの確保を省略できる。
+    /// 列と `out` の長さが異なる場合はエラーとなる。評価に失敗した場合、それより前の行の結果は書き込まれたままとなる
+    ///
+    /// 例
+    ///
+    /// - `x = [1, 2, 3]` の場合、`compile_formula("x * 2", vec![])?.eval_into(&columns, &mut out)` → `out = [2.0, 4.0, 6.0]`
+    pub fn eval_into(
+        &self,
+        columns: &HashMap<String, &[f64]>,
+        out: &mut [f64],
+    ) -> Result<(), FormulaError> {
+        if let Some((name, column)) = columns.iter().find(|(_, c)| c.len() != out.len()) {
+            return Err(FormulaError {
+                msg: ErrorCode::ColumnLength {
+                    length: column.len(),
+                    expected: out.len(),
+                }
+                .message(Subject::Text(name)),
+                error_type: ErrorKind::Processor,
+            });
+        }
+
+        // 変数の表は一度だけ構築し、行ごとに値のみを書き換える
+        let mut variables: HashMap<String, f64> =
+            columns.keys().map(|name| (name.to_string(), 0.0)).collect();
+        for (i, result) in out.iter_mut().enumerate() {
+            for (name, column) in columns {
+                if let Some(value) = variables.get_mut(name) {
+                    *value = column[i];
+                }
+            }
+            *result = Context::map_processor_error(
+                &self.values,
+                self.context
+                    .processor(&self.values, &[])
+                    .with_variable_map(&variables)
+                    .execute(),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
+/// 初等関数 (`deterministic` が true の場合はソフトウェア実装を使用する)
+fn transcendental_functions(deterministic: bool) -> Vec<Function> {
+    if deterministic {
+        vec![
+            Function::new("Sin", 1, |args| math::sin(args[0])),
+            Function::new("Cos", 1, |args| math::cos(args[0])),
+            Function::new("Exp", 1, |args| math::exp(args[0])),
+        ]
+    } else {
+        vec![
+            Function::new("Sin", 1, |args| args[0].sin()),
+            Function::new("Cos", 1, |args| args[0].cos()),
+            Function::new("Exp", 1, |args| args[0].exp()),
+        ]
+    }
+}
+
+/// `Div`, `Mod` (`strict` が true の場合は、右辺が 0 のときにエラーとする)
+fn division_functions(strict: bool) -> [Function; 2] {
+    let function = move |name: &'static str, calc: fn(f64, f64) -> f64| {
+        Function::fallible_with_code(name, Some(2), move |args| {
+            if strict && args[1] == 0.0 {
+                Err(ErrorCode::FunctionDivisionByZero {
+                    function: name,
+                    provided: (args[0], args[1]),
+                })
+            } else {
+                Ok(calc(args[0], args[1]))
+            }
+        })
+    };
+    [function("Div", |a, b| a / b), function("Mod", |a, b| a % b)]
+}
+
+/// 整数 `x` を 2 で割った余り (0 または 1) を返す (整数でない場合はエラー)
+fn parity(name: &'static str, x: f64) -> Result<f64, ErrorCode> {
+    if x.fract() != 0.0 {
+        return Err(ErrorCode::InvalidArgument {
+            function: name,
+            argument: "argument",
+            requirement: "must be an integer",
+            provided: x,
+        });
+    }
+    Ok(x.rem_euclid(2.0))
+}
+
+/// 非負整数 `n` (小数部分は切り捨てる) を表すのに必要なビット数を返す (0 の場合は 0)
+fn bit_length(n: f64) -> Result<f64, ErrorCode> {
+    let truncated = n.trunc();
+    if !truncated.is_finite() || truncated < 0.0 {
+        return Err(ErrorCode::InvalidArgument {
+            function: "BitLength",
+            argument: "argument",
+            requirement: "must be a non-negative integer",
+            provided: n,
+        });
+    }
+    if truncated < 1.0 {
+        return Ok(0.0);
+    }
+
+    // 1 以上の有限の値の指数部は floor(log2(n)) と等しい
+    let exponent = ((truncated.to_bits() >> 52) & 0x7ff) as i64 - 1023;
+    Ok((exponent + 1) as f64)
+}
+
+/// 引数を `select` で畳み込む (引数に NaN を含む場合は NaN を返す)
+fn extreme(
+    name: &'static str,
+    args: &[f64],
+    select: fn(f64, f64) -> f64,
+) -> Result<f64, ErrorCode> {
+    if args.iter().any(|x| x.is_nan()) {
+        return Ok(f64::NAN);
+    }
+    args.iter()
+        .copied()
+        .reduce(select)
+        .ok_or_else(|| no_args_error(name))
+}
+
+/// 1 つ以上の引数が必要な関数に、引数を指定しなかった場合のエラー
+fn no_args_error(name: &'static str) -> ErrorCode {
+    ErrorCode::InvalidArgsCount {
+        function: name,
+        requirement: "at least 1",
+        provided: 0,
+    }
+}
+
+/// 先頭の引数を除いた引数を `select` で畳み込む (先頭以外の引数がない場合は先頭の引数を返す)
+fn extreme_or(
+    name: &'static str,
+    args: &[f64],
+    select: fn(f64, f64) -> f64,
+) -> Result<f64, ErrorCode> {
+    match args.split_first() {
+        None => Err(no_args_error(name)),
+        Some((default, [])) => Ok(*default),
+        Some((_, rest)) => extreme(name, rest, select),
+    }
+}
+
+/// `prefer(a, b)` が真となる a を優先して、最も優先される引数の位置 (1 から始まる) を返す
+fn arg_extreme(
+    name: &'static str,
+    args: &[f64],
+    prefer: impl Fn(f64, f64) -> bool,
+) -> Result<f64, ErrorCode> {
+    if args.is_empty() {
+        return Err(no_args_error(name));
+    }
+
+    let mut best = 0;
+    for (i, arg) in args.iter().enumerate().skip(1) {
+        if prefer(*arg, args[best]) {
+            best = i;
+        }
+    }
+    Ok((best + 1) as f64)
+}
+
+/// `Factorial` の引数の上限 (171! 以上は f64 で表現できず無限大となる)
+const MAX_FACTORIAL_ARGUMENT: f64 = 170.0;
+
+/// デフォルトで使用できる定数
+///
+/// 大文字から始まるが、`(` が続かない場合は変数として扱われる。
+/// 同名の変数・定数が登録されている場合は、そちらが優先される
+const RESERVED_CONSTANTS: [(&str, f64); 3] = [
+    ("PI", std::f64::consts::PI),
+    ("E", std::f64::consts::E),
+    ("TAU", std::f64::consts::TAU),
+];
+
+/// デフォルトで使用できる定数の値を返す
+pub(crate) fn reserved_constant(name: &str) -> Option<f64> {
+    RESERVED_CONSTANTS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| *value)
+}
+
+/// デフォルトで使用できる関数
+fn reserved_functions() -> Vec<Function> {
+    let [div, modulo] = division_functions(false);
+    let mut functions = vec![
+        Function::new("Add", 2, |args| args[0] + args[1]),
+        Function::new("Sub", 2, |args| args[0] - args[1]),
+        Function::new("Mul", 2, |args| args[0] * args[1]),
+        div,
+        modulo,
+        // If(test, then, else)
+        // 選ばれなかった分岐は評価されない
+        Function::conditional("If", Some(3), |args| {
+            Ok(match args.get(0) {
+                // test の評価に失敗した場合は、そのエラーを返す
+                None => 0,
+                Some(test) if test != 0.0 => 1,
+                _ => 2,
+            })
+        })
+        .with_branch_index(|index| index - 1),
+        // Cond(test1, value1, test2, value2, ..., default)
+        // 最初に 0 以外となった test に対応する value を返し、いずれも 0 の場合は default を返す
+        Function::conditional_with_code("Cond", None, |args| {
+            if args.len() % 2 == 0 {
+                return Err(ErrorCode::InvalidArgsCount {
+                    function: "Cond",
+                    requirement: "odd",
+                    provided: args.len(),
+                });
+            }
+            for i in (0..args.len() - 1).step_by(2) {
+                match args.get(i) {
+                    // test の評価に失敗した場合は、そのエラーを返す
+                    None => return Ok(i),
+                    Some(test) if test != 0.0 => return Ok(i + 1),
+                    _ => {}
+                }
+            }
+            Ok(args.len() - 1)
+        })
+        .with_branch_index(|index| index / 2),
+        // Piecewise(cond1, value1, cond2, value2, ...)
+        // 最初に 0 以外となった cond に対応する value を返し、いずれも 0 の場合はエラーとする
+        Function::conditional_with_code("Piecewise", None, |args| {
+            if args.is_empty() || args.len() % 2 != 0 {
+                return Err(ErrorCode::InvalidArgsCount {
+                    function: "Piecewise",
+                    requirement: "a positive even number",
+                    provided: args.len(),
+                });
+            }
+            for i in (0..args.len()).step_by(2) {
+                match args.get(i) {
+                    // cond の評価に失敗した場合は、そのエラーを返す
+                    None => return Ok(i),
+                    Some(cond) if cond != 0.0 => return Ok(i + 1),
+                    _ => {}
+                }
+            }
+            Err(ErrorCode::NoConditionMatched {
+                function: "Piecewise",
+            })
+        })
+        .with_branch_index(|index| index / 2),
+        // Choose(index, v1, v2, ...)
+        // 1 から始まる index 番目の v を返す
+        Function::conditional_with_code("Choose", None, |args| {
+            if args.is_empty() {
+                return Err(no_args_error("Choose"));
+            }
+            match args.get(0) {
+                // index の評価に失敗した場合は、そのエラーを返す
+                None => Ok(0),
+                Some(index) => {
+                    if index.fract() != 0.0 || index < 1.0 || index >= args.len() as f64 {
+                        Err(ErrorCode::IndexOutOfRange {
+                            function: "Choose",
+                            max: args.len() - 1,
+                            provided: index,
+                        })
+                    } else {
+                        Ok(index as usize)
+                    }
+                }
+            }
+        })
+        .with_branch_index(|index| index - 1),
+        // Default(expr, fallback)
+        // expr の評価に失敗した場合は fallback を返す
+        // fallback は expr の評価に失敗した場合のみ評価される
+        Function::conditional("Default", Some(2), |args| {
+            Ok(if args.get(0).is_some() { 0 } else { 1 })
+        }),
+        // 0 の場合は 0 を返す (f64::signum は +0.0 に対して 1.0 を返すため使用しない)
+        Function::new("Sign", 1, |args| {
+            if args[0] > 0.0 {
+                1.0
+            } else if args[0] < 0.0 {
+                -1.0
+            } else {
+                args[0]
+            }
+        }),
+        Function::new("CopySign", 2, |args| args[0].copysign(args[1])),
+        // 日時は UNIX エポックからの秒数で表現する
+        Function::new("Now", 0, |_| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0)
+        })
+        .impure(),
+        Function::new("Days", 1, |args| args[0] * 86400.0),
+        // Eval(formula)
+        // 文字列として渡された数式を評価する (入れ子で呼び出せる深さは Context::set_eval_budget で変更できる)
+        Function::eval(),
+        // MapSum("F", a1, a2, ...)
+        // 引数 1 つの関数 F を各 a に適用した結果の合計 (F(a1) + F(a2) + ... と同じ)
+        Function::map_sum(),
+        Function::new("Round", 1, |args| RoundingMode::HalfUp.round(args[0])),
+        // Round と異なり、丸め方は set_rounding_mode の影響を受けない
+        Function::new("Floor", 1, |args| args[0].floor()),
+        Function::new("Ceil", 1, |args| args[0].ceil()),
+        // 0 の方向に切り捨てる (負の数に対して Floor と結果が異なる)
+        Function::new("Trunc", 1, |args| args[0].trunc()),
+        // 第 4 引数が 0 以外の場合は境界値を含む
+        Function::new("InRange", 4, |args| {
+            let (x, lo, hi) = (args[0], args[1], args[2]);
+            let in_range = if args[3] != 0.0 {
+                lo <= x && x <= hi
+            } else {
+                lo < x && x < hi
+            };
+            if in_range {
+                1.0
+            } else {
+                0.0
+            }
+        }),
+        // % と異なり、負の数に対しても [0, modulus) の範囲の値を返す
+        Function::fallible_with_code("Wrap", Some(2), |args| {
+            if args[1] <= 0.0 {
+                Err(ErrorCode::InvalidArgument {
+                    function: "Wrap",
+                    argument: "modulus",
+                    requirement: "must be positive",
+                    provided: args[1],
+                })
+            } else {
+                Ok(args[0].rem_euclid(args[1]))
+            }
+        }),
+        // 最大値・最小値 (NaN を含む場合は NaN を返す)
+        Function::fallible_with_code("Max", None, |args| extreme("Max", &args, f64::max)),
+        Function::fallible_with_code("Min", None, |args| extreme("Min", &args, f64::min)),
+        // MaxOr(default, a1, a2, ...), MinOr(default, a1, a2, ...)
+        // a1, a2, ... の最大値・最小値 (a1 以降がない場合は default を返す)
+        Function::fallible_with_code("MaxOr", None, |args| extreme_or("MaxOr", &args, f64::max)),
+        Function::fallible_with_code("MinOr", None, |args| extreme_or("MinOr", &args, f64::min)),
+        // NaN を除いた最大値・最小値 (すべて NaN の場合は NaN を返す)
+        Function::variadic("MaxIgnoreNan", |args| {
+            args.into_iter()
+                .filter(|x| !x.is_nan())
+                .reduce(f64::max)
+                .unwrap_or(f64::NAN)
+        }),
+        Function::variadic("MinIgnoreNan", |args| {
+            args.into_iter()
+                .filter(|x| !x.is_nan())
+                .reduce(f64::min)
+                .unwrap_or(f64::NAN)
+        }),
+        // 最大値・最小値の位置 (1 から始まる。同じ値が複数ある場合は最初の位置を返す)
+        // Choose と組み合わせて使用する e.g. Choose(ArgMax(a, b, c), x, y, z)
+        Function::fallible_with_code("ArgMax", None, |args| {
+            arg_extreme("ArgMax", &args, |a, b| a > b)
+        }),
+        Function::fallible_with_code("ArgMin", None, |args| {
+            arg_extreme("ArgMin", &args, |a, b| a < b)
+        }),
+        // SafeDiv(a, b, default)
+        // b が 0 の場合は a / b の代わりに default を返す
+        Function::new("SafeDiv", 3, |args| {
+            if args[1] == 0.0 {
+                args[2]
+            } else {
+                args[0] / args[1]
+            }
+        }),
+        // 引数の数・threshold より大きい引数の数
+        Function::variadic("Count", |args| args.len() as f64),
+        Function::fallible_with_code("CountIf", None, |args| match args.split_first() {
+            None => Err(no_args_error("CountIf")),
+            Some((threshold, values)) => {
+                Ok(values.iter().filter(|v| *v > threshold).count() as f64)
+            }
+        }),
+        // 引数の総和・平均 (配列変数を 1 つだけ渡した場合は、その要素を引数とする e.g. Sum(arr))
+        // 空の場合に -0.0 とならないよう、0.0 から足し合わせる
+        Function::variadic("Sum", |args| args.iter().fold(0.0, |acc, arg| acc + arg)),
+        Function::fallible_with_code("Average", None, |args| {
+            if args.is_empty() {
+                Err(no_args_error("Average"))
+            } else {
+                Ok(args.iter().sum::<f64>() / args.len() as f64)
+            }
+        }),
+        // (x1, y1) と (x2, y2) のユークリッド距離・その 2 乗
+        Function::new("Dist", 4, |args| {
+            (args[2] - args[0]).hypot(args[3] - args[1])
+        }),
+        Function::new("Dist2", 4, |args| {
+            let (dx, dy) = (args[2] - args[0], args[3] - args[1]);
+            dx * dx + dy * dy
+        }),
+        // 3 次元・任意の次元のベクトルの長さ (途中でオーバーフローしないよう hypot を重ねて計算する)
+        Function::new("Hypot3", 3, |args| args[0].hypot(args[1]).hypot(args[2])),
+        Function::variadic("Norm", |args| args.into_iter().fold(0.0, f64::hypot)),
+        // 0 以上の整数のみを受け付ける (結果が大きい場合は Context::evaluate_bigint を使用する)
+        Function::fallible_with_code("Factorial", Some(1), |args| {
+            if args[0] < 0.0 || args[0].fract() != 0.0 {
+                Err(ErrorCode::InvalidArgument {
+                    function: "Factorial",
+                    argument: "argument",
+                    requirement: "must be a non-negative integer",
+                    provided: args[0],
+                })
+            } else if args[0] > MAX_FACTORIAL_ARGUMENT {
+                // 171! 以上は f64 で表現できない (大きな値でループし続けないよう、計算する前に判定する)
+                Err(ErrorCode::FactorialTooLarge(args[0]))
+            } else {
+                Ok((1..=args[0] as u64).fold(1.0, |acc, i| acc * i as f64))
+            }
+        }),
+        // 小数部分を切り捨てた整数どうしで割り切れるかを判定する
+        Function::fallible_with_code("IsDivisibleBy", Some(2), |args| {
+            let (a, b) = (args[0].trunc(), args[1].trunc());
+            if b == 0.0 {
+                Err(ErrorCode::InvalidArgument {
+                    function: "IsDivisibleBy",
+                    argument: "divisor",
+                    requirement: "must not be zero",
+                    provided: args[1],
+                })
+            } else {
+                Ok(if a % b == 0.0 { 1.0 } else { 0.0 })
+            }
+        }),
+        // 偶数・奇数であれば 1 (整数でない場合はエラー)
+        Function::fallible_with_code("IsEven", Some(1), |args| {
+            parity("IsEven", args[0]).map(|rem| if rem == 0.0 { 1.0 } else { 0.0 })
+        }),
+        Function::fallible_with_code("IsOdd", Some(1), |args| {
+            parity("IsOdd", args[0]).map(|rem| if rem == 0.0 { 0.0 } else { 1.0 })
+        }),
+        Function::new("Clamp01", 1, |args| args[0].clamp(0.0, 1.0)),
+        // Clamp(x, lo, hi) は x を [lo, hi] に収めた値、WasClamped(x, lo, hi) は x が [lo, hi] の範囲外であれば 1
+        Function::fallible_with_code("Clamp", Some(3), |args| {
+            check_clamp_range("Clamp", args[1], args[2]).map(|_| args[0].clamp(args[1], args[2]))
+        }),
+        Function::fallible_with_code("WasClamped", Some(3), |args| {
+            check_clamp_range("WasClamped", args[1], args[2]).map(|_| {
+                if args[0] < args[1] || args[0] > args[2] {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+        }),
+        // [min, max] を [0, 1] に写す (範囲外の値はそのまま外側に写す)
+        Function::fallible_with_code("Normalize", Some(3), |args| {
+            if args[1] == args[2] {
+                Err(ErrorCode::InvalidArgument {
+                    function: "Normalize",
+                    argument: "min and max",
+                    requirement: "must be different",
+                    provided: args[1],
+                })
+            } else {
+                Ok((args[0] - args[1]) / (args[2] - args[1]))
+            }
+        }),
+        // Smoothstep(edge0, edge1, x)
+        // x を [edge0, edge1] から [0, 1] に写して範囲内に収め、エルミート補間 t * t * (3 - 2 * t) を行う
+        Function::fallible_with_code("Smoothstep", Some(3), |args| {
+            if args[0] == args[1] {
+                Err(ErrorCode::InvalidArgument {
+                    function: "Smoothstep",
+                    argument: "edge0 and edge1",
+                    requirement: "must be different",
+                    provided: args[0],
+                })
+            } else {
+                let t = ((args[2] - args[0]) / (args[1] - args[0])).clamp(0.0, 1.0);
+                Ok(t * t * (3.0 - 2.0 * t))
+            }
+        }),
+    ];
+    functions.extend(transcendental_functions(false));
+    functions.push(Function::new("Ln", 1, |args| args[0].ln()));
+    // 0 に近い値に対して Exp(x) - 1, Ln(1 + x) より精度よく計算する
+    functions.push(Function::new("Expm1", 1, |args| args[0].exp_m1()));
+    functions.push(Function::new("Log1p", 1, |args| args[0].ln_1p()));
+    functions.push(Function::fallible_with_code("Sqrt", Some(1), |args| {
+        if args[0] < 0.0 {
+            Err(ErrorCode::InvalidArgument {
+                function: "Sqrt",
+                argument: "argument",
+                requirement: "must be non-negative",
+                provided: args[0],
+            })
+        } else {
+            Ok(args[0].sqrt())
+        }
+    }));
+    // 立方根は負の数に対しても定義される
+    functions.push(Function::new("Cbrt", 1, |args| args[0].cbrt()));
+    functions.push(Function::fallible_with_code("Log2", Some(1), |args| {
+        if args[0] <= 0.0 {
+            Err(ErrorCode::InvalidArgument {
+                function: "Log2",
+                argument: "argument",
+                requirement: "must be positive",
+                provided: args[0],
+            })
+        } else {
+            Ok(args[0].log2())
+        }
+    }));
+    functions.push(Function::fallible_with_code("BitLength", Some(1), |args| {
+        bit_length(args[0])
+    }));
+    // 活性化関数 (Sigmoid は exp の引数が常に 0 以下となるよう符号で分け、オーバーフローを避ける)
+    functions.push(Function::new("Sigmoid", 1, |args| {
+        let x = args[0];
+        if x >= 0.0 {
+            1.0 / (1.0 + (-x).exp())
+        } else {
+            let e = x.exp();
+            e / (1.0 + e)
+        }
+    }));
+    functions.push(Function::new("Tanh", 1, |args| args[0].tanh()));
+    functions.push(Function::new("Relu", 1, |args| args[0].max(0.0)));
+    functions.push(pow_strict(false));
+    functions.push(fix_decimals(RoundingMode::HalfUp));
+    functions
+}
+
+/// x を小数点以下 n 桁に丸める関数 (n が 0 以上の整数でない場合はエラー)
+///
+/// 10^n 倍した値を `mode` で丸めてから 10^n で割る。10^n 倍すると有限でなくなる場合は x をそのまま返す
+fn fix_decimals(mode: RoundingMode) -> Function {
+    Function::fallible_with_code("FixDecimals", Some(2), move |args| {
+        let (x, n) = (args[0], args[1]);
+        if n < 0.0 || n.fract() != 0.0 {
+            return Err(ErrorCode::InvalidArgument {
+                function: "FixDecimals",
+                argument: "digits",
+                requirement: "must be a non-negative integer",
+                provided: n,
+            });
+        }
+
+        let scale = 10f64.powf(n);
+        if (x * scale).is_finite() {
+            Ok(mode.round(x * scale) / scale)
+        } else {
+            Ok(x)
+        }
+    })
+}
+
+/// `Clamp`, `WasClamped` の範囲 [lo, hi] が正しいか (`f64::clamp` は lo > hi や NaN の場合に panic する)
+fn check_clamp_range(name: &'static str, lo: f64, hi: f64) -> Result<(), ErrorCode> {
+    if lo <= hi {
+        Ok(())
+    } else {
+        Err(ErrorCode::InvalidArguments {
+            function: name,
+            argument: "range",
+            requirement: "is invalid, lo must be less than or equal to hi",
+            provided: (lo, hi),
+        })
+    }
+}
+
+/// 負の底に整数でない指数を指定した場合にエラーとなるべき乗 (`zero_pow_zero_error` が true の場合は 0 の 0 乗もエラーとする)
+fn pow_strict(zero_pow_zero_error: bool) -> Function {
+    Function::fallible_with_code("PowStrict", Some(2), move |args| {
+        let (base, exp) = (args[0], args[1]);
+        if base < 0.0 && exp.fract() != 0.0 {
+            Err(ErrorCode::InvalidArguments {
+                function: "PowStrict",
+                argument: "negative base",
+                requirement: "requires an integer exponent",
+                provided: (base, exp),
+            })
+        } else if zero_pow_zero_error && base == 0.0 && exp == 0.0 {
+            Err(ErrorCode::ZeroToThePowerOfZero {
+                function: "PowStrict",
+            })
+        } else {
+            Ok(base.powf(exp))
+        }
+    })
+}
+
+/// 数式を解析する
+///
+/// 例
+///
+/// - `parse_formula("(1 + 2) * 3", vec![], vec![]) // → 9`
+/// - `parse_formula(
+///  "Pow(2, 3) + 3",
+///  vec![Function::new("Pow", 2, |args| args[0].powf(args[1]))],
+///  vec![]
+/// ) // → 11.0`
+pub fn parse_formula(
+    input: &str,
+    functions: Vec<Function>,
+    variables: Vec<Variable>,
+) -> Result<f64, FormulaError> {
+    let mut context = Context::new();
+    for f in functions {
+        context.add_function(f);
+    }
+
+    // 変数の参照ごとに線形探索しないよう、名前から値を引ける表を構築する
+    let (variables, computed) = variable_map(variables);
+    for v in computed {
+        context.add_variable(v);
+    }
+    context.evaluate_with_resolver(input, &variables)
+}
+
+/// 数式を解析する (エラーの場合は、メッセージを生成せずに `CompactError` を返す)
+///
+/// メッセージが必要になった場合は、`to_error(&Context::new(), input)` で生成できる
+///
+/// 例
+///
+/// - `parse_formula_code("1 +", vec![], vec![]) // → Err(CompactError { kind: ErrorKind::Lexer, code: ErrorCode::UnexpectedEndOfLine, pos: Some(3), .. })`
+pub fn parse_formula_code(
+    input: &str,
+    functions: Vec<Function>,
+    variables: Vec<Variable>,
+) -> Result<f64, CompactError> {
+    let mut context = Context::new();
+    for f in functions {
+        context.add_function(f);
+    }
+
+    let (variables, computed) = variable_map(variables);
+    for v in computed {
+        context.add_variable(v);
+    }
+    context.execute_code(input, &context.variables, Some(&variables))
+}
+
+/// 変数の名前から値を引ける表を構築する (同名の変数がある場合は最初のものを使用する)
+///
+/// 計算される変数は参照のたびに計算する必要があるため、表には含めずに別に返す
+fn variable_map(variables: Vec<Variable>) -> (HashMap<String, f64>, Vec<Variable>) {
+    let mut map = HashMap::with_capacity(variables.len());
+    let mut computed: Vec<Variable> = vec![];
+    for v in variables {
+        if map.contains_key(v.name()) || computed.iter().any(|c| c.name() == v.name()) {
+            continue;
+        }
+        if v.is_computed() {
+            computed.push(v);
+        } else {
+            map.insert(v.name().to_string(), v.value());
+        }
+    }
+    (map, computed)
+}
+
+/// 1 つの変数のみを解決するもの (`Context::evaluate_sequence` で使用する)
+struct Binding<'a> {
+    name: &'a str,
+    value: f64,
+}
+
+impl VariableResolver for Binding<'_> {
+    fn resolve(&self, name: &str) -> Option<f64> {
+        (name == self.name).then_some(self.value)
+    }
+}
+
+/// 数式を字句解析・構文解析し、予約関数に `functions` を加えた関数とともに保持する
+///
+/// 例
+///
+/// - `compile_formula("x * 2", vec![])?.evaluate(vec![Variable::new("x", 3.0)]) // → 6.0`
+pub fn compile_formula(
+    input: &str,
+    functions: Vec<Function>,
+) -> Result<CompiledFormula, FormulaError> {
+    let mut context = Context::new();
+    for f in functions {
+        context.add_function(f);
+    }
+
+    let values = context.compile(input)?;
+    Ok(CompiledFormula { context, values })
+}
+
+/// 数式を字句解析し、トークン列を返す (空白・コメントは含まない)
+///
+/// 例
+///
+/// - `tokenize("Add(1, 2) * 3") // → [Property("Add"), LeftParenthesis, Number(1.0), Comma, Number(2.0), RightParenthesis, Asterisk, Number(3.0)]`
+pub fn tokenize(input: &str) -> Result<Vec<Token>, FormulaError> {
+    Lexer::new(input).tokenize().map_err(|e| FormulaError {
+        msg: e.message(input),
+        error_type: ErrorKind::Lexer,
+    })
+}
+
+/// 数式を解析し、木構造 (抽象構文木) に変換する
+///
+/// 例
+///
+/// - `parse_to_ast("1 + 2") // → Expr::BinOp(Value::Plus, Num(1.0), Num(2.0))`
+pub fn parse_to_ast(input: &str) -> Result<Expr, FormulaError> {
+    Context::new().parse_to_ast(input)
+}
+
+/// 式 `expr` を変数 `var` について微分する
+///
+/// 四則演算・べき乗と、予約関数 `Sin`, `Cos`, `Exp`, `Ln` に対応している
+///
+/// 例
+///
+/// - `differentiate(&parse_to_ast("Sin(x)")?, "x") // → Expr::Call("Cos", [Var("x")])`
+pub fn differentiate(expr: &Expr, var: &str) -> Result<Expr, FormulaError> {
+    symbolic::differentiate(expr, var).map_err(|e| FormulaError {
+        msg: e.message(),
+        error_type: ErrorKind::Symbolic,
+    })
+}
+
+/// 共有している関数・変数の表を用いて数式を解析する
+///
+/// 例
+///
+/// - `parse_formula_with_environment("x + 1", &Environment::new(vec![], vec![Variable::new("x", 2.0)])) // → 3.0`
+pub fn parse_formula_with_environment(
+    input: &str,
+    environment: &Environment,
+) -> Result<f64, FormulaError> {
+    environment.evaluate(input)
+}
+
+/// 数式が変数・純粋でない関数 (`Now` など) を含まず、常に同じ値に評価されるかを判定する
+///
+/// `functions` には予約関数以外に使用する関数を指定する。未登録の関数を含む場合はエラーとなる
+///
+/// 例
+///
+/// - `is_constant("2 * 3", &[]) // → true`
+/// - `is_constant("x + 1", &[]) // → false`
+pub fn is_constant(input: &str, functions: &[Function]) -> Result<bool, FormulaError> {
+    let context = Context::new();
+    // 予約関数を優先する (Context::add_function で登録した場合と同じ順番)
+    is_constant_values(
+        &context.compile(input)?,
+        context.functions.iter().chain(functions),
+    )
+}
+
+/// 中間表現が変数・純粋でない関数を含まないかを判定する (`functions` に含まれない関数がある場合はエラー)
+fn is_constant_values<'a>(
+    values: &[Value],
+    functions: impl Iterator<Item = &'a Function> + Clone,
+) -> Result<bool, FormulaError> {
+    for value in values {
+        match value {
+            Value::Variable(_) => return Ok(false),
+            Value::Function(name, _) | Value::KeywordFunction(name, _) => {
+                let function = functions
+                    .clone()
+                    .find(|f| f.name() == name)
+                    .ok_or_else(|| FormulaError {
+                        msg: ErrorCode::UnknownFunction.message(Subject::Text(name)),
+                        error_type: ErrorKind::Processor,
+                    })?;
+                if !function.is_pure() {
+                    return Ok(false);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(true)
+}
+
+/// テンプレート中のプレースホルダ `{name}` を、`subs` に登録された数式で置換する
+///
+/// 演算子の優先度が変わらないよう、置換後の数式は括弧で囲まれる。
+/// `subs` に登録されていないプレースホルダや、閉じられていない `{` がある場合はエラーとなる
+///
+/// 例
+///
+/// - `instantiate_template("{a} * 2", &HashMap::from([("a".to_string(), "1 + 2".to_string())])) // → "(1 + 2) * 2"`
+pub fn instantiate_template(
+    template: &str,
+    subs: &HashMap<String, String>,
+) -> Result<String, FormulaError> {
+    let error = |code: ErrorCode, name: &str| FormulaError {
+        msg: code.message(Subject::Text(name)),
+        error_type: ErrorKind::Template,
+    };
+
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| error(ErrorCode::UnclosedPlaceholder, ""))?;
+        let name = &rest[start + 1..start + end];
+        let sub = subs
+            .get(name)
+            .ok_or_else(|| error(ErrorCode::UnknownPlaceholder, name))?;
+        result.push('(');
+        result.push_str(sub);
+        result.push(')');
+
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execute() {
+        let success_data = [
+            ("4", 4.0),
+            ("-4", -4.0),
+            ("5 - 4 - (1)", 0.0),
+            ("4 - 5", -1.0),
+            ("(1 - 3) * 3", -6.0),
+            ("(-1 + 3) * 3", 6.0),
+            ("(3 - 5) % 3", -2.0),
+            ("7 \\ 2", 3.0),
+            ("-7 \\ 2", -4.0),
+            ("1 + 7 \\ 2 * 2", 7.0),
+            ("50%", 0.5),
+            ("10 % 3", 1.0),
+            ("50% + 10%", 0.6),
+            // 符号の付いた被演算子が続く '%' は剰余の演算子
+            ("10%-3", 1.0),
+            ("10% -3", 1.0),
+            ("10% +3", 1.0),
+            ("10% - 3", 0.1 - 3.0),
+            ("hoge * 25%", 0.5),
+            ("1+2*(3*(4+5)+6)*(7+8)+9==1000<10!=1", 0.0),
+            ("1 == 2 * 3 < 1", 1.0),
+            ("5 < 2 * 3", 1.0),
+            ("5 > 2 * 3", 0.0),
+            ("5 != 2 * 3", 1.0),
+            ("5 == 2 * 3", 0.0),
+            ("Add((2 + 3) + 4, 5) + Sub(2, 3)", 13.0),
+            ("If(1 == (2 - 1), 3, 1)", 3.0),
+            ("If(1 != (2 - 1), 3, 1)", 1.0),
+            ("(1 - (2 * 3)) * (4 + 5)", -45.0),
+            ("hoge + fuga * 3 - Add(1, 2)", 11.0),
+            ("Pow(2, 3)", 8.0),
+            ("2 ** 3", 8.0),
+            ("2 ** 3 ** 2", 512.0),
+            ("2 * 3 ** 2", 18.0),
+            ("(2 ** 3) ** 2", 64.0),
+            // 符号反転はべき乗より後に行う (数値・変数・括弧で同じ)
+            ("-2 ** 2", -4.0),
+            ("-2 ^ 2", -4.0),
+            ("-hoge ** 2", -4.0),
+            ("-hoge ^ 2", -4.0),
+            ("-(2) ** 2", -4.0),
+            ("-(2) ^ 2", -4.0),
+            ("(-2) ** 2", 4.0),
+            ("(-2) ^ 2", 4.0),
+            ("2 ** -1", 0.5),
+            ("1 - -2 ** 2", 5.0),
+            ("1.5e3 + 2E-1", 1500.2),
+            ("1e3 * hoge", 2000.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                parse_formula(
+                    input,
+                    vec![Function::new("Pow", 2, |args| args[0].powf(args[1]))],
+                    vec![Variable::new("hoge", 2.0,), Variable::new("fuga", 4.0,)]
+                ),
+                Ok(expected)
+            );
+        }
+
+        let failure_data = [
+            "2(3 + 2)",
+            "1 ! 3",
+            "1 ==",
+            "== 3",
+            "=",
+            "2+=3",
+            "2 - 3 (()) + 3)",
+            "()",
+            "",
+            "hello world",
+            "add(2, 3)",
+            "Add(2)",
+            "add + 2 / 3",
+            "2 * *3",
+            "Add()",
+        ];
+
+        for input in failure_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]).is_err(), input),
+                (true, input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut context = Context::new();
+        context.add_constant("c", 299792458.0);
+        context.add_variable(Variable::new("hoge", 2.0));
+
+        assert_eq!(context.evaluate("c * 2"), Ok(599584916.0));
+        assert_eq!(context.evaluate("c * hoge"), Ok(599584916.0));
+        assert_eq!(context.constant_names(), vec!["c"]);
+        assert_eq!(context.variable_names(), vec!["hoge"]);
+
+        // 同名の変数を登録しても定数が優先される
+        context.add_variable(Variable::new("c", 1.0));
+        assert_eq!(context.evaluate("c * 2"), Ok(599584916.0));
+    }
+
+    #[test]
+    fn test_reserved_constants() {
+        let mut context = Context::new();
+        let success_data = [
+            ("PI * 2", std::f64::consts::TAU),
+            ("TAU", std::f64::consts::TAU),
+            ("Ln(E)", 1.0),
+            ("E ** 0", 1.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        // 同名の変数・定数を登録した場合は、そちらが優先される
+        context.add_variable(Variable::new("PI", 3.0));
+        context.add_constant("E", 2.0);
+        assert_eq!(context.evaluate("PI * 2"), Ok(6.0));
+        assert_eq!(context.evaluate("E * TAU"), Ok(2.0 * std::f64::consts::TAU));
+        assert_eq!(
+            parse_formula("PI * 2", vec![], vec![Variable::new("PI", 1.0)]),
+            Ok(2.0)
+        );
+    }
+
+    #[test]
+    fn test_single_token() {
+        let mut context = Context::new();
+        context.add_constant("pi", std::f64::consts::PI);
+        context.add_variable(Variable::new("x", 3.0));
+
+        let success_data = [
+            ("4", 4.0),
+            ("-4", -4.0),
+            ("x", 3.0),
+            ("pi", std::f64::consts::PI),
+            ("(x)", 3.0),
+            (" x ", 3.0),
+            ("-x", -3.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+            assert_eq!((context.max_stack_depth(input), input), (Ok(1), input));
+        }
+
+        // 解析・評価の各段階で、値 1 つだけの中間表現を扱える
+        assert_eq!(tokenize("x"), Ok(vec![Token::Property("x".to_string())]));
+        assert_eq!(context.parse_to_ast("pi"), Ok(Expr::Var("pi".to_string())));
+        assert_eq!(context.eval_ast(&Expr::Var("x".to_string())), Ok(3.0));
+        assert_eq!(
+            compile_formula("x", vec![])
+                .unwrap()
+                .evaluate(vec![Variable::new("x", 5.0)]),
+            Ok(5.0)
+        );
+        assert_eq!(
+            parse_formula_with_environment(
+                "x",
+                &Environment::new(vec![], vec![Variable::new("x", 6.0)])
+            ),
+            Ok(6.0)
+        );
+        assert_eq!(
+            parse_formula("y", vec![], vec![Variable::new("y", 7.0)]),
+            Ok(7.0)
+        );
+
+        for input in ["", " ", "()", "y"] {
+            assert!(context.evaluate(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_reserved_functions() {
+        let success_data = [
+            ("Sign(-3)", -1.0),
+            ("Sign(3)", 1.0),
+            ("Sign(0)", 0.0),
+            ("CopySign(3, -1)", -3.0),
+            ("CopySign(-3, 1)", 3.0),
+            ("Dist(0, 0, 3, 4)", 5.0),
+            ("Dist(1, 1, -2, -3)", 5.0),
+            ("Dist2(0, 0, 3, 4)", 25.0),
+            ("Dist2(1, 1, 1, 1)", 0.0),
+            ("MaxIgnoreNan(1, 0 / 0, 3)", 3.0),
+            ("MaxIgnoreNan(0 / 0, -2)", -2.0),
+            ("MinIgnoreNan(1, 0 / 0, 3)", 1.0),
+            ("MinIgnoreNan(4)", 4.0),
+            ("Hypot3(2, 3, 6)", 7.0),
+            ("Hypot3(0, 0, -3)", 3.0),
+            ("Norm(3, 4)", 5.0),
+            ("Norm(2, 3, 6)", 7.0),
+            ("Norm(-5)", 5.0),
+            ("Norm()", 0.0),
+            ("Factorial(0)", 1.0),
+            ("Factorial(5)", 120.0),
+            ("Factorial(170)", 7.257415615307994e306),
+            ("Trunc(2.9)", 2.0),
+            ("Trunc(-2.9)", -2.0),
+            ("Floor(2.9)", 2.0),
+            ("Floor(-2.9)", -3.0),
+            ("Ceil(2.1)", 3.0),
+            ("Ceil(-2.9)", -2.0),
+            ("Sqrt(9)", 3.0),
+            ("Sqrt(0)", 0.0),
+            ("Cbrt(-8)", -2.0),
+            ("Cbrt(27)", 3.0),
+            ("Log2(8)", 3.0),
+            ("Log2(0.25)", -2.0),
+            ("Log2(1)", 0.0),
+            ("BitLength(255)", 8.0),
+            ("BitLength(256)", 9.0),
+            ("BitLength(1)", 1.0),
+            ("BitLength(0)", 0.0),
+            ("BitLength(0.9)", 0.0),
+            ("BitLength(7.9)", 3.0),
+            ("BitLength(2 ** 53 - 1)", 53.0),
+            ("BitLength(2 ** 100)", 101.0),
+            ("IsDivisibleBy(10, 5)", 1.0),
+            ("IsDivisibleBy(10, 3)", 0.0),
+            ("IsDivisibleBy(-9, 3)", 1.0),
+            ("IsDivisibleBy(10.7, 5.2)", 1.0),
+            ("IsEven(4)", 1.0),
+            ("IsOdd(4)", 0.0),
+            ("IsEven(-3)", 0.0),
+            ("IsOdd(-3)", 1.0),
+            ("IsEven(0)", 1.0),
+            ("Clamp01(1.5)", 1.0),
+            ("Clamp01(-0.2)", 0.0),
+            ("Clamp01(0.3)", 0.3),
+            ("Normalize(5, 0, 10)", 0.5),
+            ("Normalize(15, 10, 0)", -0.5),
+            ("Smoothstep(0, 1, 0.5)", 0.5),
+            ("Smoothstep(0, 1, -1)", 0.0),
+            ("Smoothstep(0, 1, 2)", 1.0),
+            ("Smoothstep(0, 2, 0.5)", 0.15625),
+            ("Smoothstep(1, 0, 0.25)", 0.84375),
+            ("Sum(1, 2, 3, 4)", 10.0),
+            ("Sum()", 0.0),
+            ("Max(5, 2, 9)", 9.0),
+            ("Min(5, 2, 9)", 2.0),
+            ("Max(-1)", -1.0),
+            ("MaxOr(-1, 3, 7)", 7.0),
+            ("MaxOr(-1)", -1.0),
+            ("MaxOr(10, 3, 7)", 7.0),
+            ("MinOr(-1, 3, 7)", 3.0),
+            ("MinOr(5)", 5.0),
+            ("ArgMax(3, 7, 2)", 2.0),
+            ("ArgMin(3, 7, 2)", 3.0),
+            ("ArgMax(5, 1, 5)", 1.0),
+            ("ArgMin(4)", 1.0),
+            ("Choose(ArgMax(3, 7, 2), 10, 20, 30)", 20.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+        // -0.0 == 0.0 となるため、符号はビット列で比較する
+        assert_eq!(
+            parse_formula("Sum()", vec![], vec![]).map(f64::to_bits),
+            Ok(0.0_f64.to_bits())
+        );
+
+        let failure_data = [
+            "Sqrt(-1)",
+            "Log2(0)",
+            "Log2(-8)",
+            "BitLength(-1)",
+            "BitLength(1 / 0)",
+            "BitLength(0 / 0)",
+            "IsDivisibleBy(1, 0)",
+            "IsDivisibleBy(1, 0.5)",
+            "Normalize(5, 1, 1)",
+            "Smoothstep(1, 1, 0.5)",
+            "Factorial(-1)",
+            "Factorial(1.5)",
+            "Factorial(171)",
+            "Factorial(1e12)",
+            "Factorial(1 / 0)",
+            "IsEven(2.5)",
+            "IsOdd(-0.5)",
+            "IsEven(1 / 0)",
+            "Max()",
+            "Min()",
+            "MaxOr()",
+            "MinOr()",
+            "ArgMax()",
+            "ArgMin()",
+        ];
+        for input in failure_data {
+            assert!(
+                parse_formula(input, vec![], vec![]).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_expm1_log1p() {
+        let evaluate = |input| parse_formula(input, vec![], vec![]).unwrap();
+
+        // x = 1e-10 のとき、exp(x) - 1 ≈ x + x^2 / 2, ln(1 + x) ≈ x - x^2 / 2
+        let x = 0.0000000001;
+        let expected = x + x * x / 2.0;
+        let expm1 = evaluate("Expm1(0.0000000001)");
+        let naive = evaluate("Exp(0.0000000001) - 1");
+        assert!((expm1 - expected).abs() < (naive - expected).abs());
+        assert!((expm1 - expected).abs() / expected < 1e-15);
+
+        let expected = x - x * x / 2.0;
+        let log1p = evaluate("Log1p(0.0000000001)");
+        let naive = evaluate("Ln(1 + 0.0000000001)");
+        assert!((log1p - expected).abs() < (naive - expected).abs());
+        assert!((log1p - expected).abs() / expected < 1e-15);
+
+        assert_eq!(evaluate("Expm1(0)"), 0.0);
+        assert_eq!(evaluate("Log1p(0)"), 0.0);
+    }
+
+    #[test]
+    fn test_activation_functions() {
+        let success_data = [
+            ("Relu(-3)", 0.0),
+            ("Relu(3)", 3.0),
+            ("Relu(0)", 0.0),
+            ("Sigmoid(0)", 0.5),
+            ("Tanh(0)", 0.0),
+            // 絶対値の大きい値でも NaN にならない
+            ("Sigmoid(1000)", 1.0),
+            ("Sigmoid(-1000)", 0.0),
+            ("Tanh(1000)", 1.0),
+            ("Tanh(-1000)", -1.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        let sigmoid = parse_formula("Sigmoid(-40)", vec![], vec![]).unwrap();
+        assert!(sigmoid > 0.0 && (sigmoid - (-40f64).exp()).abs() < 1e-30);
+    }
+
+    #[test]
+    fn test_ignore_nan() {
+        // NaN を除かない場合は NaN となる
+        let result = parse_formula("Max(1, 0 / 0, 3)", vec![], vec![]);
+        assert!(matches!(result, Ok(x) if x.is_nan()));
+
+        for input in [
+            "MaxIgnoreNan(0 / 0, 0 / 0)",
+            "MinIgnoreNan(0 / 0)",
+            "MaxIgnoreNan()",
+        ] {
+            let result = parse_formula(input, vec![], vec![]);
+            assert!(matches!(result, Ok(x) if x.is_nan()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        // 元利均等返済の毎回の返済額
+        let payment = || {
+            Function::keyword("Payment", &["rate", "periods", "amount"], |args| {
+                let (rate, periods, amount) = (args["rate"], args["periods"], args["amount"]);
+                amount * rate / (1.0 - (1.0 + rate).powf(-periods))
+            })
+        };
+        let expected =
+            parse_formula("1000 * 0.01 / (1 - (1 + 0.01) ** -12)", vec![], vec![]).unwrap();
+
+        let success_data = [
+            "Payment(rate=0.01, periods=12, amount=1000)",
+            "Payment(amount = 1000, rate = 0.01, periods = 12)",
+            "Payment(periods=Add(6, 6), amount=1000, rate=0.01)",
+            // キーワードを省略した場合は定義順に対応させる
+            "Payment(0.01, 12, 1000)",
+        ];
+        for input in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![payment()], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        let failure_data = [
+            "Payment(rate=0.01, periods=12)",
+            "Payment(rate=0.01, periods=12, total=1000)",
+            "Payment(rate=0.01, rate=12, amount=1000)",
+            "Payment(0.01, periods=12, amount=1000)",
+            "Add(a=1, b=2)",
+        ];
+        for input in failure_data {
+            assert!(
+                parse_formula(input, vec![payment()], vec![]).is_err(),
+                "{}",
+                input
+            );
+        }
+
+        // キーワードには '_' を含む名前も使用できる
+        let tax = Function::keyword("F", &["tax_rate", "b"], |args| args["tax_rate"] * args["b"]);
+        assert_eq!(
+            parse_formula("F(b=2, tax_rate=0.1)", vec![tax], vec![]),
+            Ok(0.2)
+        );
+
+        // 木構造でもキーワード引数を保持する
+        let mut context = Context::new();
+        context.add_function(payment());
+        let expr = context
+            .parse_to_ast("Payment(amount=1000, rate=0.01, periods=12)")
+            .unwrap();
+        assert_eq!(context.eval_ast(&expr), Ok(expected));
+    }
+
+    #[test]
+    fn test_evaluate_typed() {
+        let context = Context::new();
+        let success_data = [
+            ("2 * 2", TypedResult::Integer(4)),
+            ("1 / 3", TypedResult::Float(1.0 / 3.0)),
+            ("0.5 + 0.5", TypedResult::Integer(1)),
+            ("2 ** 70", TypedResult::Float(2f64.powi(70))),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (context.evaluate_typed(input), input),
+                (Ok(expected), input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_logical_xor() {
+        let success_data = [
+            ("1 ^^ 0", 1.0),
+            ("1 ^^ 1", 0.0),
+            ("0 ^^ 0", 0.0),
+            ("-2 ^^ 0", 1.0),
+            // 比較演算子よりも優先度が低い
+            ("1 < 2 ^^ 2 < 1", 1.0),
+            ("1 ^^ 1 == 0", 1.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_logical_and_or() {
+        let success_data = [
+            ("1 && 0", 0.0),
+            ("1 && 2", 1.0),
+            ("0 || 0", 0.0),
+            ("0 || -1", 1.0),
+            ("(2 > 1) || (3 < 1)", 1.0),
+            ("(2 > 1) && (3 < 1)", 0.0),
+            // 比較演算子よりも優先度が低い
+            ("2 > 1 && 3 < 1", 0.0),
+            ("1 + 1 && 0 == 0", 1.0),
+            // && → ^^ → || の順に優先される
+            ("1 || 0 && 0", 1.0),
+            ("1 ^^ 1 && 0", 1.0),
+            ("1 ^^ 1 || 1", 1.0),
+            ("0 || 1 ^^ 1", 0.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        for input in ["1 & 0", "1 | 0", "1 &&", "|| 1"] {
+            assert!(
+                parse_formula(input, vec![], vec![]).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_negate() {
+        let variables = vec![Variable::new("hoge", 4.0)];
+        let success_data = [
+            ("-(3+2)", -5.0),
+            ("-(1 + 2) * 2", -6.0),
+            ("-hoge", -4.0),
+            ("- hoge", -4.0),
+            ("--hoge", 4.0),
+            ("3 - -2", 5.0),
+            ("3 - -hoge", 7.0),
+            ("2 * -hoge", -8.0),
+            ("-Add(1, 2)", -3.0),
+            // べき乗より弱く結合する
+            ("-hoge ** 2", -16.0),
+            ("(-hoge) ** 2", 16.0),
+            ("2 ** -(1 + 1)", 0.25),
+            ("-.5", -0.5),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], variables.clone()), input),
+                (Ok(expected), input)
+            );
+        }
+
+        for input in ["-", "3 -", "-()", "- - "] {
+            assert!(
+                parse_formula(input, vec![], variables.clone()).is_err(),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_typed_variables() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new_bool("flag", true));
+        context.add_variable(Variable::new_number("x", 2.0));
+
+        // 真理値は真を 1 として計算する
+        assert_eq!(context.evaluate("flag + x"), Ok(3.0));
+        assert_eq!(context.evaluate_value("flag"), Ok(Evaluated::Bool(true)));
+
+        context.set_processor_options(ProcessorOptions {
+            strict_types: true,
+            ..ProcessorOptions::default()
+        });
+        assert!(context.evaluate("flag + x").is_err());
+        assert!(context.evaluate("-flag").is_err());
+        assert_eq!(context.evaluate("If(flag, x, 0)"), Ok(2.0));
+        assert_eq!(context.evaluate("flag && x > 1"), Ok(1.0));
+        assert_eq!(context.evaluate("x + 1"), Ok(3.0));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 1.0));
+        context.add_array("arr", vec![1.0, 2.0]);
+
+        let errors = context.validate("Foo(a, x) + b * a").unwrap_err();
+        assert_eq!(
+            errors.into_iter().map(|e| e.msg).collect::<Vec<_>>(),
+            vec![
+                "error: unknown variable, \"a\"",
+                "error: unknown function, \"Foo\"",
+                "error: unknown variable, \"b\"",
+            ]
+        );
+        // 配列変数は可変長引数の関数の引数としてのみ使用できる
+        assert_eq!(context.validate("arr + 1").map_err(|e| e.len()), Err(1));
+
+        for input in ["x + 1", "Sum(arr) + Add(x, 2)", "1 / 0"] {
+            assert_eq!((context.validate(input), input), (Ok(()), input));
+        }
+
+        // 構文解析に失敗した場合は、そのエラーのみを返す
+        assert_eq!(context.validate("a +").map_err(|e| e.len()), Err(1));
+    }
+
+    #[test]
+    fn test_evaluate_with_resolver() {
+        // 参照のたびに値を計算し、呼び出し回数を数える
+        struct Computed {
+            calls: std::cell::Cell<usize>,
+        }
+        impl VariableResolver for Computed {
+            fn resolve(&self, name: &str) -> Option<f64> {
+                self.calls.set(self.calls.get() + 1);
+                name.strip_prefix('x').map(|n| n.len() as f64 + 1.0)
+            }
+        }
+
+        let mut context = Context::new();
+        context.add_constant("c", 100.0);
+        context.add_variable(Variable::new("y", 5.0));
+
+        let resolver = Computed {
+            calls: std::cell::Cell::new(0),
+        };
+        let input = vec!["x"; 1000].join(" + ");
+        assert_eq!(
+            context.evaluate_with_resolver(&input, &resolver),
+            Ok(1000.0)
+        );
+        assert_eq!(resolver.calls.get(), 1000);
+
+        // resolver は登録されている変数より優先され、定数よりは優先されない
+        let success_data = [("xab * y", 15.0), ("y + c", 105.0), ("c + xa", 102.0)];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (context.evaluate_with_resolver(input, &resolver), input),
+                (Ok(expected), input)
+            );
+        }
+        assert!(context.evaluate_with_resolver("z", &resolver).is_err());
+
+        let map = HashMap::from([("y".to_string(), 2.0), ("z".to_string(), 3.0)]);
+        assert_eq!(context.evaluate_with_resolver("y * z", &map), Ok(6.0));
+        let variables = vec![Variable::new("z", 4.0), Variable::new("z", 5.0)];
+        assert_eq!(
+            context.evaluate_with_resolver("y * z", &variables),
+            Ok(20.0)
+        );
+
+        // parse_formula も内部で表を構築して変数を解決する
+        let variables = (0..1000)
+            .map(|i| Variable::new(&format!("v{}", i), i as f64))
+            .collect();
+        let input = (0..1000)
+            .map(|i| format!("v{}", i))
+            .collect::<Vec<_>>()
+            .join(" + ");
+        assert_eq!(parse_formula(&input, vec![], variables), Ok(499500.0));
+    }
+
+    #[test]
+    fn test_evaluate_with_unused() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 1.0));
+        context.add_variable(Variable::new("y", 2.0));
+        context.add_variable(Variable::new("z", 3.0));
+        context.add_function(Function::new("Double", 1, |args| args[0] * 2.0));
+        context.add_function(Function::new("Triple", 1, |args| args[0] * 3.0));
+
+        let success_data = [
+            ("x + y", 3.0, vec!["z"], vec!["Double", "Triple"]),
+            ("Double(x) + Add(y, z)", 7.0, vec![], vec!["Triple"]),
+            ("1 + 2", 3.0, vec!["x", "y", "z"], vec!["Double", "Triple"]),
+            ("MapSum(\"Triple\", x, y)", 9.0, vec!["z"], vec!["Double"]),
+        ];
+        for (input, expected, variables, functions) in success_data {
+            assert_eq!(
+                (context.evaluate_with_unused(input), input),
+                (
+                    Ok((
+                        expected,
+                        Unused {
+                            variables: variables.into_iter().map(String::from).collect(),
+                            functions: functions.into_iter().map(String::from).collect(),
+                        }
+                    )),
+                    input
+                )
+            );
+        }
+
+        assert!(context.evaluate_with_unused("x + w").is_err());
+    }
+
+    #[test]
+    fn test_ternary() {
+        let variables = vec![
+            Variable::new("a", 3.0),
+            Variable::new("b", 5.0),
+            Variable::new("c", 4.0),
+            Variable::new("x", -2.0),
+        ];
+        let success_data = [
+            ("x > 0 ? 1 : -1", -1.0),
+            ("x < 0 ? 1 : -1", 1.0),
+            // 最大値 (右結合)
+            ("a > b ? a : b > c ? b : c", 5.0),
+            ("a > b ? a : (b > c ? b : c)", 5.0),
+            ("a ? b ? 1 : 2 : 3", 1.0),
+            ("0 ? 1 : 0 ? 2 : 3", 3.0),
+            // 他のすべての演算子よりも優先度が低い
+            ("1 + 1 ? 2 + 3 : 4 * 5", 5.0),
+            ("0 || 0 ? 1 : 2", 2.0),
+            ("(a > b ? a : b) * 2", 10.0),
+            ("Add(a > 0 ? 1 : 2, 10)", 11.0),
+            // 選ばれなかった分岐の評価エラーは無視される (If と同じ)
+            ("1 ? 2 : Sqrt(-1)", 2.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], variables.clone()), input),
+                (Ok(expected), input)
+            );
+        }
+
+        for input in [
+            "1 ? 2",
+            "1 : 2",
+            "1 ? 2 : 3 : 4",
+            "(1 ? 2) : 3",
+            "1 ? (2 : 3)",
+            "Add(1 ? 2, 3)",
+            "1 ?",
+        ] {
+            assert!(
+                parse_formula(input, vec![], variables.clone()).is_err(),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_caret() {
+        let success_data = [
+            ("2 ^ 10", 1024.0),
+            // 右結合
+            ("2 ^ 3 ^ 2", 512.0),
+            ("(2 ^ 3) ^ 2", 64.0),
+            // '*' や '/' よりも優先度が高い
+            ("3 * 2 ^ 2", 12.0),
+            ("2 ^ 2 * 3", 12.0),
+            ("16 / 2 ^ 3", 2.0),
+            ("2 ^ 3 ** 2", 512.0),
+            ("2 ^ 0 ^^ 1", 0.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+        assert_eq!(
+            parse_formula(
+                "base ^ exp",
+                vec![],
+                vec![Variable::new("base", 3.0), Variable::new("exp", 4.0)]
+            ),
+            Ok(81.0)
+        );
+
+        for input in ["2 ^", "^ 2", "2 ^ ^ 2"] {
+            assert!(
+                parse_formula(input, vec![], vec![]).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_cond() {
+        let success_data = [
+            ("Cond(0, 1, 1, 2, 99)", 2.0),
+            ("Cond(0, 1, 0, 2, 99)", 99.0),
+            ("Cond(1 < 2, 10, 1, 20, 99)", 10.0),
+            ("Cond(99)", 99.0),
+            // 選ばれなかった値の評価エラーは無視される
+            ("Cond(0, Wrap(1, 0), 1, 2, 99)", 2.0),
+            ("Cond(1, 1, Wrap(1, 0), 2, 99)", 1.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        let failure_data = [
+            "Cond()",
+            "Cond(0, 1)",
+            "Cond(1, Wrap(1, 0), 99)",
+            "Cond(Wrap(1, 0), 1, 99)",
+        ];
+        for input in failure_data {
+            assert!(
+                parse_formula(input, vec![], vec![]).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+
+        // 選ばれなかった値は評価されない (呼び出し回数を数える)
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut context = Context::new();
+        context.add_function({
+            let calls = calls.clone();
+            Function::new("Expensive", 0, move |_| {
+                calls.set(calls.get() + 1);
+                1.0
+            })
+        });
+        let success_data = [
+            ("If(1, 5, Expensive())", 5.0, 0),
+            ("If(0, 5, Expensive())", 1.0, 1),
+            ("If(1 > 0 ? 0 : Expensive(), Expensive(), 7)", 7.0, 0),
+            ("Cond(0, Expensive(), 1, 2, Expensive())", 2.0, 0),
+            (
+                "Cond(Expensive(), Expensive() + 1, 0, Expensive(), 9)",
+                2.0,
+                2,
+            ),
+        ];
+        for (input, expected, count) in success_data {
+            calls.set(0);
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+            assert_eq!((calls.get(), input), (count, input));
+        }
+    }
+
+    #[test]
+    fn test_evaluate_value() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 2.0));
+        let success_data = [
+            ("1 + 2", Evaluated::Number(3.0)),
+            ("\"a\" + \"b\"", Evaluated::Str("ab".to_string())),
+            ("\"a\" + (\"b\" + \"c\")", Evaluated::Str("abc".to_string())),
+            ("\"a\" + \"b\" == \"ab\"", Evaluated::Number(1.0)),
+            ("If(\"a\" != \"b\", x, 0)", Evaluated::Number(2.0)),
+            (
+                "If(x > 1, \"big\", \"small\")",
+                Evaluated::Str("big".to_string()),
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (context.evaluate_value(input), input),
+                (Ok(expected), input)
+            );
+        }
+
+        let failure_data = ["\"a\" + 1", "\"a\" * \"b\"", "Add(\"a\", 1)"];
+        for input in failure_data {
+            assert!(
+                context.evaluate_value(input).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+
+        // 数値を返す評価では、結果が文字列の場合はエラーとなる
+        assert_eq!(context.evaluate("\"a\" == \"a\""), Ok(1.0));
+        assert!(context.evaluate("\"a\" + \"b\"").is_err());
+
+        // 演算子の挙動を追加する
+        context.add_operator(Value::Plus, "string", "number", |v1, v2| match (v1, v2) {
+            (Evaluated::Str(s), Evaluated::Number(n)) => Ok(Evaluated::Str(format!("{}{}", s, n))),
+            _ => Err("error: unexpected operands".to_string()),
+        });
+        assert_eq!(
+            context.evaluate_value("\"x=\" + x"),
+            Ok(Evaluated::Str("x=2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_typed_function() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("id", 3.0));
+        context.add_function(Function::typed("Lookup", Some(2), |args| {
+            match (&args[0], &args[1]) {
+                (Evaluated::Str(key), Evaluated::Number(id)) if *id == 3.0 => match key.as_str() {
+                    "region" => Ok(Evaluated::Str("tokyo".to_string())),
+                    "population" => Ok(Evaluated::Number(1400.0)),
+                    _ => Err(format!("error: unknown key, {:?}", key)),
+                },
+                _ => Err("error: record not found".to_string()),
+            }
+        }));
+
+        assert_eq!(
+            context.evaluate_value("Lookup(\"region\", id)"),
+            Ok(Evaluated::Str("tokyo".to_string()))
+        );
+        // 結果が数値の場合は evaluate でも評価できる
+        assert_eq!(
+            context.evaluate("Lookup(\"population\", id) * 2"),
+            Ok(2800.0)
+        );
+        assert_eq!(
+            context.evaluate("Lookup(\"region\", id) == \"tokyo\""),
+            Ok(1.0)
+        );
+
+        for input in [
+            "Lookup(\"region\", 4)",
+            "Lookup(\"city\", id)",
+            "Lookup(\"region\", id)",
+        ] {
+            assert!(context.evaluate(input).is_err(), "{} should fail", input);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_detailed() {
+        let context = Context::new();
+        let success_data = [
+            ("1 + 2", 3.0, 3, true),
+            ("Add(1, 2) * 3", 9.0, 5, true),
+            ("1 / 0", f64::INFINITY, 3, false),
+        ];
+        for (input, value, steps, is_finite) in success_data {
+            let result = context.evaluate_detailed(input).unwrap();
+            assert_eq!(
+                (result.value, result.steps, result.is_finite, input),
+                (value, steps, is_finite, input)
+            );
+        }
+        assert!(context.evaluate_detailed("1 +").is_err());
+
+        #[cfg(feature = "timing")]
+        assert!(
+            context.evaluate_detailed("1 + 2").unwrap().took < std::time::Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_branch_trace() {
+        let trace = |entries: &[(&str, usize)]| -> Vec<BranchTrace> {
+            entries
+                .iter()
+                .map(|(function, branch)| BranchTrace {
+                    function: function.to_string(),
+                    branch: *branch,
+                })
+                .collect()
+        };
+
+        let context = Context::new();
+        let success_data = [
+            ("If(1 > 0, 5, 9)", 5.0, trace(&[("If", 0)])),
+            ("If(1 < 0, 5, 9)", 9.0, trace(&[("If", 1)])),
+            ("Cond(0, 1, 1, 2, 99)", 2.0, trace(&[("Cond", 1)])),
+            ("Cond(0, 1, 0, 2, 99)", 99.0, trace(&[("Cond", 2)])),
+            ("Piecewise(0, 1, 1, 2)", 2.0, trace(&[("Piecewise", 1)])),
+            ("Choose(2, 10, 20, 30)", 20.0, trace(&[("Choose", 1)])),
+            ("Default(Wrap(1, 0), 7)", 7.0, trace(&[("Default", 1)])),
+            // 内側の呼び出しから評価順に記録される
+            (
+                "If(If(0, 1, 0), 5, Choose(1, 10)) + 1",
+                11.0,
+                trace(&[("If", 1), ("Choose", 0), ("If", 1)]),
+            ),
+            // 選ばれなかった分岐は評価されないため、その中の呼び出しは記録されない
+            ("If(0, If(1, 2, 3), 4)", 4.0, trace(&[("If", 1)])),
+            (
+                "If(1, 5, Cond(1, If(1, 2, 3), 4)) + If(0, Choose(1, 2), 6)",
+                11.0,
+                trace(&[("If", 0), ("If", 1)]),
+            ),
+            // 評価に失敗した呼び出しは記録されない
+            (
+                "Default(If(Wrap(1, 0), 1, 2), 3)",
+                3.0,
+                trace(&[("Default", 1)]),
+            ),
+            ("Add(1, 2)", 3.0, vec![]),
+        ];
+        for (input, expected, expected_trace) in success_data {
+            assert_eq!(
+                (context.evaluate_with_branch_trace(input), input),
+                (Ok((expected, expected_trace)), input)
+            );
+        }
+
+        assert!(context
+            .evaluate_with_branch_trace("If(1, Wrap(1, 0), 2)")
+            .is_err());
+    }
+
+    #[test]
+    fn test_pow_strict() {
+        let mut context = Context::new();
+        let success_data = [
+            ("PowStrict(2, 3)", 8.0),
+            ("PowStrict(-2, 3)", -8.0),
+            ("PowStrict(4, 0.5)", 2.0),
+            ("PowStrict(-2, -1)", -0.5),
+            ("PowStrict(0, 0)", 1.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+        assert!(context.evaluate("PowStrict(-8, 0.5)").is_err());
+        // 演算子は NaN を返す
+        assert!(context.evaluate("(-8) ** 0.5").unwrap().is_nan());
+
+        context.set_zero_pow_zero_error(true);
+        assert!(context.evaluate("PowStrict(0, 0)").is_err());
+        assert_eq!(context.evaluate("PowStrict(0, 2)"), Ok(0.0));
+        assert!(context.evaluate("PowStrict(-8, 0.5)").is_err());
+
+        context.set_zero_pow_zero_error(false);
+        assert_eq!(context.evaluate("PowStrict(0, 0)"), Ok(1.0));
+    }
+
+    #[test]
+    fn test_safe_div() {
+        let success_data = [
+            ("SafeDiv(10, 2, -1)", 5.0),
+            ("SafeDiv(10, 0, -1)", -1.0),
+            ("SafeDiv(0, 0, 99)", 99.0),
+            ("SafeDiv(1, 1 - 1, 0) + 1", 1.0),
+            ("SafeDiv(-3, 4, 0)", -0.75),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        assert!(parse_formula("SafeDiv(1, 0)", vec![], vec![]).is_err());
+    }
+
+    #[test]
+    fn test_clamp() {
+        let success_data = [
+            ("Clamp(15, 0, 10)", 10.0),
+            ("Clamp(-5, 0, 10)", 0.0),
+            ("Clamp(5, 0, 10)", 5.0),
+            ("Clamp(10, 0, 10)", 10.0),
+            ("WasClamped(15, 0, 10)", 1.0),
+            ("WasClamped(-5, 0, 10)", 1.0),
+            ("WasClamped(5, 0, 10)", 0.0),
+            // 境界値は範囲内とする
+            ("WasClamped(0, 0, 10)", 0.0),
+            ("WasClamped(10, 0, 10)", 0.0),
+            ("WasClamped(3, 3, 3)", 0.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        for input in [
+            "Clamp(5, 10, 0)",
+            "WasClamped(5, 10, 0)",
+            "Clamp(5, 0 / 0, 10)",
+            "Clamp(5, 0)",
+        ] {
+            assert!(parse_formula(input, vec![], vec![]).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    // 3.14 などは円周率の近似値として扱うのではなく、丸めた結果を表している
+    #[allow(clippy::approx_constant)]
+    fn test_fix_decimals() {
+        let success_data = [
+            ("FixDecimals(3.14159, 2)", 3.14),
+            ("FixDecimals(3.14159, 4)", 3.1416),
+            // 0.5 は 0 から遠い方向に丸める (Round と同じ。丸め方は set_rounding_mode で変更できる)
+            ("FixDecimals(2.5, 0)", 3.0),
+            ("FixDecimals(-2.5, 0)", -3.0),
+            ("FixDecimals(-1.2345, 3)", -1.235),
+            ("FixDecimals(1234.5, 0) + 0.5", 1235.5),
+            ("FixDecimals(1e300, 100)", 1e300),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        for input in [
+            "FixDecimals(3.14, -1)",
+            "FixDecimals(3.14, 1.5)",
+            "FixDecimals(3.14)",
+        ] {
+            assert!(parse_formula(input, vec![], vec![]).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_count() {
+        let success_data = [
+            ("Count(1, 2, 3)", 3.0),
+            ("Count()", 0.0),
+            ("Count(0 / 0, 1)", 2.0),
+            ("CountIf(2, 1, 2, 3, 4)", 2.0),
+            ("CountIf(0, -1, 0.5, 1 / 0)", 2.0),
+            ("CountIf(2)", 0.0),
+            // NaN との比較は常に偽となる
+            ("CountIf(0, 0 / 0, 1)", 1.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        for input in ["CountIf()", "Count(1, Wrap(1, 0))"] {
+            assert!(
+                parse_formula(input, vec![], vec![]).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_choose() {
+        let success_data = [
+            ("Choose(2, 10, 20, 30)", 20.0),
+            ("Choose(1, 10, 20, 30)", 10.0),
+            ("Choose(3, 10, 20, 30)", 30.0),
+            ("Choose(1 + 1, 10, 20)", 20.0),
+            // 選ばれなかった値の評価エラーは無視される
+            ("Choose(1, 10, Wrap(1, 0))", 10.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+
+        let failure_data = [
+            "Choose(5, 10, 20)",
+            "Choose(0, 10, 20)",
+            "Choose(1.5, 10, 20)",
+            "Choose(0 / 0, 10)",
+            "Choose(1)",
+            "Choose()",
+            "Choose(2, 10, Wrap(1, 0))",
+            "Choose(Wrap(1, 0), 10)",
+        ];
+        for input in failure_data {
+            assert!(
+                parse_formula(input, vec![], vec![]).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+
+        // 選ばれなかった値は評価されない (呼び出し回数を数える)
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut context = Context::new();
+        context.add_function({
+            let calls = calls.clone();
+            Function::new("Expensive", 0, move |_| {
+                calls.set(calls.get() + 1);
+                1.0
+            })
+        });
+        let success_data = [
+            ("Choose(1, 10, Expensive(), Expensive())", 10.0, 0),
+            ("Choose(3, Expensive(), 20, Expensive() + 1)", 2.0, 1),
+            ("Choose(Expensive(), 10, Expensive())", 10.0, 1),
+        ];
+        for (input, expected, count) in success_data {
+            calls.set(0);
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+            assert_eq!((calls.get(), input), (count, input));
+        }
+    }
+
+    #[test]
+    fn test_default() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("big", 1e308));
+        context.set_processor_options(ProcessorOptions {
+            overflow_error: true,
+            ..ProcessorOptions::default()
+        });
+        let success_data = [
+            ("Default(6 / 2, 99)", 3.0),
+            ("Default(Wrap(1, 0), 99)", 99.0),
+            ("Default(big * 10, 99)", 99.0),
+            ("Default(Wrap(1, 0), Default(big * 10, -1))", -1.0),
+            // fallback が使われない場合は、そのエラーは無視される
+            ("Default(1, Wrap(1, 0))", 1.0),
+            // 未定義の変数もエラーとして置き換えられる
+            ("Default(zz, 5)", 5.0),
+            ("Default(zz + 1, 5)", 5.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        for input in [
+            "Default(Wrap(1, 0), big * 10)",
+            "Default(1)",
+            "Default(1, 2) + zz",
+        ] {
+            assert!(context.evaluate(input).is_err(), "{} should fail", input);
+        }
+
+        // fallback は expr の評価に失敗した場合のみ評価される (呼び出し回数を数える)
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        context.add_function({
+            let calls = calls.clone();
+            Function::new("Expensive", 0, move |_| {
+                calls.set(calls.get() + 1);
+                1.0
+            })
+        });
+        let success_data = [
+            ("Default(6 / 2, Expensive())", 3.0, 0),
+            ("Default(Expensive(), Expensive() + 1)", 1.0, 1),
+            ("Default(Wrap(1, 0), Expensive() + 1)", 2.0, 1),
+        ];
+        for (input, expected, count) in success_data {
+            calls.set(0);
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+            assert_eq!((calls.get(), input), (count, input));
+        }
+    }
+
+    #[test]
+    fn test_map_sum() {
+        let mut context = Context::new();
+        context.add_function(Function::new("Square", 1, |args| args[0] * args[0]));
+        let success_data = [
+            ("MapSum(\"Square\", 1, 2, 3)", 14.0),
+            ("MapSum(\"Sqrt\", 4, 10 - 1)", 5.0),
+            ("MapSum(\"Square\")", 0.0),
+            ("1 + MapSum( \"Square\" , Add(1, 1)) * 2", 9.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        let failure_data = [
+            "MapSum(\"Unknown\", 1)",
+            "MapSum(\"Add\", 1, 2)",
+            "MapSum(Square, 1)",
+            "Sqrt(\"Square\")",
+            "MapSum(\"Square, 1)",
+            "MapSum()",
+            "MapSum(1, 2)",
+            "MapSum(\"Square\" + 1, 2)",
+            "MapSum(\"Square\", Wrap(1, 0))",
+        ];
+        for input in failure_data {
+            assert!(context.evaluate(input).is_err(), "{} should fail", input);
+        }
+
+        // 予約関数として登録されている (中間表現では展開されない)
+        assert!(context.function_names().contains(&"MapSum"));
+        assert_eq!(
+            context
+                .parse_with_postfix("MapSum(\"Square\", 2)")
+                .map(|(_, postfix)| postfix),
+            Ok("\"Square\" 2 MapSum".to_string())
+        );
+    }
+
+    #[test]
+    fn test_deterministic_math() {
+        let mut context = Context::new();
+        assert_eq!(context.evaluate("Sin(0)"), Ok(0.0));
+        assert_eq!(context.evaluate("Exp(0)"), Ok(1.0));
+
+        // Sin(1) は決定的モードでは常に 0.8414709848078965 (0x3FEAED548F090CEE) となる
+        context.set_deterministic_math(true);
+        assert_eq!(
+            context.evaluate("Sin(1)").map(f64::to_bits),
+            Ok(0x3FEAED548F090CEE)
+        );
+        assert_eq!(context.evaluate("Sin(1)"), Ok(0.8414709848078965));
+        assert_eq!(context.evaluate("Cos(0)"), Ok(1.0));
+
+        context.set_deterministic_math(false);
+        assert_eq!(context.evaluate("Sin(1)"), Ok(1f64.sin()));
+    }
+
+    #[test]
+    fn test_sum_over() {
+        let mut context = Context::new();
+        context.add_array("arr", vec![1.0, 2.0, 3.0]);
+        context.add_array("empty", vec![]);
+        context.add_variable(Variable::new("hoge", 10.0));
+
+        let success_data = [
+            ("arr", "i * i", 14.0),
+            ("arr", "i + hoge", 36.0),
+            ("arr", "1", 3.0),
+            ("empty", "i * i", 0.0),
+        ];
+        for (array, body, expected) in success_data {
+            assert_eq!(
+                (context.sum_over(array, "i", body), body),
+                (Ok(expected), body)
+            );
+        }
+
+        let failure_data = [("unknown", "i"), ("arr", "i *"), ("arr", "j")];
+        for (array, body) in failure_data {
+            assert!(context.sum_over(array, "i", body).is_err());
+        }
+    }
+
+    #[test]
+    fn test_array_argument() {
+        let mut context = Context::new();
+        context.add_array("arr", vec![1.0, 2.0, 3.0, 4.0]);
+        context.add_array("empty", vec![]);
+        context.add_variable(Variable::new("hoge", 10.0));
+
+        let success_data = [
+            ("Sum(arr)", 10.0),
+            ("Average(arr)", 2.5),
+            ("Sum(arr) + Average(arr) * hoge", 35.0),
+            ("Count(arr)", 4.0),
+            ("Norm(arr)", 30.0_f64.sqrt()),
+            ("Sum(empty)", 0.0),
+            ("Sum(1, 2, 3)", 6.0),
+            ("Average(hoge, 20)", 15.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+        assert_eq!(
+            context.evaluate("Sum(empty)").map(f64::to_bits),
+            Ok(0.0_f64.to_bits())
+        );
+
+        // 配列変数は可変長引数の関数のただ 1 つの引数としてのみ使用できる
+        let failure_data = [
+            "arr",
+            "arr + 1",
+            "Sum(arr, 1)",
+            "Sum(arr + 1)",
+            "Sqrt(arr)",
+            "Average(empty)",
+            "Average()",
+        ];
+        for input in failure_data {
+            assert!(context.evaluate(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_piecewise() {
+        let abs = "Piecewise(x < 0, 0 - x, x >= 0, x)";
+        let success_data = [(-3.0, 3.0), (2.0, 2.0), (0.0, 0.0)];
+        for (x, expected) in success_data {
+            assert_eq!(
+                parse_formula(abs, vec![], vec![Variable::new("x", x)]),
+                Ok(expected)
+            );
+        }
+
+        // 選ばれなかった値の評価エラーは無視される
+        assert_eq!(
+            parse_formula("Piecewise(1, 2, Wrap(1, 0), Wrap(1, 0))", vec![], vec![]),
+            Ok(2.0)
+        );
+
+        // 選ばれなかった値は評価されない (呼び出し回数を数える)
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut context = Context::new();
+        context.add_function({
+            let calls = calls.clone();
+            Function::new("Expensive", 0, move |_| {
+                calls.set(calls.get() + 1);
+                1.0
+            })
+        });
+        let success_data = [
+            ("Piecewise(1, 2, 1, Expensive())", 2.0, 0),
+            ("Piecewise(0, Expensive(), 1, 3)", 3.0, 0),
+            (
+                "Piecewise(0, Expensive(), Expensive(), Expensive() + 1)",
+                2.0,
+                2,
+            ),
+        ];
+        for (input, expected, count) in success_data {
+            calls.set(0);
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+            assert_eq!((calls.get(), input), (count, input));
+        }
+
+        let failure_data = [
+            "Piecewise(0, 1, 0, 2)",
+            "Piecewise()",
+            "Piecewise(1, 2, 3)",
+            "Piecewise(Wrap(1, 0), 1, 1, 2)",
+        ];
+        for input in failure_data {
+            assert!(parse_formula(input, vec![], vec![]).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_sequence() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("hoge", 10.0));
+
+        let values: Vec<Result<f64, FormulaError>> = context
+            .evaluate_sequence("n * 2", "n", (1..).map(|n| n as f64))
+            .unwrap()
+            .take(3)
+            .collect();
+        assert_eq!(values, vec![Ok(2.0), Ok(4.0), Ok(6.0)]);
+
+        // 登録済みの変数も参照できる
+        let values: Vec<Result<f64, FormulaError>> = context
+            .evaluate_sequence("n * n + hoge", "n", [0.0, 3.0])
+            .unwrap()
+            .collect();
+        assert_eq!(values, vec![Ok(10.0), Ok(19.0)]);
+
+        // 束縛する変数は、同名の登録済みの変数より優先される (計算される変数からも参照できる)
+        context.add_variable(Variable::new("n", 100.0));
+        context.add_variable(Variable::new_computed("twice", |env| {
+            Some(env.resolve("n")? * 2.0)
+        }));
+        let values: Vec<Result<f64, FormulaError>> = context
+            .evaluate_sequence("n + twice", "n", [1.0, 2.0])
+            .unwrap()
+            .collect();
+        assert_eq!(values, vec![Ok(3.0), Ok(6.0)]);
+
+        assert!(context.evaluate_sequence("n *", "n", [0.0, 1.0]).is_err());
+    }
+
+    #[test]
+    fn test_uppercase_variables() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("MAX", 10.0));
+        assert!(context.evaluate("MAX + 1").is_err());
+
+        context.set_lexer_options(LexerOptions {
+            uppercase_variables: true,
+            ..LexerOptions::default()
+        });
+        assert_eq!(context.evaluate("MAX + 1"), Ok(11.0));
+        assert_eq!(context.evaluate("Add(MAX, 1)"), Ok(11.0));
+    }
+
+    #[test]
+    fn test_identifier_convention() {
+        let mut context = Context::new();
+        context.add_function(Function::new("foo", 1, |args| args[0] * 2.0));
+        context.add_variable(Variable::new("Foo", 10.0));
+        assert!(context.evaluate("foo(1)").is_err());
+
+        context.set_lexer_options(LexerOptions {
+            identifier_convention: IdentifierConvention::Parenthesis,
+            ..LexerOptions::default()
+        });
+        let success_data = [
+            ("foo(1)", 2.0),
+            ("foo(Foo) + Foo", 30.0),
+            ("Add(foo(1), 1)", 3.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        // '(' が続かない識別子は変数として扱われる
+        assert!(context.evaluate("foo + 1").is_err());
+    }
+
+    #[test]
+    fn test_case_insensitive_functions() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 4.0));
+        assert!(context.evaluate("ADD(1, 2)").is_err());
+
+        context.set_lexer_options(LexerOptions {
+            identifier_convention: IdentifierConvention::Parenthesis,
+            ..LexerOptions::default()
+        });
+        context.set_processor_options(ProcessorOptions {
+            case_insensitive_functions: true,
+            ..ProcessorOptions::default()
+        });
+        let success_data = [
+            ("add(1, 2)", 3.0),
+            ("Add(1, 2)", 3.0),
+            ("ADD(1, 2)", 3.0),
+            ("sqrt(x) + SQRT(x)", 4.0),
+            ("if(x > 1, max(x, 10), 0)", 10.0),
+            ("dIV(7, 2)", 3.5),
+            // MapSum に渡す関数名も区別しない
+            ("mapsum(\"SQRT\", x, 16)", 6.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+        assert!(context.evaluate("ad(1, 2)").is_err());
+    }
+
+    #[test]
+    fn test_currency_symbols() {
+        let mut context = Context::new();
+        context.set_lexer_options(LexerOptions {
+            currency_symbols: vec!['$'],
+            ..LexerOptions::default()
+        });
+        assert_eq!(context.evaluate("$100 + $50"), Ok(150.0));
+        assert_eq!(context.evaluate("$100 - 50 * 2"), Ok(0.0));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 2.0));
+        let input = "Add(x, 3) * -x ** 2 + If(x > 1, 1.5, 0)";
+
+        // 中間表現を保存・復元して評価する
+        let values = compile_formula(input, vec![]).unwrap().values().to_vec();
+        let json = serde_json::to_string(&values).unwrap();
+        let restored: Vec<Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, values);
+        assert_eq!(context.eval_values(&restored), context.evaluate(input));
+
+        let expr = context.parse_to_ast(input).unwrap();
+        let json = serde_json::to_string(&expr).unwrap();
+        assert_eq!(serde_json::from_str::<Expr>(&json).unwrap(), expr);
+
+        let tokens = tokenize(input).unwrap();
+        let json = serde_json::to_string(&tokens).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Token>>(&json).unwrap(), tokens);
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 2.0));
+        // Chain(n) は n が 0 の場合は "0" を、それ以外の場合は "Eval(Chain(n - 1)) + 1" を返す (Eval を n + 1 段呼び出す)
+        context.add_function(Function::typed("Chain", Some(1), |args| match &args[0] {
+            Evaluated::Number(n) if *n == 0.0 => Ok(Evaluated::Str("0".to_string())),
+            Evaluated::Number(n) => Ok(Evaluated::Str(format!("Eval(Chain({})) + 1", n - 1.0))),
+            _ => Err("error: unexpected argument".to_string()),
+        }));
+        // 自身を評価する数式を返す
+        context.add_function(Function::typed("Me", Some(0), |_| {
+            Ok(Evaluated::Str("Eval(Me())".to_string()))
+        }));
+
+        let success_data = [
+            ("Eval(\"1 + 2\") * x", 6.0),
+            ("Eval(\"Add(x, 1)\")", 3.0),
+            ("Eval(Chain(3))", 3.0),
+            ("Eval(Chain(15))", 15.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        for input in ["Eval(1)", "Eval(\"1 +\")", "Eval(\"y\")", "Eval(Chain(16))"] {
+            assert!(context.evaluate(input).is_err(), "{} should fail", input);
+        }
+
+        // 自身を評価し続ける場合は、スタックが溢れる前に深さの上限でエラーとなる
+        assert_eq!(
+            context.evaluate("Eval(Me())").map_err(|e| e.msg),
+            Err("error: recursion budget of \"Eval\" is exhausted, \"Eval(Me())\"".to_string())
+        );
+
+        context.set_eval_budget(3);
+        assert_eq!(context.evaluate("Eval(Chain(2))"), Ok(2.0));
+        assert!(context.evaluate("Eval(Chain(3))").is_err());
+        context.set_eval_budget(0);
+        assert!(context.evaluate("Eval(\"1\")").is_err());
+        assert_eq!(context.evaluate("Default(Eval(\"1\"), 5)"), Ok(5.0));
+
+        // Eval に渡された数式も、外側の数式と同じ字句解析のオプションで解析する
+        let mut context = Context::new();
+        context.set_lexer_options(LexerOptions {
+            argument_separator: ';',
+            ..LexerOptions::default()
+        });
+        assert_eq!(context.evaluate("Eval(\"Add(1; 2)\")"), Ok(3.0));
+        assert!(context.evaluate("Eval(\"Add(1, 2)\")").is_err());
+    }
+
+    #[test]
+    fn test_computed_variables() {
+        let area = || {
+            Variable::new_computed("area", |env| {
+                Some(env.resolve("width")? * env.resolve("height")?)
+            })
+        };
+        let mut context = Context::new();
+        context.add_constant("height", 3.0);
+        context.add_variable(area());
+        context.add_variable(Variable::new("width", 2.0));
+
+        assert_eq!(context.evaluate("area * 2"), Ok(12.0));
+
+        // 参照した変数が存在しない場合はエラー (Default で置き換えられる)
+        let mut context = Context::new();
+        context.add_variable(area());
+        assert_eq!(
+            context.evaluate("area").map_err(|e| e.msg),
+            Err("error: failed to compute variable, \"area\"".to_string())
+        );
+        assert_eq!(context.evaluate("Default(area, -1)"), Ok(-1.0));
+
+        // parse_formula, Environment でも使用できる
+        let variables = || {
+            vec![
+                Variable::new("width", 4.0),
+                area(),
+                Variable::new("height", 0.5),
+                // 先に指定されたものを使用する
+                Variable::new("area", 100.0),
+            ]
+        };
+        assert_eq!(parse_formula("area + 1", vec![], variables()), Ok(3.0));
+        assert_eq!(
+            Environment::new(vec![], variables()).evaluate("area + width"),
+            Ok(6.0)
+        );
+    }
+
+    #[test]
+    fn test_approx_equal() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 1.0));
+
+        let success_data = [
+            ("0.1 + 0.2 ~= 0.3", 1.0),
+            ("0.1 + 0.2 == 0.3", 0.0),
+            ("x ~= 1.01", 0.0),
+            ("x ~= 1.01 == 0", 1.0),
+            ("If(0.1 * 3 ~= 0.3, 5, 6)", 5.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        context.set_approx_tolerance(0.1);
+        assert_eq!(context.evaluate("x ~= 1.01"), Ok(1.0));
+        assert_eq!(context.evaluate("x ~= 1.2"), Ok(0.0));
+        assert_eq!(context.evaluate("Eval(\"x ~= 1.01\")"), Ok(1.0));
+        context.set_approx_tolerance(0.0);
+        assert_eq!(context.evaluate("0.1 + 0.2 ~= 0.3"), Ok(0.0));
+        assert_eq!(context.evaluate("x ~= 1"), Ok(1.0));
+    }
+
+    #[test]
+    fn test_formula_error() {
+        let context = Context::new();
+        let failure_data = [
+            ("1 + $", ErrorKind::Lexer, "error: unexpected char, '$'"),
+            ("(1 + 2", ErrorKind::Lexer, "error: unexpected end of line"),
+            (
+                "x + 1",
+                ErrorKind::Processor,
+                "error: unknown variable, \"x\"",
+            ),
+        ];
+        for (input, kind, msg) in failure_data {
+            let error = context.evaluate(input).unwrap_err();
+            assert_eq!((error.kind(), error.message()), (kind, msg), "{}", input);
+            assert_eq!(error.to_string(), msg);
+        }
+
+        // Box<dyn Error> を返す関数で ? を使用できる
+        fn evaluate(input: &str) -> Result<f64, Box<dyn std::error::Error>> {
+            Ok(Context::new().evaluate(input)?)
+        }
+        assert_eq!(evaluate("1 + 2").unwrap(), 3.0);
+        assert_eq!(
+            format!("failed: {}", evaluate("1 +").unwrap_err()),
+            "failed: error: unexpected end of line"
+        );
+    }
+
+    /// 現在のスレッドでメモリを確保した回数を数えるアロケータ
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// `f` の実行中に (結果の破棄を含めて) メモリを確保した回数
+    fn count_allocations<T>(f: impl FnOnce() -> T) -> usize {
+        let before = ALLOCATIONS.with(|count| count.get());
+        drop(f());
+        ALLOCATIONS.with(|count| count.get()) - before
+    }
+
+    #[test]
+    fn test_evaluate_code() {
+        let mut context = Context::new();
+        context.set_processor_options(ProcessorOptions {
+            division_by_zero_error: true,
+            ..ProcessorOptions::default()
+        });
+        assert_eq!(context.evaluate_code("1 + 2"), Ok(3.0));
+        assert_eq!(
+            parse_formula_code("x * 2", vec![], vec![Variable::new("x", 3.0)]),
+            Ok(6.0)
+        );
+
+        let failure_data = [
+            (
+                "1 + $",
+                ErrorKind::Lexer,
+                ErrorCode::UnexpectedChar('$'),
+                (Some(4), None),
+            ),
+            (
+                "(1 + 2",
+                ErrorKind::Lexer,
+                ErrorCode::UnexpectedEndOfLine,
+                (Some(6), None),
+            ),
+            (
+                "1 : 2",
+                ErrorKind::Parser,
+                ErrorCode::ColonWithoutQuestion,
+                (None, Some(1)),
+            ),
+            (
+                "x + 1",
+                ErrorKind::Processor,
+                ErrorCode::UnknownVariable,
+                (None, Some(0)),
+            ),
+            (
+                "1 / 0",
+                ErrorKind::Processor,
+                ErrorCode::DivisionByZero(1.0, 0.0),
+                (None, Some(2)),
+            ),
+            (
+                "Div(1, 0) + 2",
+                ErrorKind::Processor,
+                ErrorCode::FunctionDivisionByZero {
+                    function: "Div",
+                    provided: (1.0, 0.0),
+                },
+                (None, Some(2)),
+            ),
+            (
+                "Max()",
+                ErrorKind::Processor,
+                ErrorCode::InvalidArgsCount {
+                    function: "Max",
+                    requirement: "at least 1",
+                    provided: 0,
+                },
+                (None, Some(0)),
+            ),
+            (
+                "1 + CountIf()",
+                ErrorKind::Processor,
+                ErrorCode::InvalidArgsCount {
+                    function: "CountIf",
+                    requirement: "at least 1",
+                    provided: 0,
+                },
+                (None, Some(1)),
+            ),
+        ];
+        for (input, kind, code, position) in failure_data {
+            let error = context.evaluate_code(input).unwrap_err();
+            assert_eq!(
+                (error.kind(), error.code(), (error.pos(), error.index())),
+                (kind, code, position),
+                "{}",
+                input
+            );
+
+            // メッセージは必要になった時点で、コードと位置から生成できる
+            let full = context.evaluate(input).unwrap_err();
+            assert_eq!(error.to_error(&context, input), Some(full), "{}", input);
+
+            // メッセージを生成しないため、メモリの確保が少ない
+            let full = count_allocations(|| context.evaluate(input));
+            let compact = count_allocations(|| context.evaluate_code(input));
+            assert!(compact < full, "{}: {} >= {}", input, compact, full);
+        }
+
+        let error = parse_formula_code("1 +", vec![], vec![]).unwrap_err();
+        assert_eq!(
+            (error.kind(), error.code(), error.pos()),
+            (ErrorKind::Lexer, ErrorCode::UnexpectedEndOfLine, Some(3))
+        );
+        assert_eq!(
+            error.to_error(&Context::new(), "1 +"),
+            parse_formula("1 +", vec![], vec![]).err()
+        );
+        let full = count_allocations(|| parse_formula("y", vec![], vec![]));
+        let compact = count_allocations(|| parse_formula_code("y", vec![], vec![]));
+        assert!(compact < full, "{} >= {}", compact, full);
+
+        // メッセージの生成では数式を評価しないため、評価のたびに結果が変わる関数でも元のエラーとなる
+        let ticks = std::rc::Rc::new(std::cell::Cell::new(0.0));
+        let counter = std::rc::Rc::clone(&ticks);
+        context.add_function(
+            Function::new("Tick", 0, move |_| {
+                counter.set(counter.get() + 1.0);
+                counter.get() - 1.0
+            })
+            .impure(),
+        );
+        let error = context.evaluate_code("1 / Tick()").unwrap_err();
+        assert_eq!(
+            error.to_error(&context, "1 / Tick()").map(|e| e.msg),
+            Some("error: division by zero, 1.0 Slash 0.0".to_string())
+        );
+        assert_eq!(ticks.get(), 1.0);
+
+        // 登録した関数が返したメッセージは、コードと位置から生成できない
+        context.add_function(Function::new_fallible("Fail", 0, |_| {
+            Err("error: failed".to_string())
+        }));
+        let error = context.evaluate_code("Fail()").unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Custom);
+        assert_eq!(error.to_error(&context, "Fail()"), None);
+    }
+
+    #[test]
+    fn test_evaluate_partial() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 2.0));
+
+        let success_data = [
+            ("1 + 2", PartialEvaluation::Complete(3.0)),
+            ("x", PartialEvaluation::Complete(2.0)),
+            ("1 +", PartialEvaluation::Incomplete),
+            ("1 + ", PartialEvaluation::Incomplete),
+            ("", PartialEvaluation::Incomplete),
+            ("-", PartialEvaluation::Incomplete),
+            ("(1 + 2", PartialEvaluation::Incomplete),
+            ("Add(1, ", PartialEvaluation::Incomplete),
+            ("Add(1", PartialEvaluation::Incomplete),
+            ("Add(Add(1, 2", PartialEvaluation::Incomplete),
+            ("x > 1 ? 1", PartialEvaluation::Incomplete),
+            ("x > 1 ? (1 :", PartialEvaluation::Incomplete),
+            ("1e", PartialEvaluation::Incomplete),
+            ("\"ab", PartialEvaluation::Incomplete),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (context.evaluate_partial(input), input),
+                (Ok(expected), input)
+            );
+        }
+
+        let failure_data = [
+            "1 + * 2",
+            "1 2",
+            "1 + 2)",
+            "(1 + 2))",
+            "x : 1",
+            "Add(1, 2) $",
+        ];
+        for input in failure_data {
+            assert!(
+                context.evaluate_partial(input).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+
+        // 完全な数式の評価エラーは、そのまま返す
+        assert!(context.evaluate_partial("y + 1").is_err());
+    }
+
+    #[test]
+    fn test_safe_profile() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new_bool("flag", true));
+        context.add_constant("inf", f64::INFINITY);
+        context.set_processor_options(ProcessorOptions::safe_profile());
+
+        let success_data = [
+            ("1 + 2", 3.0),
+            ("7 % 4", 3.0),
+            ("Sqrt(4)", 2.0),
+            ("If(flag, 1, 0)", 1.0),
+            // 評価に失敗した引数は Default で置き換えられる
+            ("Default(1 / 0, 99)", 99.0),
+            // 変数・定数の値はそのまま使用できる
+            ("inf", f64::INFINITY),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        let failure_data = [
+            "1 / 0",
+            "1e308 * 10",
+            "0 / 0",
+            "1 % 0",
+            "Ln(0)",
+            "inf - 1",
+            "flag + 1",
+        ];
+        for input in failure_data {
+            assert!(context.evaluate(input).is_err(), "{} should fail", input);
+        }
+
+        // 個別のオプションでも有効にできる
+        context.set_processor_options(ProcessorOptions {
+            division_by_zero_error: true,
+            ..ProcessorOptions::default()
+        });
+        assert_eq!(
+            context.evaluate("1 / 0").map_err(|e| e.msg),
+            Err("error: division by zero, 1.0 Slash 0.0".to_string())
+        );
+        assert_eq!(context.evaluate("Default(1 / 0, 99)"), Ok(99.0));
+        assert_eq!(context.evaluate("Ln(0)"), Ok(f64::NEG_INFINITY));
+
+        // 評価結果のみを検査する
+        context.set_processor_options(ProcessorOptions {
+            non_finite_result_error: true,
+            ..ProcessorOptions::default()
+        });
+        assert_eq!(
+            context.evaluate("0 / 0").map_err(|e| e.msg),
+            Err("error: non-finite result, NaN produced by Slash".to_string())
+        );
+        assert!(context.evaluate("inf").is_err());
+        assert_eq!(context.evaluate("1 / inf"), Ok(0.0));
+        assert_eq!(context.evaluate("If(1 / 0 > 0, 1, 2)"), Ok(1.0));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let mut context = Context::new();
+        assert_eq!(context.evaluate("1 / 0"), Ok(f64::INFINITY));
+        assert_eq!(context.evaluate("Div(1, 0)"), Ok(f64::INFINITY));
+        assert!(context.evaluate("Mod(1, 0)").unwrap().is_nan());
+
+        context.set_processor_options(ProcessorOptions {
+            division_by_zero_error: true,
+            ..ProcessorOptions::default()
+        });
+        let failure_data = [
+            ("1 / 0", "error: division by zero, 1.0 Slash 0.0"),
+            ("5 % (2 - 2)", "error: division by zero, 5.0 Percent 0.0"),
+            ("Div(1, 0)", "error: division by zero, Div(1.0, 0.0)"),
+            ("Mod(1, 0)", "error: division by zero, Mod(1.0, 0.0)"),
+        ];
+        for (input, expected) in failure_data {
+            assert_eq!(
+                (context.evaluate(input).map_err(|e| e.msg), input),
+                (Err(expected.to_string()), input)
+            );
+        }
+        assert_eq!(context.evaluate("Div(6, 4) + Mod(7, 4)"), Ok(4.5));
+
+        // 無効にすると元の挙動に戻る
+        context.set_processor_options(ProcessorOptions::default());
+        assert_eq!(context.evaluate("Div(1, 0)"), Ok(f64::INFINITY));
+    }
+
+    #[test]
+    fn test_overflow_error() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("big", 1e308));
+        context.add_constant("inf", f64::INFINITY);
+        assert_eq!(context.evaluate("big * 10"), Ok(f64::INFINITY));
+
+        context.set_processor_options(ProcessorOptions {
+            overflow_error: true,
+            ..ProcessorOptions::default()
+        });
+        let success_data = [
+            ("big * 1", 1e308),
+            // 無限大を含む演算・ゼロ除算はオーバーフローとして扱わない
+            ("inf * 2", f64::INFINITY),
+            ("1 / 0", f64::INFINITY),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!((context.evaluate(input), input), (Ok(expected), input));
+        }
+
+        for input in ["big * 10", "big + big", "10 ** 400", "Add(1, big * big)"] {
+            assert_eq!(
+                (context.evaluate(input).map_err(|e| e.msg), input),
+                (Err("error: arithmetic overflow".to_string()), input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_to_ast() {
+        let expr = parse_to_ast("1 + 2 * 3").unwrap();
+        assert_eq!(
+            expr,
+            Expr::BinOp(
+                Value::Plus,
+                Box::new(Expr::Num(1.0)),
+                Box::new(Expr::BinOp(
+                    Value::Asterisk,
+                    Box::new(Expr::Num(2.0)),
+                    Box::new(Expr::Num(3.0)),
+                )),
+            )
+        );
+
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 2.0));
+        assert_eq!(context.eval_ast(&expr), Ok(7.0));
+
+        let success_data = [
+            ("(1 + 2) * 3", 9.0),
+            ("Add(x, 1) ** 2", 9.0),
+            ("Cond(0, Wrap(1, 0), 5)", 5.0),
+        ];
+        for (input, expected) in success_data {
+            let expr = context.parse_to_ast(input).unwrap();
+            assert_eq!((context.eval_ast(&expr), input), (Ok(expected), input));
+        }
+
+        // 符号反転
+        let expr = Expr::UnOp(Value::Minus, Box::new(Expr::Var("x".to_string())));
+        assert_eq!(context.eval_ast(&expr), Ok(-2.0));
+
+        assert!(parse_to_ast("1 +").is_err());
+    }
+
+    #[test]
+    fn test_differentiate() {
+        let expr = differentiate(&parse_to_ast("Sin(x)").unwrap(), "x");
+        assert_eq!(
+            expr,
+            Ok(Expr::Call(
+                "Cos".to_string(),
+                vec![Expr::Var("x".to_string())]
+            ))
+        );
+
+        // 導関数の値を x = 3 で評価する
+        let mut context = Context::new();
+        context.add_variable(Variable::new("x", 3.0));
+        context.add_variable(Variable::new("y", 2.0));
+        let success_data = [
+            ("x * x", 6.0),
+            ("x * y + 1", 2.0),
+            ("x ** 3 - 2 * x", 25.0),
+            ("(x + 1) / x", -1.0 / 9.0),
+            ("Exp(2 * x)", 2.0 * 6f64.exp()),
+            ("Ln(x * x)", 2.0 / 3.0),
+            ("x ** x", 27.0 * (3f64.ln() + 1.0)),
+        ];
+        for (input, expected) in success_data {
+            let expr = differentiate(&context.parse_to_ast(input).unwrap(), "x").unwrap();
+            let actual = context.eval_ast(&expr).unwrap();
+            assert!(
+                (actual - expected).abs() < 1e-9,
+                "{}: actual {}, expected {}",
+                input,
+                actual,
+                expected
+            );
+        }
+
+        // 簡約すると x * x の導関数は 2 * x となる
+        assert_eq!(
+            differentiate(&parse_to_ast("x * x").unwrap(), "x").map(simplify),
+            parse_to_ast("2 * x")
+        );
+
+        assert!(differentiate(&parse_to_ast("x > 1").unwrap(), "x").is_err());
+        assert!(differentiate(&parse_to_ast("Round(x)").unwrap(), "x").is_err());
+    }
+
+    #[test]
+    fn test_trailing_commas() {
+        let mut context = Context::new();
+        assert!(context.evaluate("Add(1, 2,)").is_err());
+
+        context.set_lexer_options(LexerOptions {
+            trailing_commas: true,
+            ..LexerOptions::default()
+        });
+        assert_eq!(context.evaluate("Add(1, 2,)"), Ok(3.0));
+        assert_eq!(context.evaluate("Cond(0, 1, 99,)"), Ok(99.0));
+        // 引数の数は変わらない
+        assert!(context.evaluate("Sign(1, )").is_ok());
+        assert!(context.evaluate("Add(1,)").is_err());
+    }
+
+    #[test]
+    fn test_comments() {
+        let success_data = [
+            ("1 + 2 // note", 3.0),
+            ("1 /* x */ + 2", 3.0),
+            ("// 税込価格\n100 * /* 税率 */ 1.1", 110.00000000000001),
+            ("8 / /* 半分 */ 2", 4.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula(input, vec![], vec![]), input),
+                (Ok(expected), input)
+            );
+        }
+        assert!(parse_formula("1 /* x", vec![], vec![]).is_err());
+    }
+
+    #[test]
+    fn test_argument_separator() {
+        let mut context = Context::new();
+        context.set_lexer_options(LexerOptions {
+            argument_separator: ';',
+            ..LexerOptions::default()
+        });
+        assert_eq!(context.evaluate("Add(1; 2)"), Ok(3.0));
+        assert_eq!(context.evaluate("Cond(0; 1; Sub(5; 3))"), Ok(2.0));
+        assert!(context.evaluate("Add(1, 2)").is_err());
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn test_evaluate_bigint() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("n", 25.0));
+        let success_data = [
+            ("Factorial(25)", "15511210043330985984000000"),
+            ("Factorial(n)", "15511210043330985984000000"),
+            ("Factorial(30) / Factorial(28)", "870"),
+            ("2 ** 64 + 1", "18446744073709551617"),
+            ("Mod(-7, 3)", "-1"),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (context.evaluate_bigint(input).map(|n| n.to_string()), input),
+                (Ok(expected.to_string()), input)
+            );
+        }
+
+        // f64 では正確に表現できない
+        assert_ne!(
+            context.evaluate("Factorial(25)").map(|n| n.to_string()),
+            Ok("15511210043330985984000000".to_string())
+        );
+
+        for input in ["1 / 3", "1.5 + 1", "Sin(1)", "x + 1", "Factorial(-1)"] {
+            assert!(
+                context.evaluate_bigint(input).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_constant() {
+        let functions = [
+            Function::new("Rand", 0, |_| 0.4).impure(),
+            Function::new("Pow", 2, |args| args[0].powf(args[1])),
+        ];
+        let success_data = [
+            ("2 * 3", true),
+            ("Add(1, Pow(2, 3)) + Sin(1)", true),
+            ("x + 1", false),
+            ("Add(1, x)", false),
+            ("Rand() + 1", false),
+            ("Now()", false),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (is_constant(input, &functions), input),
+                (Ok(expected), input)
+            );
+        }
+
+        for input in ["Unknown(1)", "1 +"] {
+            assert!(
+                is_constant(input, &functions).is_err(),
+                "{} should fail",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_instantiate_template() {
+        let subs = HashMap::from([
+            ("a".to_string(), "1+2".to_string()),
+            ("b".to_string(), "3".to_string()),
+            ("neg".to_string(), "-4".to_string()),
+        ]);
+
+        let success_data = [
+            ("{a} + {b}", "(1+2) + (3)", 6.0),
+            // 置換後も優先度は変わらない
+            ("{a} * {b}", "(1+2) * (3)", 9.0),
+            ("{b} - {neg}", "(3) - (-4)", 7.0),
+            ("Add({a}, {a})", "Add((1+2), (1+2))", 6.0),
+            ("10", "10", 10.0),
+        ];
+        for (template, expected_formula, expected) in success_data {
+            let formula = instantiate_template(template, &subs).unwrap();
+            assert_eq!(formula, expected_formula);
+            assert_eq!(
+                (parse_formula(&formula, vec![], vec![]), template),
+                (Ok(expected), template)
+            );
+        }
+
+        let failure_data = ["{c} + 1", "{a", "{} + 1"];
+        for template in failure_data {
+            assert_eq!(
+                (
+                    instantiate_template(template, &subs).map_err(|e| e.error_type),
+                    template
+                ),
+                (Err(ErrorKind::Template), template)
+            );
+        }
+    }
+
+    #[test]
+    fn test_environment() {
+        let environment = Environment::new(
+            vec![Function::new("Pow", 2, |args| args[0].powf(args[1]))],
+            vec![
+                Variable::new("x", 2.0),
+                Variable::new("y", 3.0),
+                Variable::new("x", 100.0),
+            ],
+        );
+
+        let success_data = [
+            ("x + y", 5.0),
+            ("Pow(x, y) * 2", 16.0),
+            ("If(x < y, Add(x, 1), y)", 3.0),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (parse_formula_with_environment(input, &environment), input),
+                (Ok(expected), input)
+            );
+        }
+        assert!(environment.evaluate("z + 1").is_err());
+
+        // Context の定数・オプションも使用できる
+        let mut context = Context::new();
+        context.add_constant("x", 10.0);
+        let environment = Environment::with_context(context, vec![Variable::new("x", 2.0)]);
+        assert_eq!(environment.evaluate("x * 2"), Ok(20.0));
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("Add(1, 2) * 3"),
+            Ok(vec![
+                Token::Property("Add".to_string()),
+                Token::LeftParenthesis,
+                Token::Number(1.0),
+                Token::Comma,
+                Token::Number(2.0),
+                Token::RightParenthesis,
+                Token::Asterisk,
+                Token::Number(3.0),
+            ])
+        );
+        assert_eq!(
+            tokenize("  "),
+            Err(FormulaError {
+                msg: "error: unexpected end of line".to_string(),
+                error_type: ErrorKind::Lexer,
+            })
+        );
+        assert!(tokenize("2(3 + 2)").is_err());
+    }
+
+    #[test]
+    fn test_compile_formula() {
+        let formula = compile_formula(
+            "Square(x) + y * 2",
+            vec![Function::new("Square", 1, |args| args[0] * args[0])],
+        )
+        .unwrap();
+
+        let success_data = [
+            (vec![Variable::new("x", 1.0), Variable::new("y", 2.0)], 5.0),
+            (vec![Variable::new("x", 3.0), Variable::new("y", -1.0)], 7.0),
+            (
+                vec![
+                    Variable::new("y", 0.5),
+                    Variable::new("x", 2.0),
+                    Variable::new("x", 100.0),
+                ],
+                5.0,
+            ),
+        ];
+        for (variables, expected) in success_data {
+            assert_eq!(formula.evaluate(variables), Ok(expected));
+        }
+        assert!(formula.evaluate(vec![Variable::new("x", 1.0)]).is_err());
+
+        assert!(compile_formula("1 +", vec![]).is_err());
+        assert!(compile_formula("Add(1", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_precompute() {
+        let double = || vec![Function::new("Double", 1, |args| args[0] * 2.0)];
+        let success_data = [
+            ("2 * 3 + 1", 7.0),
+            ("Add(1, 2) ** 2", 9.0),
+            ("Double(4) - 1", 7.0),
+            ("1 / 0", f64::INFINITY),
+        ];
+        for (input, expected) in success_data {
+            let formula = compile_formula(input, double()).unwrap().precompute();
+            assert_eq!(
+                (formula.values(), input),
+                (&[Value::Number(expected)][..], input)
+            );
+            assert_eq!(formula.evaluate(vec![]), Ok(expected));
+        }
+
+        // 定数でない場合・評価に失敗する場合はそのまま
+        for input in ["x * 2", "Now() + 1", "Sqrt(-1)", "Double(x)"] {
+            let formula = compile_formula(input, double()).unwrap();
+            let values = formula.values().to_vec();
+            assert_eq!((formula.precompute().values(), input), (&values[..], input));
+        }
+
+        let formula = compile_formula("x * (2 + 3)", vec![]).unwrap().precompute();
+        assert_eq!(formula.evaluate(vec![Variable::new("x", 2.0)]), Ok(10.0));
+        assert!(compile_formula("Sqrt(-1)", vec![])
+            .unwrap()
+            .precompute()
+            .evaluate(vec![])
+            .is_err());
+    }
+
+    #[test]
+    fn test_eval_into() {
+        let formula = compile_formula("x * y + 1", vec![]).unwrap();
+        let mut out = vec![0.0; 3];
+
+        let (x, y) = ([1.0, 2.0, 3.0], [10.0, 20.0, 30.0]);
+        let columns = HashMap::from([("x".to_string(), &x[..]), ("y".to_string(), &y[..])]);
+        assert_eq!(formula.eval_into(&columns, &mut out), Ok(()));
+        assert_eq!(out, [11.0, 41.0, 91.0]);
+
+        // 同じバッファを次のバッチで使い回す
+        let (x, y) = ([-1.0, 0.0, 0.5], [2.0, 5.0, 4.0]);
+        let columns = HashMap::from([("x".to_string(), &x[..]), ("y".to_string(), &y[..])]);
+        assert_eq!(formula.eval_into(&columns, &mut out), Ok(()));
+        assert_eq!(out, [-1.0, 1.0, 3.0]);
+
+        // 列の長さが異なる場合はエラー
+        let short = [1.0, 2.0];
+        let columns = HashMap::from([("x".to_string(), &x[..]), ("y".to_string(), &short[..])]);
+        assert!(formula.eval_into(&columns, &mut out).is_err());
+        let columns = HashMap::from([("x".to_string(), &x[..]), ("y".to_string(), &y[..])]);
+        assert!(formula.eval_into(&columns, &mut [0.0; 4]).is_err());
+
+        // 存在しない列を参照した場合はエラー
+        let columns = HashMap::from([("x".to_string(), &x[..])]);
+        assert!(formula.eval_into(&columns, &mut out).is_err());
+
+        // 行がない場合は何も評価しない
+        let columns: HashMap<String, &[f64]> = HashMap::from([("x".to_string(), &[][..])]);
+        assert_eq!(formula.eval_into(&columns, &mut []), Ok(()));
+    }
+
+    #[test]
+    fn test_formula_cache() {
+        let mut cache = FormulaCache::new(
+            2,
+            vec![Function::new("Pow", 2, |args| args[0].powf(args[1]))],
+        );
+        let x = |value| [Variable::new("x", value)];
+
+        assert_eq!(cache.eval("Pow(x, 2)", &x(3.0)), Ok(9.0));
+        assert_eq!(cache.compile_count(), 1);
+        // 2 回目は解析しない
+        assert_eq!(cache.eval("Pow(x, 2)", &x(4.0)), Ok(16.0));
+        assert_eq!(cache.compile_count(), 1);
+
+        assert_eq!(cache.eval("x + 1", &x(1.0)), Ok(2.0));
+        assert_eq!(cache.compile_count(), 2);
+        assert_eq!(cache.len(), 2);
+
+        // 容量を超えた場合は、最後に使われてから最も時間が経ったもの ("Pow(x, 2)") を破棄する
+        assert_eq!(cache.eval("x + 1", &x(2.0)), Ok(3.0));
+        assert_eq!(cache.eval("x * 2", &x(2.0)), Ok(4.0));
+        assert_eq!(cache.compile_count(), 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.eval("x + 1", &x(3.0)), Ok(4.0));
+        assert_eq!(cache.compile_count(), 3);
+        assert_eq!(cache.eval("Pow(x, 2)", &x(5.0)), Ok(25.0));
+        assert_eq!(cache.compile_count(), 4);
+
+        // 解析に失敗した数式は保持しない・評価に失敗した数式は保持する
+        assert!(cache.eval("1 +", &[]).is_err());
+        assert!(cache.eval("y", &[]).is_err());
+        assert!(cache.eval("y", &[Variable::new("y", 1.0)]).is_ok());
+        assert_eq!(cache.compile_count(), 5);
+
+        // 容量が 0 の場合は保持しない
+        let mut cache = FormulaCache::new(0, vec![]);
+        assert_eq!(cache.eval("1 + 1", &[]), Ok(2.0));
+        assert_eq!(cache.eval("1 + 1", &[]), Ok(2.0));
+        assert_eq!(cache.compile_count(), 2);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_now() {
+        let mut context = Context::new();
+        context.set_now(1_700_000_000.0);
+        assert_eq!(context.evaluate("Now() + Days(1)"), Ok(1_700_086_400.0));
+        assert_eq!(context.evaluate("Days(2) - Days(1)"), Ok(86400.0));
+
+        // 固定しない場合は現在時刻を返す
+        let now = Context::new().evaluate("Now()").unwrap();
+        assert!(now > 1_700_000_000.0);
+    }
+
+    #[test]
+    fn test_rounding_mode() {
+        let success_data = [
+            (RoundingMode::HalfUp, [3.0, 4.0, -3.0]),
+            (RoundingMode::HalfEven, [2.0, 4.0, -2.0]),
+            (RoundingMode::Floor, [2.0, 3.0, -3.0]),
+            (RoundingMode::Ceil, [3.0, 4.0, -2.0]),
+        ];
+        for (mode, expected) in success_data {
+            let mut context = Context::new();
+            context.set_rounding_mode(mode);
+            for (input, expected) in ["Round(2.5)", "Round(3.5)", "Round(-2.5)"]
+                .into_iter()
+                .zip(expected)
+            {
+                assert_eq!(
+                    (context.evaluate(input), input, mode),
+                    (Ok(expected), input, mode)
+                );
+            }
+
+            // FixDecimals も同じ丸め方を使用する
+            assert_eq!(
+                (context.evaluate("FixDecimals(0.25, 1)"), mode),
+                (Ok(expected[0] / 10.0), mode)
+            );
+
+            // Floor・Ceil・Trunc は丸め方の影響を受けない
+            assert_eq!(context.evaluate("Floor(-2.5)"), Ok(-3.0));
+            assert_eq!(context.evaluate("Ceil(-2.5)"), Ok(-2.0));
+            assert_eq!(context.evaluate("Trunc(-2.5)"), Ok(-2.0));
+        }
+    }
+
+    #[test]
+    fn test_nan_policy() {
+        let success_data = [
+            (NanPolicy::Propagate, "(0 / 0) + 5", Some(f64::NAN)),
+            (NanPolicy::Strict, "(0 / 0) + 5", None),
+            (NanPolicy::Absorb, "(0 / 0) + 5", Some(5.0)),
+            (NanPolicy::Absorb, "5 - (0 / 0)", Some(5.0)),
+            (NanPolicy::Absorb, "(0 / 0) - 5", Some(-5.0)),
+            (NanPolicy::Absorb, "(0 / 0) * 5", Some(5.0)),
+            (NanPolicy::Absorb, "5 / (0 / 0)", Some(5.0)),
+            (NanPolicy::Absorb, "2 ** (0 / 0)", Some(2.0)),
+            // 両方が NaN の場合や、単位元のない演算の場合は伝播させる
+            (NanPolicy::Absorb, "(0 / 0) + (0 / 0)", Some(f64::NAN)),
+            (NanPolicy::Absorb, "5 % (0 / 0)", Some(f64::NAN)),
+            // NaN を含まない演算は変わらない
+            (NanPolicy::Strict, "1 + 2", Some(3.0)),
+            (NanPolicy::Absorb, "1 + 2", Some(3.0)),
+        ];
+        for (policy, input, expected) in success_data {
+            let mut context = Context::new();
+            context.set_processor_options(ProcessorOptions {
+                nan_policy: policy,
+                ..ProcessorOptions::default()
+            });
+            let result = context.evaluate(input);
+            match expected {
+                Some(expected) if expected.is_nan() => {
+                    assert!(result.unwrap().is_nan(), "{} ({:?})", input, policy)
+                }
+                Some(expected) => {
+                    assert_eq!((result, input, policy), (Ok(expected), input, policy))
+                }
+                None => assert!(result.is_err(), "{} ({:?})", input, policy),
+            }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_checked() {
+        let mut context = Context::new();
+        context.add_variable(Variable::new("big", 1e16));
+        let success_data = [
+            ("(10000000000000000 + 1) - 10000000000000000", 0.0, 1),
+            ("(big + 1) - big", 0.0, 1),
+            ("1 - big", 1.0 - 1e16, 1),
+            // 桁落ち
+            ("(1 + 0.000000001) - 1", (1.0 + 0.000000001) - 1.0, 1),
+            (
+                "100000000 - 99999999.999999",
+                100000000.0 - 99999999.999999,
+                1,
+            ),
+            // 値の近い数同士の減算は、結果が正確であっても警告する (0 となる場合を除く)
+            ("(big + 2) - big", 2.0, 1),
+            ("big - big", 0.0, 0),
+            ("1 + 2 - 3", 0.0, 0),
+            ("0.1 + 0.2", 0.1 + 0.2, 0),
+            // 選ばれなかった分岐は評価されないため、警告も報告されない
+            ("If(1, 0, (big + 1) - big)", 0.0, 0),
+            ("If(0, 0, (big + 1) - big)", 0.0, 1),
+        ];
+        for (input, expected, warnings) in success_data {
+            let (result, actual) = context.evaluate_checked(input).unwrap();
+            assert_eq!((result, input), (expected, input));
+            assert_eq!((actual.len(), input), (warnings, input), "{:?}", actual);
+            assert!(actual.iter().all(|w| w.kind == WarningKind::PrecisionLoss));
+        }
+
+        // 警告があっても結果は evaluate と変わらない
+        assert_eq!(
+            context.evaluate("(big + 1) - big"),
+            context.evaluate_checked("(big + 1) - big").map(|r| r.0)
+        );
+        assert!(context.evaluate_checked("1 +").is_err());
+    }
+
+    #[test]
+    fn test_registration_limits() {
+        let mut context = Context::new();
+        context.set_registration_limits(RegistrationLimits {
+            max_functions: Some(1),
+            max_variables: Some(2),
+        });
+
+        assert!(context
+            .try_add_function(Function::new("Pow", 2, |args| args[0].powf(args[1])))
+            .is_ok());
+        assert_eq!(
+            context
+                .try_add_function(Function::new("Twice", 1, |args| args[0] * 2.0))
+                .map_err(|e| (e.msg, e.error_type)),
+            Err((
+                "error: too many functions, the limit is 1".to_string(),
+                ErrorKind::Registration
+            ))
+        );
+
+        assert!(context.try_add_variable(Variable::new("x", 1.0)).is_ok());
+        assert!(context.try_add_variable(Variable::new("y", 2.0)).is_ok());
+        assert!(context.try_add_variable(Variable::new("z", 3.0)).is_err());
+
+        // 上限を超えたものは登録されない
+        assert_eq!(context.evaluate("Pow(x + y, 2)"), Ok(9.0));
+        assert!(context.evaluate("Twice(1)").is_err());
+        assert!(context.evaluate("z").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "error: too many variables, the limit is 0")]
+    fn test_registration_limits_panic() {
+        let mut context = Context::new();
+        context.set_registration_limits(RegistrationLimits {
+            max_variables: Some(0),
+            ..RegistrationLimits::default()
+        });
+        context.add_variable(Variable::new("x", 1.0));
+    }
+
+    #[test]
+    fn test_parse_with_postfix() {
+        let context = Context::new();
+        let success_data = [
+            ("(2 + 3) * 4", "2 3 + 4 *"),
+            ("2 + 3 * 4", "2 3 4 * +"),
+            ("2 ** 3 ** 2", "2 3 2 ** **"),
+            ("Add(x, 1) / -y", "x 1 Add y neg /"),
+            ("x > 0 ? 1 : 2", "x 0 > 1 2 If"),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (context.parse_with_postfix(input).map(|(_, s)| s), input),
+                (Ok(expected.to_string()), input)
+            );
+        }
+        assert!(context.parse_with_postfix("1 +").is_err());
+    }
+
+    #[test]
+    fn test_max_stack_depth() {
+        let context = Context::new();
+        let success_data = [
+            ("1", 1),
+            ("1 + 2", 2),
+            ("1 * 2 + 3 * 4", 3),
+            ("1 + (2 + (3 + (4 + (5 + 6))))", 6),
+            ("Add(1, Add(2, Add(3, 4)))", 4),
+            ("Now() + 1", 2),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                (context.max_stack_depth(input), input),
+                (Ok(expected), input)
+            );
+        }
+
+        assert!(context.max_stack_depth("1 +").is_err());
     }
 }
@@ -0,0 +1,127 @@
+// 環境 (libm の実装・バージョン) によらず同じ結果を返す初等関数
+//
+// 四則演算と丸めのみを使用し、常に同じ順序で計算することで、結果をビット単位で一致させる。
+// 精度は標準ライブラリの実装よりわずかに劣ることがある。
+
+use std::f64::consts::{LN_2, PI, TAU};
+
+/// テイラー展開の項数
+const TERMS: usize = 24;
+
+/// x を [-π, π] の範囲に縮約する
+fn reduce(x: f64) -> f64 {
+    let r = x - (x / TAU).round() * TAU;
+    r.clamp(-PI, PI)
+}
+
+pub fn sin(x: f64) -> f64 {
+    if !x.is_finite() {
+        return f64::NAN;
+    }
+
+    // sin(r) = r - r^3/3! + r^5/5! - ...
+    let r = reduce(x);
+    let r2 = r * r;
+    let mut sum = 0.0;
+    for n in (0..TERMS).rev() {
+        let k = (2 * n + 2) as f64;
+        sum = 1.0 - r2 / (k * (k + 1.0)) * sum;
+    }
+    r * sum
+}
+
+pub fn cos(x: f64) -> f64 {
+    if !x.is_finite() {
+        return f64::NAN;
+    }
+
+    // cos(r) = 1 - r^2/2! + r^4/4! - ...
+    let r = reduce(x);
+    let r2 = r * r;
+    let mut sum = 0.0;
+    for n in (0..TERMS).rev() {
+        let k = (2 * n + 1) as f64;
+        sum = 1.0 - r2 / (k * (k + 1.0)) * sum;
+    }
+    sum
+}
+
+pub fn exp(x: f64) -> f64 {
+    if x.is_nan() {
+        return f64::NAN;
+    }
+    if x > 709.8 {
+        return f64::INFINITY;
+    }
+    if x < -745.2 {
+        return 0.0;
+    }
+
+    // exp(x) = 2^k * exp(r) (|r| <= ln2 / 2)
+    let k = (x / LN_2).round();
+    let r = x - k * LN_2;
+    let mut sum = 1.0;
+    for n in (1..=TERMS).rev() {
+        sum = 1.0 + r / n as f64 * sum;
+    }
+
+    // 2^k は 2 のべき乗をかけることで求める (非正規数の範囲でも正規数のみを使うよう、2 回に分ける)
+    let k = k as i64;
+    let half = k / 2;
+    sum * pow2(half) * pow2(k - half)
+}
+
+/// 2^k (k は正規数の指数の範囲 [-1022, 1023] であること)
+///
+/// `f64::powi` は環境によって精度が異なりうるため、ビット列から直接組み立てる
+fn pow2(k: i64) -> f64 {
+    debug_assert!((-1022..=1023).contains(&k));
+    f64::from_bits(((k + 1023) as u64) << 52)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    #[test]
+    fn test_functions() {
+        let success_data = [
+            (sin as fn(f64) -> f64, f64::sin as fn(f64) -> f64),
+            (cos, f64::cos),
+            (exp, f64::exp),
+        ];
+        for (f, expected) in success_data {
+            for x in [0.0, 0.5, 1.0, -1.0, 2.0, FRAC_PI_2, 3.0, 10.0, -20.0] {
+                assert!(
+                    (f(x) - expected(x)).abs() <= 1e-12 * expected(x).abs().max(1.0),
+                    "x: {}, actual: {}, expected: {}",
+                    x,
+                    f(x),
+                    expected(x)
+                );
+            }
+        }
+
+        assert_eq!(sin(0.0), 0.0);
+        assert_eq!(cos(0.0), 1.0);
+        assert_eq!(exp(0.0), 1.0);
+        assert!(sin(f64::INFINITY).is_nan());
+        assert_eq!(exp(1000.0), f64::INFINITY);
+        assert_eq!(exp(-1000.0), 0.0);
+
+        // 非正規数・最大値付近の範囲
+        for x in [-745.0, -740.0, -708.5, 709.0, 709.7] {
+            assert!(
+                (exp(x) - x.exp()).abs() <= 1e-12 * x.exp(),
+                "x: {}, actual: {}, expected: {}",
+                x,
+                exp(x),
+                x.exp()
+            );
+        }
+        assert_eq!(pow2(-1022), f64::MIN_POSITIVE);
+        assert_eq!(pow2(1023), 2f64.powi(1023));
+        assert_eq!(pow2(0), 1.0);
+    }
+}
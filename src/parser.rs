@@ -3,37 +3,186 @@
 
 use std::collections::LinkedList;
 
+use crate::error::{ErrorCode, Subject};
 use crate::lexer::Token;
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Value {
     Number(f64),
-    Function(String),
+    /// 関数名と、呼び出し時に渡された引数の数
+    Function(String, usize),
+    /// 関数名と、呼び出し時に指定されたキーワード引数の名前 (引数の順番通り)
+    KeywordFunction(String, Vec<String>),
     Variable(String),
+    /// 文字列 (`"..."`)
+    Str(String),
     Plus,
     Minus,
     Asterisk,
     Slash,
     Percent,
+    /// 切り捨て除算 (`a \ b`)。商を負の無限大方向に丸める
+    Backslash,
+    Power,
     Equal,
     NotEqual,
+    /// 許容誤差の範囲で等しい (`a ~= b`)。許容誤差は評価時に指定する
+    ApproxEqual,
     GreaterThan,
     GreaterThanOrEqual,
     LessThan,
     LessThanOrEqual,
+    LogicalXor,
+    LogicalAnd,
+    LogicalOr,
+    /// 単項のマイナス (符号反転)。被演算子を 1 つだけ取る
+    Negate,
 }
 
-#[derive(Debug, PartialEq)]
+/// 数式の木構造 (抽象構文木)
+///
+/// 逆ポーランド記法と相互に変換でき、式の変形 (簡約・微分など) に使用する
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Expr {
+    Num(f64),
+    Var(String),
+    /// 関数名と引数
+    Call(String, Vec<Expr>),
+    /// 関数名と、キーワード引数の名前と値の組
+    KeywordCall(String, Vec<(String, Expr)>),
+    /// 二項演算子 (`Value::Plus` など) と左右の式
+    BinOp(Value, Box<Expr>, Box<Expr>),
+    /// 単項演算子と式 (`Value::Minus` による符号反転のみ)
+    UnOp(Value, Box<Expr>),
+}
+
+impl Expr {
+    /// 中間表現 (逆ポーランド記法) から木構造を組み立てる
+    pub fn from_values(values: &[Value]) -> Result<Expr, ParserError> {
+        let mut stack = vec![];
+
+        for value in values {
+            let expr = match value {
+                Value::Number(num) => Expr::Num(*num),
+                Value::Variable(v) => Expr::Var(v.to_string()),
+                Value::Str(_) => return Err(ParserError::new(ErrorCode::StringInExpressionTree)),
+                Value::Function(f, arity) => {
+                    if stack.len() < *arity {
+                        return Err(ParserError::new(ErrorCode::Syntax));
+                    }
+                    let args = stack.split_off(stack.len() - arity);
+                    Expr::Call(f.to_string(), args)
+                }
+                Value::KeywordFunction(f, names) => {
+                    if stack.len() < names.len() {
+                        return Err(ParserError::new(ErrorCode::Syntax));
+                    }
+                    let args = stack.split_off(stack.len() - names.len());
+                    Expr::KeywordCall(f.to_string(), names.iter().cloned().zip(args).collect())
+                }
+                Value::Negate => {
+                    let expr = stack.pop().ok_or(ParserError::new(ErrorCode::Syntax))?;
+                    Expr::UnOp(Value::Minus, Box::new(expr))
+                }
+                operator => {
+                    let (Some(rhs), Some(lhs)) = (stack.pop(), stack.pop()) else {
+                        return Err(ParserError::new(ErrorCode::Syntax));
+                    };
+                    Expr::BinOp(operator.clone(), Box::new(lhs), Box::new(rhs))
+                }
+            };
+            stack.push(expr);
+        }
+
+        match (stack.pop(), stack.is_empty()) {
+            (Some(expr), true) => Ok(expr),
+            _ => Err(ParserError::new(ErrorCode::Syntax)),
+        }
+    }
+
+    /// 木構造を中間表現 (逆ポーランド記法) に変換する
+    ///
+    /// 符号反転は `0 - x` として表す
+    pub fn to_values(&self) -> Vec<Value> {
+        let mut values = vec![];
+        self.push_values(&mut values);
+        values
+    }
+
+    fn push_values(&self, values: &mut Vec<Value>) {
+        match self {
+            Expr::Num(num) => values.push(Value::Number(*num)),
+            Expr::Var(v) => values.push(Value::Variable(v.to_string())),
+            Expr::Call(f, args) => {
+                for arg in args {
+                    arg.push_values(values);
+                }
+                values.push(Value::Function(f.to_string(), args.len()));
+            }
+            Expr::KeywordCall(f, args) => {
+                for (_, arg) in args {
+                    arg.push_values(values);
+                }
+                let names = args.iter().map(|(name, _)| name.to_string()).collect();
+                values.push(Value::KeywordFunction(f.to_string(), names));
+            }
+            Expr::BinOp(operator, lhs, rhs) => {
+                lhs.push_values(values);
+                rhs.push_values(values);
+                values.push(operator.clone());
+            }
+            Expr::UnOp(operator, expr) => {
+                values.push(Value::Number(0.0));
+                expr.push_values(values);
+                values.push(operator.clone());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ParserError {
-    pub msg: String,
+    pub code: ErrorCode,
+    /// エラーの原因となったトークンの位置 (トークン列中の番号)
+    pub at: Option<usize>,
 }
 
 impl ParserError {
-    fn new(msg: &str) -> ParserError {
-        ParserError {
-            msg: msg.to_string(),
-        }
+    fn new(code: ErrorCode) -> ParserError {
+        ParserError { code, at: None }
+    }
+
+    fn at(code: ErrorCode, at: usize) -> ParserError {
+        ParserError { code, at: Some(at) }
     }
+
+    /// 構文解析したトークン列 `tokens` から、エラーメッセージを生成する
+    pub fn message(&self, tokens: &[Token]) -> String {
+        let token = self.at.and_then(|at| tokens.get(at));
+        self.code
+            .message(token.map_or(Subject::None, Subject::Token))
+    }
+}
+
+/// 入力途中の数式を解析した結果
+#[derive(Debug, PartialEq)]
+pub enum PartialParse {
+    /// 完全な数式の中間表現
+    Complete(Vec<Value>),
+    /// 誤りはないが、数式が途中で終わっている (e.g. `Add(1, 2`, `x ? 1`)
+    Incomplete,
+}
+
+/// 解析中の括弧の情報
+struct Group {
+    /// 括弧を開いた時点の出力キューの長さ
+    start: usize,
+    /// 括弧内の各カンマを読み込んだ時点の出力キューの長さ
+    commas: Vec<usize>,
+    /// 括弧内で指定されたキーワード引数の名前
+    keywords: Vec<String>,
 }
 
 pub struct Parser {
@@ -46,233 +195,331 @@ impl Parser {
         Parser { tokens, index: 0 }
     }
 
+    /// 解析するトークン列 (`ParserError::message` に渡す)
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
     /// 字句解析によってトークンに変換された数式を、中間表現 (逆ポーランド記法) に変換する
     pub fn parse(&mut self) -> Result<Vec<Value>, ParserError> {
         let tokens = self.parse_expr()?;
         if tokens.is_empty() | self.peek().is_some() {
             // トークンが空 or 探索が終わっていない場合は解析エラーとする
-            return Err(ParserError::new("error: syntax error"));
+            return Err(ParserError::new(ErrorCode::Syntax));
+        }
+        if !Parser::reduces_to_single_value(&tokens) {
+            // 演算子のみの場合など、評価しても値が 1 つにならない場合は解析エラーとする
+            return Err(ParserError::new(ErrorCode::Syntax));
         }
 
         Ok(tokens)
     }
 
+    /// `parse` と同様に中間表現に変換し、後置記法の文字列 (`to_postfix` を参照) とともに返す
+    pub fn parse_with_postfix(&mut self) -> Result<(Vec<Value>, String), ParserError> {
+        let values = self.parse()?;
+        let postfix = to_postfix(&values);
+        Ok((values, postfix))
+    }
+
+    /// 字句解析によってトークンに変換された数式を、木構造 (抽象構文木) に変換する
+    ///
+    /// 中間表現 (逆ポーランド記法) に変換してから組み立てる
+    pub fn parse_ast(&mut self) -> Result<Expr, ParserError> {
+        self.parse().and_then(|values| Expr::from_values(&values))
+    }
+
+    /// 入力途中の数式を解析する
+    ///
+    /// 閉じられていない括弧や `:` のない `?` を補えば完全な数式になる場合は `PartialParse::Incomplete` を返し、
+    /// 補っても解析できない場合はエラーとする
+    pub fn parse_partial(&mut self) -> Result<PartialParse, ParserError> {
+        let completion = Parser::completion(&self.tokens);
+        if completion.is_empty() {
+            return self.parse().map(PartialParse::Complete);
+        }
+
+        let mut tokens = self.tokens.clone();
+        tokens.extend(completion);
+        Parser::new(tokens)
+            .parse()
+            .map(|_| PartialParse::Incomplete)
+    }
+
+    /// 閉じられていない `(`, `?` を閉じるために補うトークン (内側のものから順に閉じる)
+    fn completion(tokens: &[Token]) -> Vec<Token> {
+        let mut openers = vec![];
+        for token in tokens {
+            match token {
+                Token::LeftParenthesis | Token::Question => openers.push(token),
+                Token::RightParenthesis | Token::Colon => {
+                    openers.pop();
+                }
+                _ => {}
+            }
+        }
+
+        let mut completion = vec![];
+        for opener in openers.into_iter().rev() {
+            match opener {
+                Token::Question => completion.extend([Token::Colon, Token::Number(0.0)]),
+                _ => completion.push(Token::RightParenthesis),
+            }
+        }
+        completion
+    }
+
+    /// 中間表現を評価したときに、スタックが不足せずに値がちょうど 1 つ残るか
+    fn reduces_to_single_value(values: &[Value]) -> bool {
+        let mut depth: usize = 0;
+        for value in values {
+            let arity = match value {
+                Value::Number(_) | Value::Variable(_) | Value::Str(_) => 0,
+                Value::Function(_, arity) => *arity,
+                Value::KeywordFunction(_, names) => names.len(),
+                Value::Negate => 1,
+                _ => 2,
+            };
+            if depth < arity {
+                return false;
+            }
+            depth = depth - arity + 1;
+        }
+
+        depth == 1
+    }
+
     /// 操車場アルゴリズムによってトークンを逆ポーランド記法に変換する
     ///
     /// see: https://ja.wikipedia.org/wiki/%E6%93%8D%E8%BB%8A%E5%A0%B4%E3%82%A2%E3%83%AB%E3%82%B4%E3%83%AA%E3%82%BA%E3%83%A0
     pub fn parse_expr(&mut self) -> Result<Vec<Value>, ParserError> {
         let mut values = vec![];
-        let mut stack = LinkedList::new();
-
-        loop {
-            match self.peek() {
-                Some(token) => match token {
-                    Token::WhiteSpace => {
-                        self.next();
-                    }
-                    Token::Number(number) => {
-                        values.push(Value::Number(*number));
-                        self.next();
-                    }
-                    Token::Plus
-                    | Token::Minus
-                    | Token::Percent
-                    | Token::Equal
-                    | Token::NotEqual
-                    | Token::GreaterThan
-                    | Token::GreaterThanOrEqual
-                    | Token::LessThan
-                    | Token::LessThanOrEqual => loop {
-                        match stack.back() {
-                            Some(t) => match t {
-                                // o1の優先度がo2以上ではない
-                                Token::Plus
-                                | Token::Minus
-                                | Token::Percent
-                                | Token::Asterisk
-                                | Token::Slash
-                                | Token::Equal
-                                | Token::NotEqual
-                                | Token::GreaterThan
-                                | Token::GreaterThanOrEqual
-                                | Token::LessThan
-                                | Token::LessThanOrEqual => {
-                                    values.push(Parser::token_into_value(t, true)?);
-                                    stack.pop_back();
-                                }
-                                _ => {
-                                    stack.push_back(token.clone());
-                                    self.next();
-                                    break;
-                                }
-                            },
-                            None => {
-                                stack.push_back(token.clone());
-                                self.next();
+        // 演算子・括弧・関数名のトークンと、その位置
+        let mut stack: LinkedList<(Token, usize)> = LinkedList::new();
+        // 開いている括弧ごとの情報
+        let mut groups: Vec<Group> = vec![];
 
+        while let Some(token) = self.peek() {
+            let at = self.index;
+            match token {
+                Token::WhiteSpace => {
+                    self.next();
+                }
+                Token::Number(number) => {
+                    values.push(Value::Number(*number));
+                    self.next();
+                }
+                Token::Colon => {
+                    // 対応する '?' までの演算子を出力キューに移し、'?' を ':' に置き換える
+                    // (':' をスタックから取り出すときに If(cond, a, b) として出力する)
+                    loop {
+                        match stack.pop_back() {
+                            Some((Token::Question, _)) => {
+                                stack.push_back((Token::Colon, at));
                                 break;
                             }
+                            Some((t, i)) if Parser::precedence(&t).is_some() => {
+                                values.push(Parser::token_into_value(&t, i)?);
+                            }
+                            _ => return Err(ParserError::at(ErrorCode::ColonWithoutQuestion, at)),
+                        }
+                    }
+                    self.next();
+                }
+                Token::Negate => {
+                    // 前置の単項演算子は左側の被演算子を持たないため、スタックから何も取り出さずに積む
+                    stack.push_back((Token::Negate, at));
+                    self.next();
+                }
+                t if Parser::precedence(t).is_some() => {
+                    let token = t.clone();
+                    // スタックのトップにある演算子 o2 について、o1 より o2 の優先度が高い場合、
+                    // または優先度が等しく o1 が左結合性の場合は、o2 をスタックから取り出して出力キューに追加する
+                    while let Some((top, i)) = stack.back() {
+                        if !Parser::should_pop(&token, top) {
+                            break;
                         }
-                    },
-                    Token::Asterisk | Token::Slash => loop {
-                        match stack.back() {
-                            Some(t) => match t {
-                                Token::Asterisk | Token::Slash => {
-                                    // o1の優先度がo2より高くない && o1が左結合性のため、スタックのトップから演算子トークンを取り出して出力キューに追加する
-                                    values.push(Parser::token_into_value(t, true)?);
-                                    stack.pop_back();
+                        values.push(Parser::token_into_value(top, *i)?);
+                        stack.pop_back();
+                    }
+
+                    stack.push_back((token, at));
+                    self.next();
+                }
+                Token::LeftParenthesis => {
+                    groups.push(Group {
+                        start: values.len(),
+                        commas: vec![],
+                        keywords: vec![],
+                    });
+                    stack.push_back((token.clone(), at));
+                    self.next();
+                }
+                Token::RightParenthesis => {
+                    // スタックのトップにあるトークンが左括弧になるまで、スタックからポップした演算子を出力キューに追加する動作を繰り返す。
+                    // 左括弧をスタックからポップするが、出力には追加せずに捨てる。
+                    loop {
+                        match stack.pop_back() {
+                            Some((t, i)) => match t {
+                                t if Parser::precedence(&t).is_some() => {
+                                    values.push(Parser::token_into_value(&t, i)?);
                                 }
-                                _ => {
-                                    stack.push_back(token.clone());
+                                Token::LeftParenthesis => {
                                     self.next();
+                                    let group = groups.pop().ok_or(ParserError::at(
+                                        ErrorCode::UnmatchedParenthesis,
+                                        at,
+                                    ))?;
+
+                                    // スタックのトップにあるトークンが関数トークンなら、それをポップして出力キューに追加する。
+                                    // 括弧内に何も出力されていなければ引数なし、そうでなければカンマの数 + 1 を引数の数とする
+                                    if let Some((Token::Property(f), i)) = stack.back() {
+                                        let arity = if values.len() == group.start {
+                                            0
+                                        } else {
+                                            group.commas.len() + 1
+                                        };
+
+                                        if group.keywords.is_empty() {
+                                            values.push(Value::Function(f.to_string(), arity));
+                                        } else if group.keywords.len() == arity {
+                                            values.push(Value::KeywordFunction(
+                                                f.to_string(),
+                                                group.keywords,
+                                            ));
+                                        } else {
+                                            return Err(ParserError::at(
+                                                ErrorCode::MixedKeywordArguments,
+                                                *i,
+                                            ));
+                                        }
+                                        stack.pop_back();
+                                    } else if !group.keywords.is_empty() {
+                                        return Err(ParserError::at(
+                                            ErrorCode::KeywordArgumentOutsideCall,
+                                            at,
+                                        ));
+                                    }
 
                                     break;
                                 }
+                                _ => return Err(ParserError::at(ErrorCode::UnexpectedProperty, i)),
                             },
                             None => {
-                                stack.push_back(token.clone());
-                                self.next();
-
-                                break;
+                                return Err(ParserError::at(ErrorCode::UnmatchedParenthesis, at))
                             }
                         }
-                    },
-                    Token::LeftParenthesis => {
-                        stack.push_back(token.clone());
-                        self.next();
                     }
-                    Token::RightParenthesis => {
-                        // スタックのトップにあるトークンが左括弧になるまで、スタックからポップした演算子を出力キューに追加する動作を繰り返す。
-                        // 左括弧をスタックからポップするが、出力には追加せずに捨てる。
-                        loop {
-                            match stack.pop_back() {
-                                Some(t) => match t {
-                                    Token::Plus
-                                    | Token::Minus
-                                    | Token::Asterisk
-                                    | Token::Slash
-                                    | Token::Percent
-                                    | Token::Equal
-                                    | Token::NotEqual
-                                    | Token::GreaterThan
-                                    | Token::GreaterThanOrEqual
-                                    | Token::LessThan
-                                    | Token::LessThanOrEqual => {
-                                        values.push(Parser::token_into_value(&t, true)?);
-                                    }
-                                    Token::LeftParenthesis => {
-                                        self.next();
-
-                                        // スタックのトップにあるトークンが関数トークンなら、それをポップして出力キューに追加する。
-                                        if let Some(tt) = stack.back() {
-                                            if let Token::Property(_) = tt {
-                                                values.push(Parser::token_into_value(tt, true)?);
-                                                stack.pop_back();
-                                            }
-                                        }
+                }
+                Token::StringLiteral(string) => {
+                    values.push(Value::Str(string.to_string()));
+                    self.next();
+                }
+                Token::Property(_) => {
+                    let t = token.clone();
+                    self.next();
 
-                                        break;
-                                    }
-                                    _ => {
-                                        return Err(ParserError::new(&format!(
-                                            "error: unexpected property, token: {:?}",
-                                            t
-                                        )))
-                                    }
-                                },
-                                None => {
-                                    return Err(ParserError::new(
-                                        "error: parenthesis is not matchedd",
-                                    ))
-                                }
-                            }
+                    // 次が ( → 関数, = → キーワード引数の名前, それ以外 → 変数
+                    match self.peek() {
+                        Some(Token::LeftParenthesis) => {
+                            stack.push_back((t, at));
                         }
-                    }
-                    Token::Property(_) => {
-                        let t = token.clone();
-                        self.next();
-
-                        // 次が ( → 関数, それ以外 → 変数
-                        match self.peek() {
-                            Some(tt) => match tt {
-                                Token::LeftParenthesis => {
-                                    stack.push_back(t);
-                                }
-                                _ => values.push(Parser::token_into_value(&t, false)?),
-                            },
-                            None => values.push(Parser::token_into_value(&t, false)?),
+                        Some(Token::Assign) => {
+                            let (Some(group), Token::Property(name)) = (groups.last_mut(), t)
+                            else {
+                                return Err(ParserError::at(
+                                    ErrorCode::KeywordArgumentOutsideCall,
+                                    at,
+                                ));
+                            };
+                            group.keywords.push(name);
+                            self.next();
                         }
+                        _ => values.push(Parser::token_into_value(&t, at)?),
                     }
-                    Token::Comma => loop {
-                        // スタックのトップにあるトークンが左括弧となるまで、スタックから演算子をポップして出力キューに追加する動作を繰り返す。左括弧が出てこない場合、引数セパレータの位置がおかしいか、左右の括弧が不一致となっている（エラー）。
-                        match stack.back() {
-                            Some(t) => match t {
-                                Token::Plus
-                                | Token::Minus
-                                | Token::Asterisk
-                                | Token::Slash
-                                | Token::Percent
-                                | Token::Equal
-                                | Token::NotEqual
-                                | Token::GreaterThan
-                                | Token::GreaterThanOrEqual
-                                | Token::LessThan
-                                | Token::LessThanOrEqual => {
-                                    values.push(Parser::token_into_value(&t, true)?);
-                                    stack.pop_back();
-                                }
-                                Token::LeftParenthesis => {
-                                    self.next();
-                                    break;
-                                }
-                                _ => {
-                                    return Err(ParserError::new(&format!(
-                                        "error: unexpected property, token: {:?}",
-                                        t
-                                    )))
+                }
+                Token::Comma => loop {
+                    // スタックのトップにあるトークンが左括弧となるまで、スタックから演算子をポップして出力キューに追加する動作を繰り返す。左括弧が出てこない場合、引数セパレータの位置がおかしいか、左右の括弧が不一致となっている（エラー）。
+                    match stack.back() {
+                        Some((t, i)) => match t {
+                            t if Parser::precedence(t).is_some() => {
+                                values.push(Parser::token_into_value(t, *i)?);
+                                stack.pop_back();
+                            }
+                            Token::LeftParenthesis => {
+                                if let Some(group) = groups.last_mut() {
+                                    group.commas.push(values.len());
                                 }
-                            },
-                            None => {
-                                // ここに入っている模様
-                                return Err(ParserError::new("error: parenthesis is not matched"));
+                                self.next();
+                                break;
                             }
+                            _ => return Err(ParserError::at(ErrorCode::UnexpectedProperty, *i)),
+                        },
+                        None => {
+                            // ここに入っている模様
+                            return Err(ParserError::at(ErrorCode::UnmatchedParenthesis, at));
                         }
-                    },
+                    }
                 },
-                None => break,
+                _ => return Err(ParserError::at(ErrorCode::UnexpectedToken, at)),
             }
         }
 
-        loop {
-            match stack.pop_back() {
-                Some(t) => match t {
-                    Token::Plus
-                    | Token::Minus
-                    | Token::Percent
-                    | Token::Asterisk
-                    | Token::Slash
-                    | Token::Equal
-                    | Token::NotEqual
-                    | Token::GreaterThan
-                    | Token::GreaterThanOrEqual
-                    | Token::LessThan
-                    | Token::LessThanOrEqual => {
-                        values.push(Parser::token_into_value(&t, true)?);
-                    }
-                    _ => {
-                        return Err(ParserError::new(&format!(
-                            "error: unexpected token: {:?}",
-                            t
-                        )))
-                    }
-                },
-                None => break,
+        while let Some((t, i)) = stack.pop_back() {
+            match t {
+                t if Parser::precedence(&t).is_some() => {
+                    values.push(Parser::token_into_value(&t, i)?);
+                }
+                _ => return Err(ParserError::at(ErrorCode::UnexpectedToken, i)),
             }
         }
 
         Ok(values)
     }
 
+    /// 演算子の優先度 (大きいほど強く結合する)
+    ///
+    /// 符号反転は乗除算より強く、べき乗より弱く結合する (e.g. `-x ** 2` は `-(x ** 2)`)
+    ///
+    /// 演算子ではないトークンの場合は None を返す
+    fn precedence(token: &Token) -> Option<usize> {
+        match token {
+            Token::Question | Token::Colon => Some(0),
+            Token::Or => Some(1),
+            Token::CaretCaret => Some(2),
+            Token::And => Some(3),
+            Token::Plus
+            | Token::Minus
+            | Token::Percent
+            | Token::Equal
+            | Token::NotEqual
+            | Token::ApproxEqual
+            | Token::GreaterThan
+            | Token::GreaterThanOrEqual
+            | Token::LessThan
+            | Token::LessThanOrEqual => Some(4),
+            Token::Asterisk | Token::Slash | Token::Backslash => Some(5),
+            Token::Negate => Some(6),
+            Token::DoubleAsterisk | Token::Caret => Some(7),
+            _ => None,
+        }
+    }
+
+    fn is_right_associative(token: &Token) -> bool {
+        matches!(
+            token,
+            Token::DoubleAsterisk | Token::Caret | Token::Question | Token::Colon
+        )
+    }
+
+    /// 演算子 o1 を読み込んだときに、スタックのトップにある o2 を出力キューに移すべきか
+    fn should_pop(o1: &Token, o2: &Token) -> bool {
+        match (Parser::precedence(o1), Parser::precedence(o2)) {
+            (Some(p1), Some(p2)) => p2 > p1 || (p2 == p1 && !Parser::is_right_associative(o1)),
+            _ => false,
+        }
+    }
+
     fn peek(&self) -> Option<&Token> {
         self.tokens.get(self.index)
     }
@@ -282,32 +529,96 @@ impl Parser {
         self.tokens.get(self.index - 1)
     }
 
-    fn token_into_value(token: &Token, is_function: bool) -> Result<Value, ParserError> {
+    /// `at` はトークンの位置 (エラーの位置として使用する)
+    fn token_into_value(token: &Token, at: usize) -> Result<Value, ParserError> {
         match token {
             Token::Plus => Ok(Value::Plus),
             Token::Minus => Ok(Value::Minus),
             Token::Percent => Ok(Value::Percent),
             Token::Asterisk => Ok(Value::Asterisk),
             Token::Slash => Ok(Value::Slash),
+            Token::Backslash => Ok(Value::Backslash),
+            Token::DoubleAsterisk | Token::Caret => Ok(Value::Power),
             Token::Equal => Ok(Value::Equal),
             Token::NotEqual => Ok(Value::NotEqual),
+            Token::ApproxEqual => Ok(Value::ApproxEqual),
             Token::GreaterThan => Ok(Value::GreaterThan),
             Token::GreaterThanOrEqual => Ok(Value::GreaterThanOrEqual),
             Token::LessThan => Ok(Value::LessThan),
             Token::LessThanOrEqual => Ok(Value::LessThanOrEqual),
-            Token::Property(f) => Ok(if is_function {
-                Value::Function(f.to_string())
-            } else {
-                Value::Variable(f.to_string())
-            }),
-            _ => Err(ParserError::new(&format!(
-                "error: unexpected token, {:?}",
-                token
-            ))),
+            Token::CaretCaret => Ok(Value::LogicalXor),
+            Token::And => Ok(Value::LogicalAnd),
+            Token::Or => Ok(Value::LogicalOr),
+            Token::Negate => Ok(Value::Negate),
+            // cond ? a : b は If(cond, a, b) と同じ
+            Token::Colon => Ok(Value::Function("If".to_string(), 3)),
+            Token::Question => Err(ParserError::at(ErrorCode::QuestionWithoutColon, at)),
+            Token::Property(f) => Ok(Value::Variable(f.to_string())),
+            _ => Err(ParserError::at(ErrorCode::UnexpectedToken, at)),
         }
     }
 }
 
+/// 中間表現 (逆ポーランド記法) を、空白区切りの後置記法の文字列に変換する (解析結果の確認・学習用)
+///
+/// 演算子は記号、関数は関数名 (三項演算子は `If`)、符号反転は `neg` で表す
+///
+/// 例
+///
+/// - `(2 + 3) * 4` → `"2 3 + 4 *"`
+/// - `Add(x, -1)` → `"x 1 neg Add"`
+pub fn to_postfix(values: &[Value]) -> String {
+    values
+        .iter()
+        .map(|value| match value {
+            Value::Number(num) => num.to_string(),
+            Value::Function(name, _) | Value::KeywordFunction(name, _) => name.to_string(),
+            Value::Variable(name) => name.to_string(),
+            Value::Str(string) => format!("{:?}", string),
+            Value::Plus => "+".to_string(),
+            Value::Minus => "-".to_string(),
+            Value::Asterisk => "*".to_string(),
+            Value::Slash => "/".to_string(),
+            Value::Percent => "%".to_string(),
+            Value::Backslash => "\\".to_string(),
+            Value::Power => "**".to_string(),
+            Value::Equal => "==".to_string(),
+            Value::NotEqual => "!=".to_string(),
+            Value::ApproxEqual => "~=".to_string(),
+            Value::GreaterThan => ">".to_string(),
+            Value::GreaterThanOrEqual => ">=".to_string(),
+            Value::LessThan => "<".to_string(),
+            Value::LessThanOrEqual => "<=".to_string(),
+            Value::LogicalXor => "^^".to_string(),
+            Value::LogicalAnd => "&&".to_string(),
+            Value::LogicalOr => "||".to_string(),
+            Value::Negate => "neg".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 中間表現 (逆ポーランド記法) を評価する際に必要となるスタックの最大の深さを返す
+///
+/// 値・変数は 1 つ積み、関数は引数の数だけ取り出して 1 つ積み、符号反転は 1 つ取り出して 1 つ積み、二項演算子は 2 つ取り出して 1 つ積むものとして計算する
+pub fn max_stack_depth(values: &[Value]) -> usize {
+    let mut depth: usize = 0;
+    let mut max_depth = 0;
+
+    for value in values {
+        depth = match value {
+            Value::Number(_) | Value::Variable(_) | Value::Str(_) => depth + 1,
+            Value::Function(_, arity) => depth.saturating_sub(*arity) + 1,
+            Value::KeywordFunction(_, names) => depth.saturating_sub(names.len()) + 1,
+            Value::Negate => depth.max(1),
+            _ => depth.saturating_sub(2) + 1,
+        };
+        max_depth = max_depth.max(depth);
+    }
+
+    max_depth
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -346,10 +657,10 @@ mod tests {
                     Value::Number(4.0),
                     Value::Plus,
                     Value::Number(5.0),
-                    Value::Function("Add".to_string()),
+                    Value::Function("Add".to_string(), 2),
                     Value::Number(2.0),
                     Value::Number(3.0),
-                    Value::Function("Sub".to_string()),
+                    Value::Function("Sub".to_string(), 2),
                     Value::Plus,
                 ],
             ),
@@ -444,13 +755,186 @@ mod tests {
                     Value::Plus,
                 ],
             ),
+            (
+                // 1 + 7 \ 2 * 3
+                // → 1 7 2 \ 3 * +
+                vec![
+                    Token::Number(1.0),
+                    Token::Plus,
+                    Token::Number(7.0),
+                    Token::Backslash,
+                    Token::Number(2.0),
+                    Token::Asterisk,
+                    Token::Number(3.0),
+                ],
+                vec![
+                    Value::Number(1.0),
+                    Value::Number(7.0),
+                    Value::Number(2.0),
+                    Value::Backslash,
+                    Value::Number(3.0),
+                    Value::Asterisk,
+                    Value::Plus,
+                ],
+            ),
+            (
+                // 2 * 3 ** 2 ** 2
+                // → 2 3 2 2 ** ** *
+                vec![
+                    Token::Number(2.0),
+                    Token::Asterisk,
+                    Token::Number(3.0),
+                    Token::DoubleAsterisk,
+                    Token::Number(2.0),
+                    Token::DoubleAsterisk,
+                    Token::Number(2.0),
+                ],
+                vec![
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Number(2.0),
+                    Value::Number(2.0),
+                    Value::Power,
+                    Value::Power,
+                    Value::Asterisk,
+                ],
+            ),
+            (
+                // -x ** 2 * 3
+                // → x 2 ** neg 3 *
+                vec![
+                    Token::Negate,
+                    Token::Property("x".to_string()),
+                    Token::DoubleAsterisk,
+                    Token::Number(2.0),
+                    Token::Asterisk,
+                    Token::Number(3.0),
+                ],
+                vec![
+                    Value::Variable("x".to_string()),
+                    Value::Number(2.0),
+                    Value::Power,
+                    Value::Negate,
+                    Value::Number(3.0),
+                    Value::Asterisk,
+                ],
+            ),
+            (
+                // 3 - -(1 + 2)
+                // → 3 1 2 + neg -
+                vec![
+                    Token::Number(3.0),
+                    Token::Minus,
+                    Token::Negate,
+                    Token::LeftParenthesis,
+                    Token::Number(1.0),
+                    Token::Plus,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                ],
+                vec![
+                    Value::Number(3.0),
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    Value::Plus,
+                    Value::Negate,
+                    Value::Minus,
+                ],
+            ),
+            (
+                // 2 ^ 3 ^ 2 * 4
+                // → 2 3 2 ^ ^ 4 *
+                vec![
+                    Token::Number(2.0),
+                    Token::Caret,
+                    Token::Number(3.0),
+                    Token::Caret,
+                    Token::Number(2.0),
+                    Token::Asterisk,
+                    Token::Number(4.0),
+                ],
+                vec![
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Number(2.0),
+                    Value::Power,
+                    Value::Power,
+                    Value::Number(4.0),
+                    Value::Asterisk,
+                ],
+            ),
+            (
+                // 4 * 2 ^ 3 ** 2
+                // → 4 2 3 2 ** ^ *
+                vec![
+                    Token::Number(4.0),
+                    Token::Asterisk,
+                    Token::Number(2.0),
+                    Token::Caret,
+                    Token::Number(3.0),
+                    Token::DoubleAsterisk,
+                    Token::Number(2.0),
+                ],
+                vec![
+                    Value::Number(4.0),
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Number(2.0),
+                    Value::Power,
+                    Value::Power,
+                    Value::Asterisk,
+                ],
+            ),
         ];
 
-        success_data.map(|(input, expected)| {
+        for (input, expected) in success_data {
             assert_eq!(Parser::new(input).parse(), Ok(expected));
-        });
+        }
+
+        // Pay(rate=0.05, n=Add(1, 2)) → 0.05 1 2 Add Pay[rate, n]
+        assert_eq!(
+            Parser::new(vec![
+                Token::Property("Pay".to_string()),
+                Token::LeftParenthesis,
+                Token::Property("rate".to_string()),
+                Token::Assign,
+                Token::Number(0.05),
+                Token::Comma,
+                Token::Property("n".to_string()),
+                Token::Assign,
+                Token::Property("Add".to_string()),
+                Token::LeftParenthesis,
+                Token::Number(1.0),
+                Token::Comma,
+                Token::Number(2.0),
+                Token::RightParenthesis,
+                Token::RightParenthesis,
+            ])
+            .parse(),
+            Ok(vec![
+                Value::Number(0.05),
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Function("Add".to_string(), 2),
+                Value::KeywordFunction(
+                    "Pay".to_string(),
+                    vec!["rate".to_string(), "n".to_string()]
+                ),
+            ])
+        );
 
         let failure_data = [
+            // Pay(rate=0.05, 12)
+            vec![
+                Token::Property("Pay".to_string()),
+                Token::LeftParenthesis,
+                Token::Property("rate".to_string()),
+                Token::Assign,
+                Token::Number(0.05),
+                Token::Comma,
+                Token::Number(12.0),
+                Token::RightParenthesis,
+            ],
             // 1+2*(3*(4+5)+6)*(7+8+9
             vec![
                 Token::Number(1.0),
@@ -478,8 +962,557 @@ mod tests {
             ],
         ];
 
-        failure_data.map(|input| {
-            assert_eq!(Parser::new(input).parse().is_err(), true);
-        });
+        for input in failure_data {
+            assert!(Parser::new(input).parse().is_err());
+        }
+
+        // 1)
+        assert_eq!(
+            Parser::new(vec![Token::Number(1.0), Token::RightParenthesis]).parse(),
+            Err(ParserError::at(ErrorCode::UnmatchedParenthesis, 1))
+        );
+    }
+
+    #[test]
+    fn test_parse_ast() {
+        let var = |name: &str| Token::Property(name.to_string());
+        let success_data = [
+            // 1 + 2 * 3
+            (
+                vec![
+                    Token::Number(1.0),
+                    Token::Plus,
+                    Token::Number(2.0),
+                    Token::Asterisk,
+                    Token::Number(3.0),
+                ],
+                Expr::BinOp(
+                    Value::Plus,
+                    Box::new(Expr::Num(1.0)),
+                    Box::new(Expr::BinOp(
+                        Value::Asterisk,
+                        Box::new(Expr::Num(2.0)),
+                        Box::new(Expr::Num(3.0)),
+                    )),
+                ),
+            ),
+            // Add(a, b)
+            (
+                vec![
+                    var("Add"),
+                    Token::LeftParenthesis,
+                    var("a"),
+                    Token::Comma,
+                    var("b"),
+                    Token::RightParenthesis,
+                ],
+                Expr::Call(
+                    "Add".to_string(),
+                    vec![Expr::Var("a".to_string()), Expr::Var("b".to_string())],
+                ),
+            ),
+            // -(a - 1)
+            (
+                vec![
+                    Token::Negate,
+                    Token::LeftParenthesis,
+                    var("a"),
+                    Token::Minus,
+                    Token::Number(1.0),
+                    Token::RightParenthesis,
+                ],
+                Expr::UnOp(
+                    Value::Minus,
+                    Box::new(Expr::BinOp(
+                        Value::Minus,
+                        Box::new(Expr::Var("a".to_string())),
+                        Box::new(Expr::Num(1.0)),
+                    )),
+                ),
+            ),
+        ];
+        for (tokens, expected) in success_data {
+            assert_eq!(Parser::new(tokens).parse_ast(), Ok(expected));
+        }
+
+        let failure_data = [
+            vec![Token::Number(1.0), Token::Plus],
+            vec![Token::LeftParenthesis, Token::Number(1.0)],
+        ];
+        for tokens in failure_data {
+            assert!(
+                Parser::new(tokens.clone()).parse_ast().is_err(),
+                "{:?}",
+                tokens
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        let num = Token::Number;
+        let success_data = [
+            // 1 + 2
+            (
+                vec![num(1.0), Token::Plus, num(2.0)],
+                PartialParse::Complete(vec![Value::Number(1.0), Value::Number(2.0), Value::Plus]),
+            ),
+            // (1 + 2
+            (
+                vec![Token::LeftParenthesis, num(1.0), Token::Plus, num(2.0)],
+                PartialParse::Incomplete,
+            ),
+            // Add(1
+            (
+                vec![
+                    Token::Property("Add".to_string()),
+                    Token::LeftParenthesis,
+                    num(1.0),
+                ],
+                PartialParse::Incomplete,
+            ),
+            // 1 ? (2
+            (
+                vec![num(1.0), Token::Question, Token::LeftParenthesis, num(2.0)],
+                PartialParse::Incomplete,
+            ),
+        ];
+        for (tokens, expected) in success_data {
+            assert_eq!(Parser::new(tokens).parse_partial(), Ok(expected));
+        }
+
+        let failure_data = [
+            // 1 + * 2
+            vec![num(1.0), Token::Plus, Token::Asterisk, num(2.0)],
+            // (1 + 2))
+            vec![
+                Token::LeftParenthesis,
+                num(1.0),
+                Token::Plus,
+                num(2.0),
+                Token::RightParenthesis,
+                Token::RightParenthesis,
+            ],
+            // (1 2
+            vec![Token::LeftParenthesis, num(1.0), num(2.0)],
+        ];
+        for tokens in failure_data {
+            assert!(
+                Parser::new(tokens.clone()).parse_partial().is_err(),
+                "{:?}",
+                tokens
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_token() {
+        let success_data = [
+            (Token::Number(4.0), Value::Number(4.0)),
+            (Token::Number(-4.0), Value::Number(-4.0)),
+            (
+                Token::Property("x".to_string()),
+                Value::Variable("x".to_string()),
+            ),
+            (
+                Token::StringLiteral("a".to_string()),
+                Value::Str("a".to_string()),
+            ),
+        ];
+        for (token, expected) in success_data {
+            assert_eq!(Parser::new(vec![token]).parse(), Ok(vec![expected]));
+        }
+
+        let failure_data = [
+            vec![],
+            vec![Token::Plus],
+            vec![Token::LeftParenthesis],
+            vec![Token::Comma],
+            vec![Token::Question],
+        ];
+        for input in failure_data {
+            assert!(Parser::new(input.clone()).parse().is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_ternary() {
+        let var = |name: &str| Token::Property(name.to_string());
+        let if_value = Value::Function("If".to_string(), 3);
+        let success_data = [
+            (
+                // x > 0 ? 1 : -1
+                // → x 0 > 1 -1 If
+                vec![
+                    var("x"),
+                    Token::GreaterThan,
+                    Token::Number(0.0),
+                    Token::Question,
+                    Token::Number(1.0),
+                    Token::Colon,
+                    Token::Number(-1.0),
+                ],
+                vec![
+                    Value::Variable("x".to_string()),
+                    Value::Number(0.0),
+                    Value::GreaterThan,
+                    Value::Number(1.0),
+                    Value::Number(-1.0),
+                    if_value.clone(),
+                ],
+            ),
+            (
+                // a > b ? a : b > c ? b : c
+                // → a b > a b c > b c If If
+                vec![
+                    var("a"),
+                    Token::GreaterThan,
+                    var("b"),
+                    Token::Question,
+                    var("a"),
+                    Token::Colon,
+                    var("b"),
+                    Token::GreaterThan,
+                    var("c"),
+                    Token::Question,
+                    var("b"),
+                    Token::Colon,
+                    var("c"),
+                ],
+                vec![
+                    Value::Variable("a".to_string()),
+                    Value::Variable("b".to_string()),
+                    Value::GreaterThan,
+                    Value::Variable("a".to_string()),
+                    Value::Variable("b".to_string()),
+                    Value::Variable("c".to_string()),
+                    Value::GreaterThan,
+                    Value::Variable("b".to_string()),
+                    Value::Variable("c".to_string()),
+                    if_value.clone(),
+                    if_value.clone(),
+                ],
+            ),
+            (
+                // a ? b ? 1 : 2 : 3
+                // → a b 1 2 If 3 If
+                vec![
+                    var("a"),
+                    Token::Question,
+                    var("b"),
+                    Token::Question,
+                    Token::Number(1.0),
+                    Token::Colon,
+                    Token::Number(2.0),
+                    Token::Colon,
+                    Token::Number(3.0),
+                ],
+                vec![
+                    Value::Variable("a".to_string()),
+                    Value::Variable("b".to_string()),
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    if_value.clone(),
+                    Value::Number(3.0),
+                    if_value.clone(),
+                ],
+            ),
+            (
+                // (a ? 1 : 2) * 3
+                // → a 1 2 If 3 *
+                vec![
+                    Token::LeftParenthesis,
+                    var("a"),
+                    Token::Question,
+                    Token::Number(1.0),
+                    Token::Colon,
+                    Token::Number(2.0),
+                    Token::RightParenthesis,
+                    Token::Asterisk,
+                    Token::Number(3.0),
+                ],
+                vec![
+                    Value::Variable("a".to_string()),
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    if_value.clone(),
+                    Value::Number(3.0),
+                    Value::Asterisk,
+                ],
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(Parser::new(input).parse(), Ok(expected));
+        }
+
+        let failure_data = [
+            // 1 ? 2
+            vec![Token::Number(1.0), Token::Question, Token::Number(2.0)],
+            // 1 : 2
+            vec![Token::Number(1.0), Token::Colon, Token::Number(2.0)],
+            // 1 ? 2 : 3 : 4
+            vec![
+                Token::Number(1.0),
+                Token::Question,
+                Token::Number(2.0),
+                Token::Colon,
+                Token::Number(3.0),
+                Token::Colon,
+                Token::Number(4.0),
+            ],
+            // (1 ? 2) : 3
+            vec![
+                Token::LeftParenthesis,
+                Token::Number(1.0),
+                Token::Question,
+                Token::Number(2.0),
+                Token::RightParenthesis,
+                Token::Colon,
+                Token::Number(3.0),
+            ],
+            // 1 ? (2 : 3)
+            vec![
+                Token::Number(1.0),
+                Token::Question,
+                Token::LeftParenthesis,
+                Token::Number(2.0),
+                Token::Colon,
+                Token::Number(3.0),
+                Token::RightParenthesis,
+            ],
+        ];
+        for input in failure_data {
+            assert!(Parser::new(input.clone()).parse().is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_string() {
+        // "a" + "b" → "a" "b" +
+        assert_eq!(
+            Parser::new(vec![
+                Token::StringLiteral("a".to_string()),
+                Token::Plus,
+                Token::StringLiteral("b".to_string()),
+            ])
+            .parse(),
+            Ok(vec![
+                Value::Str("a".to_string()),
+                Value::Str("b".to_string()),
+                Value::Plus,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_map_sum() {
+        // MapSum("Square", 1, 2 + 3) → "Square" 1 2 3 + MapSum (展開せず、通常の関数として呼び出す)
+        let tokens = vec![
+            Token::Property("MapSum".to_string()),
+            Token::LeftParenthesis,
+            Token::StringLiteral("Square".to_string()),
+            Token::Comma,
+            Token::Number(1.0),
+            Token::Comma,
+            Token::Number(2.0),
+            Token::Plus,
+            Token::Number(3.0),
+            Token::RightParenthesis,
+        ];
+        assert_eq!(
+            Parser::new(tokens).parse(),
+            Ok(vec![
+                Value::Str("Square".to_string()),
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Number(3.0),
+                Value::Plus,
+                Value::Function("MapSum".to_string(), 3),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_postfix() {
+        // (2 + 3) * 4
+        let tokens = vec![
+            Token::LeftParenthesis,
+            Token::Number(2.0),
+            Token::Plus,
+            Token::Number(3.0),
+            Token::RightParenthesis,
+            Token::Asterisk,
+            Token::Number(4.0),
+        ];
+        let (values, postfix) = Parser::new(tokens).parse_with_postfix().unwrap();
+        assert_eq!(
+            values,
+            vec![
+                Value::Number(2.0),
+                Value::Number(3.0),
+                Value::Plus,
+                Value::Number(4.0),
+                Value::Asterisk,
+            ]
+        );
+        assert_eq!(postfix, "2 3 + 4 *");
+
+        let data = [
+            // Add(x, -1.5) ** 2
+            (
+                vec![
+                    Value::Variable("x".to_string()),
+                    Value::Number(1.5),
+                    Value::Negate,
+                    Value::Function("Add".to_string(), 2),
+                    Value::Number(2.0),
+                    Value::Power,
+                ],
+                "x 1.5 neg Add 2 **",
+            ),
+            // "a" == s && Now() >= 0
+            (
+                vec![
+                    Value::Str("a".to_string()),
+                    Value::Variable("s".to_string()),
+                    Value::Equal,
+                    Value::Function("Now".to_string(), 0),
+                    Value::Number(0.0),
+                    Value::GreaterThanOrEqual,
+                    Value::LogicalAnd,
+                ],
+                "\"a\" s == Now 0 >= &&",
+            ),
+        ];
+        for (values, expected) in data {
+            assert_eq!(to_postfix(&values), expected);
+        }
+    }
+
+    #[test]
+    fn test_max_stack_depth() {
+        let success_data = [
+            // 1 + 2 → 1 2 +
+            (vec![Value::Number(1.0), Value::Number(2.0), Value::Plus], 2),
+            // 1 + 2 + 3 → 1 2 + 3 +
+            (
+                vec![
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    Value::Plus,
+                    Value::Number(3.0),
+                    Value::Plus,
+                ],
+                2,
+            ),
+            // 1 + (2 + (3 + 4)) → 1 2 3 4 + + +
+            (
+                vec![
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Number(4.0),
+                    Value::Plus,
+                    Value::Plus,
+                    Value::Plus,
+                ],
+                4,
+            ),
+            // hoge + Add(1, Now()) → hoge 1 Now Add +
+            (
+                vec![
+                    Value::Variable("hoge".to_string()),
+                    Value::Number(1.0),
+                    Value::Function("Now".to_string(), 0),
+                    Value::Function("Add".to_string(), 2),
+                    Value::Plus,
+                ],
+                3,
+            ),
+            (vec![], 0),
+        ];
+
+        for (input, expected) in success_data {
+            assert_eq!(max_stack_depth(&input), expected);
+        }
+    }
+
+    #[test]
+    fn test_expr() {
+        let success_data = [
+            (
+                // 1 + 2 * 3 → 1 2 3 * +
+                vec![
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Asterisk,
+                    Value::Plus,
+                ],
+                Expr::BinOp(
+                    Value::Plus,
+                    Box::new(Expr::Num(1.0)),
+                    Box::new(Expr::BinOp(
+                        Value::Asterisk,
+                        Box::new(Expr::Num(2.0)),
+                        Box::new(Expr::Num(3.0)),
+                    )),
+                ),
+            ),
+            (
+                // Add(hoge, Now()) - 1 → hoge Now Add 1 -
+                vec![
+                    Value::Variable("hoge".to_string()),
+                    Value::Function("Now".to_string(), 0),
+                    Value::Function("Add".to_string(), 2),
+                    Value::Number(1.0),
+                    Value::Minus,
+                ],
+                Expr::BinOp(
+                    Value::Minus,
+                    Box::new(Expr::Call(
+                        "Add".to_string(),
+                        vec![
+                            Expr::Var("hoge".to_string()),
+                            Expr::Call("Now".to_string(), vec![]),
+                        ],
+                    )),
+                    Box::new(Expr::Num(1.0)),
+                ),
+            ),
+        ];
+
+        for (values, expected) in success_data {
+            assert_eq!(Expr::from_values(&values), Ok(expected.clone()));
+            assert_eq!(expected.to_values(), values);
+        }
+
+        assert_eq!(
+            Expr::UnOp(Value::Minus, Box::new(Expr::Var("x".to_string()))).to_values(),
+            vec![
+                Value::Number(0.0),
+                Value::Variable("x".to_string()),
+                Value::Minus
+            ]
+        );
+
+        assert_eq!(
+            Expr::from_values(&[Value::Variable("x".to_string()), Value::Negate]),
+            Ok(Expr::UnOp(
+                Value::Minus,
+                Box::new(Expr::Var("x".to_string()))
+            ))
+        );
+
+        let failure_data = [
+            vec![],
+            vec![Value::Negate],
+            vec![Value::Number(1.0), Value::Plus],
+            vec![Value::Number(1.0), Value::Number(2.0)],
+            vec![Value::Number(1.0), Value::Function("Add".to_string(), 2)],
+        ];
+        for values in failure_data {
+            assert!(Expr::from_values(&values).is_err());
+        }
     }
 }
@@ -1,40 +1,455 @@
-use std::collections::LinkedList;
+use std::cell::RefCell;
+use std::collections::{HashMap, LinkedList};
+use std::ops::Range;
+use std::rc::Rc;
 
-use crate::parser::Value;
+use crate::error::{ErrorCode, Subject};
+use crate::lexer::{Lexer, LexerOptions};
+use crate::parser::{Parser, Value};
+
+/// 引数 (参照した時点で評価される) から、結果とする引数の位置を選ぶ関数
+type Selector = Box<dyn Fn(&mut ConditionalArgs) -> Result<usize, ProcessorError>>;
+
+/// 引数名と値の組を受け取る関数
+type KeywordHandler = Box<dyn Fn(&HashMap<String, f64>) -> f64>;
+
+/// 数値・文字列の引数を受け取り、数値・文字列を返す関数
+type TypedHandler = Box<dyn Fn(Vec<Evaluated>) -> Result<Evaluated, String>>;
+
+/// 条件分岐の関数の呼び出し (関数の位置と、各引数が占める範囲)
+type ConditionalCall = (usize, Vec<Range<usize>>);
+
+/// 他の変数の値から、変数の値を計算する関数
+type ComputedHandler = Rc<dyn Fn(&dyn VariableResolver) -> Option<f64>>;
+
+enum Handler {
+    Infallible(Box<dyn Fn(Vec<f64>) -> f64>),
+    Fallible(Box<dyn Fn(Vec<f64>) -> Result<f64, ProcessorError>>),
+    /// 引数のうち 1 つを選んでそのまま返す
+    ///
+    /// 引数は参照された時点で評価され、参照されなかった引数は評価されない
+    Conditional(Selector),
+    /// 引数名と値の組を受け取る
+    Keyword(KeywordHandler),
+    /// 文字列の引数を受け取る
+    Typed(TypedHandler),
+    /// 文字列として渡された数式を評価する (`Processor` が直接評価する)
+    Eval,
+    /// 関数名で指定した関数を各引数に適用した結果の合計を求める (`Processor` が直接評価する)
+    MapSum,
+}
+
+/// `Eval` を入れ子で呼び出せる深さの既定値
+pub(crate) const DEFAULT_EVAL_BUDGET: usize = 16;
+
+/// `~=` の許容誤差の既定値
+pub(crate) const DEFAULT_APPROX_TOLERANCE: f64 = 1e-9;
+
+/// 計算される変数を入れ子で参照できる深さ
+const COMPUTED_VARIABLE_DEPTH: usize = 16;
 
 pub struct Function {
     name: String,
-    args_count: usize,
-    handler: fn(Vec<f64>) -> f64,
+    /// 引数の数 (None の場合は可変長引数)
+    args_count: Option<usize>,
+    /// キーワード引数を受け取る関数の引数名 (それ以外の関数の場合は空)
+    params: Vec<String>,
+    handler: Handler,
+    pure: bool,
+    /// 条件分岐の関数で、選んだ引数の位置から分岐の番号を求める関数 (None の場合は引数の位置をそのまま使う)
+    branch_index: Option<Box<dyn Fn(usize) -> usize>>,
 }
 
 impl Function {
-    pub fn new(name: &str, args_count: usize, handler: fn(Vec<f64>) -> f64) -> Function {
+    pub fn new(
+        name: &str,
+        args_count: usize,
+        handler: impl Fn(Vec<f64>) -> f64 + 'static,
+    ) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count: Some(args_count),
+            params: vec![],
+            handler: Handler::Infallible(Box::new(handler)),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 失敗する可能性のある関数を生成する
+    ///
+    /// `handler` が `Err` を返した場合、そのメッセージで評価エラーとなる
+    pub fn new_fallible(
+        name: &str,
+        args_count: usize,
+        handler: impl Fn(Vec<f64>) -> Result<f64, String> + 'static,
+    ) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count: Some(args_count),
+            params: vec![],
+            handler: Handler::Fallible(Box::new(move |args| {
+                handler(args).map_err(ProcessorError::custom)
+            })),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 任意の数の引数を受け取る関数を生成する
+    pub fn variadic(name: &str, handler: impl Fn(Vec<f64>) -> f64 + 'static) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count: None,
+            params: vec![],
+            handler: Handler::Infallible(Box::new(handler)),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 任意の数の引数を受け取る、失敗する可能性のある関数を生成する
+    ///
+    /// `handler` が `Err` を返した場合、そのメッセージで評価エラーとなる
+    pub fn variadic_fallible(
+        name: &str,
+        handler: impl Fn(Vec<f64>) -> Result<f64, String> + 'static,
+    ) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count: None,
+            params: vec![],
+            handler: Handler::Fallible(Box::new(move |args| {
+                handler(args).map_err(ProcessorError::custom)
+            })),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 失敗した場合に `ErrorCode` を返す関数を生成する (予約関数で使用する)
+    ///
+    /// `args_count` が None の場合は可変長引数となる
+    pub(crate) fn fallible_with_code(
+        name: &str,
+        args_count: Option<usize>,
+        handler: impl Fn(Vec<f64>) -> Result<f64, ErrorCode> + 'static,
+    ) -> Function {
         Function {
             name: name.to_string(),
             args_count,
-            handler,
+            params: vec![],
+            handler: Handler::Fallible(Box::new(move |args| {
+                handler(args).map_err(ProcessorError::new)
+            })),
+            pure: true,
+            branch_index: None,
         }
     }
 
-    fn calc(&self, args: Vec<f64>) -> Result<f64, ProcessorError> {
+    /// 引数のうち `selector` が返した位置の値を結果とする関数を生成する
+    ///
+    /// 引数は `selector` が `ConditionalArgs::get` で参照した時点で評価され、参照も選択もされなかった引数は評価されない。
+    /// そのため、`If` のように使われない分岐は実行されず、そのエラーで失敗することもない。
+    /// `args_count` が None の場合は可変長引数となる
+    pub fn conditional(
+        name: &str,
+        args_count: Option<usize>,
+        selector: impl Fn(&mut ConditionalArgs) -> Result<usize, String> + 'static,
+    ) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count,
+            params: vec![],
+            handler: Handler::Conditional(Box::new(move |args| {
+                selector(args).map_err(ProcessorError::custom)
+            })),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 選べない場合に `ErrorCode` を返す、条件分岐の関数を生成する (予約関数で使用する)
+    pub(crate) fn conditional_with_code(
+        name: &str,
+        args_count: Option<usize>,
+        selector: impl Fn(&mut ConditionalArgs) -> Result<usize, ErrorCode> + 'static,
+    ) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count,
+            params: vec![],
+            handler: Handler::Conditional(Box::new(move |args| {
+                selector(args).map_err(ProcessorError::new)
+            })),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 文字列の引数を受け取る関数を生成する (e.g. `Lookup("region", 3)`)
+    ///
+    /// `handler` には評価済みの引数がそのまま渡され、数値・文字列のどちらを返してもよい。
+    /// `handler` が `Err` を返した場合、そのメッセージで評価エラーとなる。`args_count` が None の場合は可変長引数となる
+    pub fn typed(
+        name: &str,
+        args_count: Option<usize>,
+        handler: impl Fn(Vec<Evaluated>) -> Result<Evaluated, String> + 'static,
+    ) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count,
+            params: vec![],
+            handler: Handler::Typed(Box::new(handler)),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 文字列として渡された数式を、呼び出し元と同じ関数・変数・定数を用いて評価する関数 `Eval(formula)` を生成する
+    pub(crate) fn eval() -> Function {
+        Function {
+            name: "Eval".to_string(),
+            args_count: Some(1),
+            params: vec![],
+            handler: Handler::Eval,
+            pure: false,
+            branch_index: None,
+        }
+    }
+
+    /// 文字列で指定した引数 1 つの関数を各引数に適用し、結果の合計を返す関数 `MapSum("F", a1, a2, ...)` を生成する
+    ///
+    /// 適用する関数は実行するまで分からないため、純粋な関数としては扱わない
+    pub(crate) fn map_sum() -> Function {
+        Function {
+            name: "MapSum".to_string(),
+            args_count: None,
+            params: vec![],
+            handler: Handler::MapSum,
+            pure: false,
+            branch_index: None,
+        }
+    }
+
+    /// キーワード引数 (`Payment(rate=0.05, periods=12)`) を受け取る関数を生成する
+    ///
+    /// `handler` には引数名と値の組が渡される。キーワード引数は任意の順番で指定でき、
+    /// キーワードを省略した場合は `params` の順番で引数名に対応させる
+    pub fn keyword(
+        name: &str,
+        params: &[&str],
+        handler: impl Fn(&HashMap<String, f64>) -> f64 + 'static,
+    ) -> Function {
+        Function {
+            name: name.to_string(),
+            args_count: Some(params.len()),
+            params: params.iter().map(|p| p.to_string()).collect(),
+            handler: Handler::Keyword(Box::new(handler)),
+            pure: true,
+            branch_index: None,
+        }
+    }
+
+    /// 同じ引数でも呼び出すたびに結果が変わりうる関数 (`Now` など) であることを示す
+    pub fn impure(mut self) -> Function {
+        self.pure = false;
+        self
+    }
+
+    /// 条件分岐の関数で、選んだ引数の位置から分岐の番号を求める関数を指定する
+    ///
+    /// 分岐の番号は `Context::evaluate_with_branch_trace` で報告される (e.g. `If` の場合は then が 0、else が 1)
+    pub fn with_branch_index(
+        mut self,
+        branch_index: impl Fn(usize) -> usize + 'static,
+    ) -> Function {
+        self.branch_index = Some(Box::new(branch_index));
+        self
+    }
+
+    pub fn is_pure(&self) -> bool {
+        self.pure
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check_args_count(&self, count: usize) -> Result<(), ErrorCode> {
         // 引数があっていなければエラーとする
-        if args.len() != self.args_count {
-            Err(ProcessorError::new(&format!(
-                "error: args count of {:?} expects {:?}, but provide {:?}",
-                self.name,
-                self.args_count,
-                args.len()
-            )))
+        match self.args_count {
+            Some(expected) if expected != count => Err(ErrorCode::ArgsCount {
+                expected,
+                provided: count,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// キーワード引数を、引数名の定義順に並べ替える
+    fn order_keyword_args<T>(&self, names: &[String], args: Vec<T>) -> Result<Vec<T>, ErrorCode> {
+        if self.params.is_empty() {
+            return Err(ErrorCode::KeywordArgumentsNotAccepted);
+        }
+        self.check_args_count(names.len())?;
+
+        let mut ordered: Vec<Option<T>> = self.params.iter().map(|_| None).collect();
+        for (index, (name, arg)) in names.iter().zip(args).enumerate() {
+            match self.params.iter().position(|p| p == name) {
+                Some(i) if ordered[i].is_none() => ordered[i] = Some(arg),
+                Some(_) => return Err(ErrorCode::DuplicateKeywordArgument(index)),
+                None => return Err(ErrorCode::UnknownKeywordArgument(index)),
+            }
+        }
+
+        // 引数の数が一致し重複もないため、すべての引数名に値が対応している
+        Ok(ordered.into_iter().flatten().collect())
+    }
+
+    /// 条件分岐の関数で、選んだ引数の位置に対応する分岐の番号
+    fn branch(&self, index: usize) -> usize {
+        match &self.branch_index {
+            Some(branch_index) => branch_index(index),
+            None => index,
+        }
+    }
+
+    /// 評価済みの引数 (評価に失敗したものを含む) を元に関数を実行する
+    ///
+    /// 条件分岐の関数の場合は、結果とともに選んだ分岐の番号を返す (評価に失敗した場合は None)
+    /// 文字列の引数を受け取る関数以外では、文字列の引数はエラーとなる
+    fn calc(
+        &self,
+        args: Vec<Result<Evaluated, ProcessorError>>,
+    ) -> (Result<Evaluated, ProcessorError>, Option<usize>) {
+        let numbers = |args: Vec<Result<Evaluated, ProcessorError>>| {
+            args.into_iter()
+                .map(|a| a.and_then(Evaluated::into_number))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        match &self.handler {
+            Handler::Infallible(handler) => {
+                (numbers(args).map(handler).map(Evaluated::Number), None)
+            }
+            Handler::Fallible(handler) => {
+                (numbers(args).and_then(handler).map(Evaluated::Number), None)
+            }
+            // 評価済みの引数 (配列変数を展開した場合など) から選ぶ
+            Handler::Conditional(selector) => {
+                let values: Vec<Option<f64>> = args.iter().map(ConditionalArgs::number).collect();
+                let mut evaluate = |index: usize| values.get(index).copied().flatten();
+                let index = match selector(&mut ConditionalArgs::new(args.len(), &mut evaluate)) {
+                    Ok(index) => index,
+                    Err(e) => return (Err(e), None),
+                };
+
+                let result = args.into_iter().nth(index).unwrap_or_else(|| {
+                    Err(ProcessorError::new(ErrorCode::OutOfRangeArgument(index)))
+                });
+                let branch = result.is_ok().then(|| self.branch(index));
+                (result, branch)
+            }
+            Handler::Keyword(handler) => (
+                numbers(args)
+                    .map(|values| handler(&self.params.iter().cloned().zip(values).collect()))
+                    .map(Evaluated::Number),
+                None,
+            ),
+            // Processor::eval, Processor::map_sum で評価されるため、ここで実行されることはない
+            Handler::Eval | Handler::MapSum => (
+                Err(ProcessorError::new(ErrorCode::NotEvaluatedByProcessor)),
+                None,
+            ),
+            Handler::Typed(handler) => (
+                args.into_iter()
+                    .collect::<Result<Vec<_>, _>>()
+                    .and_then(|args| handler(args).map_err(ProcessorError::custom)),
+                None,
+            ),
+        }
+    }
+}
+
+/// 条件分岐の関数 (`Function::conditional`) に渡される引数
+///
+/// 引数は `get` で参照された時点で評価されるため、参照されなかった引数 (選ばれなかった分岐) は評価されない
+pub struct ConditionalArgs<'f> {
+    len: usize,
+    evaluate: &'f mut dyn FnMut(usize) -> Option<f64>,
+}
+
+impl<'f> ConditionalArgs<'f> {
+    fn new(len: usize, evaluate: &'f mut dyn FnMut(usize) -> Option<f64>) -> ConditionalArgs<'f> {
+        ConditionalArgs { len, evaluate }
+    }
+
+    /// 引数の数
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// `index` 番目の引数を評価して返す (評価に失敗した場合・数値でない場合・範囲外の場合は None)
+    ///
+    /// 同じ引数を複数回参照しても、評価は一度だけ行われる
+    pub fn get(&mut self, index: usize) -> Option<f64> {
+        if index < self.len {
+            (self.evaluate)(index)
         } else {
-            Ok((self.handler)(args))
+            None
+        }
+    }
+
+    /// 評価済みの引数を、selector に渡す値に変換する (文字列は評価に失敗した引数として扱い、選ばれた場合はそのまま返す)
+    fn number(arg: &Result<Evaluated, ProcessorError>) -> Option<f64> {
+        match arg {
+            Ok(Evaluated::Number(num)) => Some(*num),
+            Ok(Evaluated::Bool(b)) => Some(if *b { 1.0 } else { 0.0 }),
+            _ => None,
         }
     }
 }
 
+/// 変数名から値を解決するもの
+///
+/// `Processor::with_resolver` に指定すると、変数の参照ごとに呼び出される
+pub trait VariableResolver {
+    fn resolve(&self, name: &str) -> Option<f64>;
+}
+
+impl VariableResolver for HashMap<String, f64> {
+    fn resolve(&self, name: &str) -> Option<f64> {
+        self.get(name).copied()
+    }
+}
+
+impl VariableResolver for Vec<Variable> {
+    /// 同名の変数がある場合は最初のものを使用する
+    fn resolve(&self, name: &str) -> Option<f64> {
+        self.iter().find(|v| v.name == name).map(|v| v.value)
+    }
+}
+
+/// 変数の宣言された型
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum VariableType {
+    #[default]
+    Number,
+    /// 真理値 (評価中は `Evaluated::Bool` として扱い、数値としては真を 1、偽を 0 とする)
+    Bool,
+}
+
+#[derive(Clone)]
 pub struct Variable {
     name: String,
     value: f64,
+    kind: VariableType,
+    /// 参照されるたびに値を計算する場合の計算方法
+    computed: Option<ComputedHandler>,
 }
 
 impl Variable {
@@ -42,141 +457,1278 @@ impl Variable {
         Variable {
             name: name.to_string(),
             value,
+            kind: VariableType::Number,
+            computed: None,
+        }
+    }
+
+    /// 数値型の変数を生成する (`Variable::new` と同じ)
+    pub fn new_number(name: &str, value: f64) -> Variable {
+        Variable::new(name, value)
+    }
+
+    /// 真理値型の変数を生成する
+    pub fn new_bool(name: &str, value: bool) -> Variable {
+        Variable {
+            name: name.to_string(),
+            value: if value { 1.0 } else { 0.0 },
+            kind: VariableType::Bool,
+            computed: None,
+        }
+    }
+
+    /// 参照されるたびに `compute` で値を計算する (数値型の) 変数を生成する
+    ///
+    /// `compute` には他の変数 (定数・計算される変数を含む) の値を解決するものが渡される。
+    /// None を返した場合 (参照した変数が存在しない場合など) は、評価がエラーとなる。
+    /// 計算される変数どうしが循環して参照している場合は、一定の深さでエラーとなる
+    ///
+    /// `Context` に登録した変数、`parse_formula`・`Environment` に渡した変数で使用できる
+    pub fn new_computed(
+        name: &str,
+        compute: impl Fn(&dyn VariableResolver) -> Option<f64> + 'static,
+    ) -> Variable {
+        Variable {
+            name: name.to_string(),
+            value: f64::NAN,
+            kind: VariableType::Number,
+            computed: Some(Rc::new(compute)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 変数の値 (計算される変数の場合は NaN)
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// 参照されるたびに値を計算する変数か
+    pub fn is_computed(&self) -> bool {
+        self.computed.is_some()
+    }
+
+    pub fn kind(&self) -> VariableType {
+        self.kind
+    }
+
+    fn evaluated(&self) -> Evaluated {
+        match self.kind {
+            VariableType::Number => Evaluated::Number(self.value),
+            VariableType::Bool => Evaluated::Bool(self.value != 0.0),
+        }
+    }
+}
+
+/// 計算される変数に渡す、他の変数の値を解決するもの
+struct ComputedEnv<'p, 'a> {
+    processor: &'p Processor<'a>,
+    /// 計算される変数をあと何段まで入れ子で参照できるか
+    depth: usize,
+    /// 参照した変数の計算に失敗した場合の (最初の) エラー
+    error: RefCell<Option<ProcessorError>>,
+}
+
+impl VariableResolver for ComputedEnv<'_, '_> {
+    fn resolve(&self, name: &str) -> Option<f64> {
+        match self.processor.resolve_at_depth(name, self.depth)? {
+            Ok(value) => value.into_number().ok(),
+            Err(e) => {
+                self.error.borrow_mut().get_or_insert(e);
+                None
+            }
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct ProcessorError {
-    pub msg: String,
+    code: ErrorCode,
+    source: Source,
+}
+
+/// エラーメッセージに含める名前などの取り出し元
+#[derive(Debug, PartialEq)]
+enum Source {
+    /// なし (メッセージがコードのみから決まる)
+    None,
+    /// 中間表現中の値の位置
+    At(usize),
+    /// 中間表現から取り出せない名前など (評価中に生成した文字列・計算される変数の名前)
+    Text(String),
+    /// 生成済みのメッセージ (登録した関数が返したメッセージ、`Eval` に渡した数式のエラー)
+    Message(String),
 }
 
 impl ProcessorError {
-    fn new(msg: &str) -> ProcessorError {
+    fn new(code: ErrorCode) -> ProcessorError {
         ProcessorError {
-            msg: msg.to_string(),
+            code,
+            source: Source::None,
+        }
+    }
+
+    fn at(code: ErrorCode, index: usize) -> ProcessorError {
+        ProcessorError {
+            code,
+            source: Source::At(index),
+        }
+    }
+
+    fn with_text(code: ErrorCode, text: String) -> ProcessorError {
+        ProcessorError {
+            code,
+            source: Source::Text(text),
+        }
+    }
+
+    /// 登録した関数・演算子が返したメッセージのエラー
+    fn custom(msg: String) -> ProcessorError {
+        ProcessorError {
+            code: ErrorCode::Custom,
+            source: Source::Message(msg),
+        }
+    }
+
+    /// 中間表現中の位置 (ない場合は None) のエラー
+    pub(crate) fn with_index(code: ErrorCode, index: Option<usize>) -> ProcessorError {
+        match index {
+            Some(index) => ProcessorError::at(code, index),
+            None => ProcessorError::new(code),
+        }
+    }
+
+    /// 取り出し元がない場合は、中間表現中の `index` の位置の値とする
+    fn or_at(mut self, index: usize) -> ProcessorError {
+        if self.source == Source::None {
+            self.source = Source::At(index);
+        }
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// メッセージに含める名前などを取り出す、中間表現中の値の位置
+    pub fn index(&self) -> Option<usize> {
+        match self.source {
+            Source::At(index) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// コードと中間表現中の位置のみから、メッセージを生成し直せるか
+    pub fn is_reproducible(&self) -> bool {
+        matches!(self.source, Source::None | Source::At(_))
+    }
+
+    /// 評価した中間表現 `values` から、エラーメッセージを生成する
+    pub fn into_message(self, values: &[Value]) -> String {
+        match self.source {
+            Source::None => self.code.message(Subject::None),
+            Source::At(index) => self
+                .code
+                .message(values.get(index).map_or(Subject::None, Subject::Value)),
+            Source::Text(text) => self.code.message(Subject::Text(&text)),
+            Source::Message(msg) => msg,
+        }
+    }
+}
+
+/// 型付きの評価結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypedResult {
+    /// 整数であり、i64 の範囲に収まる値
+    Integer(i64),
+    /// それ以外の値 (NaN・無限大を含む)
+    Float(f64),
+}
+
+impl From<f64> for TypedResult {
+    fn from(value: f64) -> TypedResult {
+        // i64::MAX は f64 で正確に表せないため、2^63 未満かどうかで判定する
+        if value.fract() == 0.0 && value >= i64::MIN as f64 && value < -(i64::MIN as f64) {
+            TypedResult::Integer(value as i64)
+        } else {
+            TypedResult::Float(value)
+        }
+    }
+}
+
+/// 評価結果に影響はないが、結果が意図通りでない可能性がある演算についての警告
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub msg: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WarningKind {
+    /// 桁落ち (値の近い数同士の減算) や情報落ち (絶対値が大きく異なる数同士の加算) により、有効数字が大きく失われた
+    PrecisionLoss,
+}
+
+/// 二項演算の被演算子に NaN が含まれる場合の扱い
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum NanPolicy {
+    /// NaN を結果として伝播させる (`(0 / 0) + 5` → NaN)
+    #[default]
+    Propagate,
+    /// エラーとする
+    Strict,
+    /// NaN を演算の単位元 (`+`, `-` は 0、`*`, `/`, `**` は 1) とみなす (`(0 / 0) + 5` → 5)
+    ///
+    /// 両方の被演算子が NaN の場合や、単位元のない演算 (`%`, 比較演算子など) の場合は伝播させる
+    Absorb,
+}
+
+/// 評価結果と、評価に関する情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalResult {
+    pub value: f64,
+    /// 評価した中間表現 (逆ポーランド記法) の要素の数
+    pub steps: usize,
+    pub is_finite: bool,
+    /// 評価にかかった時間 (`timing` フィーチャーが必要)
+    #[cfg(feature = "timing")]
+    pub took: std::time::Duration,
+}
+
+/// 条件分岐の関数 (`If`, `Cond` など) で選ばれた分岐
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchTrace {
+    /// 関数名
+    pub function: String,
+    /// 選ばれた分岐の番号 (0 から始まる。`If` の場合は then が 0、else が 1)
+    pub branch: usize,
+}
+
+/// 評価中の値
+#[derive(Debug, Clone, PartialEq)]
+pub enum Evaluated {
+    Number(f64),
+    Str(String),
+    /// 真理値型の変数 (`Variable::new_bool`) の値
+    Bool(bool),
+}
+
+impl Evaluated {
+    /// 演算子の表で被演算子の型として使用する名前
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Evaluated::Number(_) => "number",
+            Evaluated::Str(_) => "string",
+            Evaluated::Bool(_) => "bool",
+        }
+    }
+
+    /// 数値を取り出す (真理値は真を 1、偽を 0 とし、それ以外の場合はエラー)
+    pub fn into_number(self) -> Result<f64, ProcessorError> {
+        match self {
+            Evaluated::Number(num) => Ok(num),
+            Evaluated::Bool(b) => Ok(if b { 1.0 } else { 0.0 }),
+            Evaluated::Str(string) => {
+                Err(ProcessorError::with_text(ErrorCode::ExpectedNumber, string))
+            }
         }
     }
 }
 
-pub struct Processor {
-    values: Vec<Value>,
-    functions: Vec<Function>,
-    variables: Vec<Variable>,
+/// 左右の被演算子を受け取り、二項演算の結果を返す関数
+type OperatorHandler = Box<dyn Fn(&Evaluated, &Evaluated) -> Result<Evaluated, String>>;
+
+/// 二項演算子の、被演算子の型ごとの挙動の表
+///
+/// 演算子と左右の被演算子の型名 (`Evaluated::type_name`) の組に対して挙動を登録する。
+/// 数値どうしの演算は、表に登録されていなければ通常の計算 (`ProcessorOptions` の影響を受ける) を行う
+pub struct OperatorTable {
+    entries: Vec<(Value, String, String, OperatorHandler)>,
+}
+
+impl OperatorTable {
+    /// 何も登録されていない表を生成する
+    pub fn empty() -> OperatorTable {
+        OperatorTable { entries: vec![] }
+    }
+
+    /// 演算子と左右の被演算子の型名の組に対する挙動を登録する
+    ///
+    /// 同じ組がすでに登録されている場合は、後から登録したものを使用する
+    pub fn register(
+        &mut self,
+        operator: Value,
+        lhs: &str,
+        rhs: &str,
+        handler: impl Fn(&Evaluated, &Evaluated) -> Result<Evaluated, String> + 'static,
+    ) {
+        self.entries.push((
+            operator,
+            lhs.to_string(),
+            rhs.to_string(),
+            Box::new(handler),
+        ));
+    }
+
+    fn find(&self, operator: &Value, lhs: &str, rhs: &str) -> Option<&OperatorHandler> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(o, l, r, _)| o == operator && l == lhs && r == rhs)
+            .map(|(_, _, _, handler)| handler)
+    }
+}
+
+impl Default for OperatorTable {
+    /// 文字列の連結 (`+`) と比較 (`==`, `!=`) を登録した表を生成する
+    fn default() -> OperatorTable {
+        let mut table = OperatorTable::empty();
+        table.register(Value::Plus, "string", "string", |v1, v2| match (v1, v2) {
+            (Evaluated::Str(s1), Evaluated::Str(s2)) => Ok(Evaluated::Str(format!("{}{}", s1, s2))),
+            _ => Err("error: \"+\" expects strings".to_string()),
+        });
+        table.register(Value::Equal, "string", "string", |v1, v2| {
+            Ok(Evaluated::Number(if v1 == v2 { 1.0 } else { 0.0 }))
+        });
+        table.register(Value::NotEqual, "string", "string", |v1, v2| {
+            Ok(Evaluated::Number(if v1 != v2 { 1.0 } else { 0.0 }))
+        });
+        table
+    }
+}
+
+/// 評価の挙動を変更するオプション
+#[derive(Debug, Clone, Default)]
+pub struct ProcessorOptions {
+    /// 有限の値同士の二項演算の結果が無限大になった場合にエラーとする
+    ///
+    /// ゼロ除算 (`1 / 0` など) はオーバーフローとして扱わない
+    pub overflow_error: bool,
+    /// 二項演算の被演算子に NaN が含まれる場合の扱い
+    pub nan_policy: NanPolicy,
+    /// 真理値型の変数を算術演算子 (`+`, `-`, `*`, `/`, `%`, `\`, `**`, 符号反転) の被演算子とした場合にエラーとする
+    ///
+    /// 無効な場合は、真を 1、偽を 0 として計算する
+    pub strict_types: bool,
+    /// `/`, `%`, `\` の右辺が 0 の場合にエラーとする
+    pub division_by_zero_error: bool,
+    /// 二項演算・関数の結果が NaN または無限大になった場合にエラーとする
+    ///
+    /// 変数・定数の値が NaN・無限大であること自体はエラーとしない
+    pub non_finite_error: bool,
+    /// 評価結果 (最終的な値) が NaN または無限大の場合にエラーとする
+    ///
+    /// `non_finite_error` と異なり、途中の値は検査しないが、変数・定数の値をそのまま返す場合もエラーとなる
+    pub non_finite_result_error: bool,
+    /// 関数名の大文字・小文字を区別せずに関数を探す (e.g. `add`, `ADD` で `Add` を呼び出す)
+    ///
+    /// 大文字・小文字まで一致する関数がある場合は、その関数を優先する。
+    /// 小文字で始まる関数名を関数として字句解析するには、`IdentifierConvention::Parenthesis` を併用する
+    pub case_insensitive_functions: bool,
+}
+
+impl ProcessorOptions {
+    /// 安全な計算のためのオプションをまとめて有効にしたもの
+    ///
+    /// ゼロ除算・オーバーフロー・NaN や無限大となる結果・NaN を含む演算・真理値の算術演算をすべてエラーとする
+    pub fn safe_profile() -> ProcessorOptions {
+        ProcessorOptions {
+            overflow_error: true,
+            nan_policy: NanPolicy::Strict,
+            strict_types: true,
+            division_by_zero_error: true,
+            non_finite_error: true,
+            // 変数・定数の値はそのまま返せるようにする
+            non_finite_result_error: false,
+            case_insensitive_functions: false,
+        }
+    }
+}
+
+pub struct Processor<'a> {
+    values: &'a [Value],
+    functions: &'a [Function],
+    variables: &'a [Variable],
+    constants: Option<&'a HashMap<String, f64>>,
+    resolver: Option<&'a dyn VariableResolver>,
+    arrays: Option<&'a HashMap<String, Vec<f64>>>,
+    options: ProcessorOptions,
+    operators: Option<&'a OperatorTable>,
+    warnings: Vec<Warning>,
+    /// 条件分岐の追跡を行う場合、選ばれた分岐を評価順に記録する
+    branch_trace: Option<Vec<BranchTrace>>,
+    /// `Eval` をあと何段まで入れ子で呼び出せるか
+    eval_budget: usize,
+    /// `Eval` に渡された数式の字句解析のオプション
+    lexer_options: Option<&'a LexerOptions>,
+    /// `~=` の許容誤差 (差の絶対値がこれ以下であれば等しいとする)
+    approx_tolerance: f64,
+    /// 条件分岐の関数の呼び出し (最初の引数の開始位置ごとに、関数の位置と各引数の範囲を持つ)
+    conditional_calls: HashMap<usize, Vec<ConditionalCall>>,
     index: usize,
 }
 
-impl Processor {
+impl<'a> Processor<'a> {
     pub fn new(
-        values: Vec<Value>,
-        functions: Vec<Function>,
-        variables: Vec<Variable>,
-    ) -> Processor {
+        values: &'a [Value],
+        functions: &'a [Function],
+        variables: &'a [Variable],
+    ) -> Processor<'a> {
         Processor {
             values,
             functions,
             variables,
+            constants: None,
+            resolver: None,
+            arrays: None,
+            options: ProcessorOptions::default(),
+            operators: None,
+            warnings: vec![],
+            branch_trace: None,
+            eval_budget: DEFAULT_EVAL_BUDGET,
+            lexer_options: None,
+            approx_tolerance: DEFAULT_APPROX_TOLERANCE,
+            conditional_calls: HashMap::new(),
+            index: 0,
+        }
+    }
+
+    /// 定数を指定する
+    ///
+    /// 定数は変数よりも優先して解決されるため、同名の変数によって上書きされることはない
+    pub fn with_constants(mut self, constants: &'a HashMap<String, f64>) -> Processor<'a> {
+        self.constants = Some(constants);
+        self
+    }
+
+    /// 名前から値を引けるように構築済みの変数を指定する
+    ///
+    /// `variables` よりも優先して解決される
+    pub fn with_variable_map(self, variables: &'a HashMap<String, f64>) -> Processor<'a> {
+        self.with_resolver(variables)
+    }
+
+    /// 変数名から値を解決するものを指定する
+    ///
+    /// `variables` よりも優先して解決される (`with_variable_map` と同時には指定できず、後に指定したものを使用する)
+    pub fn with_resolver(mut self, resolver: &'a dyn VariableResolver) -> Processor<'a> {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// 配列変数を指定する
+    ///
+    /// 配列変数は、可変長引数の関数のただ 1 つの引数とした場合のみ使用でき、その要素が引数として展開される (e.g. `Sum(arr)`)
+    pub fn with_arrays(mut self, arrays: &'a HashMap<String, Vec<f64>>) -> Processor<'a> {
+        self.arrays = Some(arrays);
+        self
+    }
+
+    /// 評価のオプションを指定する
+    pub fn with_options(mut self, options: ProcessorOptions) -> Processor<'a> {
+        self.options = options;
+        self
+    }
+
+    /// `Eval` を入れ子で呼び出せる深さを指定する (0 の場合は `Eval` を使用できない)
+    pub fn with_eval_budget(mut self, budget: usize) -> Processor<'a> {
+        self.eval_budget = budget;
+        self
+    }
+
+    /// `Eval` に渡された数式の字句解析のオプションを指定する (指定しない場合はデフォルトのオプションを使用する)
+    ///
+    /// 外側の数式と同じ規則 (引数の区切り文字など) で解析するために使用する
+    pub fn with_lexer_options(mut self, options: &'a LexerOptions) -> Processor<'a> {
+        self.lexer_options = Some(options);
+        self
+    }
+
+    /// `~=` の許容誤差を指定する
+    pub fn with_approx_tolerance(mut self, tolerance: f64) -> Processor<'a> {
+        self.approx_tolerance = tolerance;
+        self
+    }
+
+    /// 二項演算子の、被演算子の型ごとの挙動の表を指定する
+    ///
+    /// 指定しない場合は数値どうしの演算のみ行うことができる
+    pub fn with_operators(mut self, operators: &'a OperatorTable) -> Processor<'a> {
+        self.operators = Some(operators);
+        self
+    }
+
+    /// 逆ポーランド記法に変換された数式を評価し、結果とともに評価に関する情報を返す
+    pub fn execute_detailed(&mut self) -> Result<EvalResult, ProcessorError> {
+        #[cfg(feature = "timing")]
+        let start = std::time::Instant::now();
+
+        let value = self.execute()?;
+
+        Ok(EvalResult {
+            value,
+            steps: self.index,
+            is_finite: value.is_finite(),
+            #[cfg(feature = "timing")]
+            took: start.elapsed(),
+        })
+    }
+
+    /// 逆ポーランド記法に変換された数式を評価し、結果とともに条件分岐の関数で選ばれた分岐を評価順に返す
+    pub fn execute_with_branch_trace(&mut self) -> Result<(f64, Vec<BranchTrace>), ProcessorError> {
+        self.branch_trace = Some(vec![]);
+        let result = self.execute()?;
+        Ok((result, self.branch_trace.take().unwrap_or_default()))
+    }
+
+    /// 数式を評価せずに、未定義の変数・関数をすべて検出する
+    ///
+    /// 同じ名前は一度だけ、最初に現れた順に報告する
+    pub fn validate(&self) -> Result<(), Vec<ProcessorError>> {
+        let mut unknowns: Vec<(ErrorCode, &str)> = vec![];
+        let mut errors = vec![];
+        for (index, value) in self.values.iter().enumerate() {
+            let unknown = match value {
+                Value::Variable(v)
+                    if self.expand_array_argument(index, v).is_none()
+                        && self.resolve_evaluated(v).is_none() =>
+                {
+                    (ErrorCode::UnknownVariable, v.as_str())
+                }
+                Value::Function(f, _) | Value::KeywordFunction(f, _)
+                    if self.find_function(f).is_none() =>
+                {
+                    (ErrorCode::UnknownFunction, f.as_str())
+                }
+                _ => continue,
+            };
+            if !unknowns.contains(&unknown) {
+                unknowns.push(unknown);
+                errors.push(ProcessorError::at(unknown.0, index));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// 逆ポーランド記法に変換された数式を評価する
+    ///
+    /// 結果が数値でない場合はエラーとなる
+    pub fn execute(&mut self) -> Result<f64, ProcessorError> {
+        self.execute_evaluated()?.into_number()
+    }
+
+    /// 逆ポーランド記法に変換された数式を評価し、結果を型付きの値として返す
+    ///
+    /// 関数の実行や演算に失敗した場合は、そのエラーを値としてスタックに積み、
+    /// 条件分岐の関数の引数として使われた場合に、その関数が扱えるようにする。
+    /// 条件分岐の関数の引数は、関数が参照した時点で評価する (選ばれなかった分岐は評価しない)
+    pub fn execute_evaluated(&mut self) -> Result<Evaluated, ProcessorError> {
+        self.conditional_calls = self.find_conditional_calls();
+        let mut stack = self.execute_until(self.values.len())?;
+
+        if stack.len() != 1 {
+            return Err(ProcessorError::new(ErrorCode::Syntax));
+        }
+        match stack.pop_back().unwrap()? {
+            // 最後に評価した値 (演算子・関数・変数) を、結果を生成したものとして報告する
+            Evaluated::Number(num) if self.options.non_finite_result_error && !num.is_finite() => {
+                Err(ProcessorError::at(
+                    ErrorCode::NonFiniteResult(num),
+                    self.values.len() - 1,
+                ))
+            }
+            result => Ok(result),
+        }
+    }
+
+    /// 現在の位置から `end` の手前までを評価し、評価後のスタックを返す
+    fn execute_until(
+        &mut self,
+        end: usize,
+    ) -> Result<LinkedList<Result<Evaluated, ProcessorError>>, ProcessorError> {
+        let mut stack: LinkedList<Result<Evaluated, ProcessorError>> = LinkedList::new();
+
+        while self.index < end {
+            // 条件分岐の関数の呼び出しは、選んだ引数のみを評価して関数の位置まで読み進める
+            if let Some((call, spans)) = self.conditional_call_at(self.index, end) {
+                let result = self.call_conditional(call, &spans)?;
+                stack.push_back(result);
+                self.index = call + 1;
+                continue;
+            }
+
+            let vv = &self.values[self.index];
+            match vv {
+                // 値をスタックにプッシュする
+                Value::Number(num) => stack.push_back(Ok(Evaluated::Number(*num))),
+                Value::Str(string) => stack.push_back(Ok(Evaluated::Str(string.to_string()))),
+                Value::Function(f, arity) => {
+                    // 関数の一覧から関数名を元に関数を取得し、実行する
+                    let func = self.function_at(f, self.index)?;
+                    func.check_args_count(*arity)
+                        .map_err(|code| ProcessorError::at(code, self.index))?;
+
+                    let args = Processor::pop_args(&mut stack, *arity)?;
+                    let result = self.call(func, args, self.index);
+                    stack.push_back(result);
+                }
+                Value::KeywordFunction(f, names) => {
+                    // キーワード引数を引数名の定義順に並べ替えてから実行する
+                    let func = self.function_at(f, self.index)?;
+                    let args = Processor::pop_args(&mut stack, names.len())?;
+                    let args = func
+                        .order_keyword_args(names, args)
+                        .map_err(|code| ProcessorError::at(code, self.index))?;
+
+                    let result = self.call(func, args, self.index);
+                    stack.push_back(result);
+                }
+                Value::Variable(v) => match self.expand_array_argument(self.index, v) {
+                    Some((func, array)) => {
+                        // 配列の要素を引数として関数を実行し、関数の分も読み進める
+                        let args = array
+                            .iter()
+                            .map(|num| Ok(Evaluated::Number(*num)))
+                            .collect();
+                        let result = self.call(func, args, self.index + 1);
+                        stack.push_back(result);
+                        self.next();
+                    }
+                    // 定数・変数の一覧から名前を元に値を取得し、評価する
+                    None => match self.resolve_evaluated(v) {
+                        // 値 (計算に失敗した場合はエラー) をスタックにプッシュする
+                        Some(value) => stack.push_back(value),
+                        // 未定義の変数もエラーとしてプッシュする (Default などで置き換えられる)
+                        None => stack.push_back(Err(ProcessorError::at(
+                            ErrorCode::UnknownVariable,
+                            self.index,
+                        ))),
+                    },
+                },
+                Value::Negate => {
+                    // 単項演算子の評価 (被演算子は数値のみ)
+                    let v = stack
+                        .pop_back()
+                        .ok_or(ProcessorError::new(ErrorCode::Syntax))?;
+                    stack.push_back(
+                        v.and_then(|v| self.coerce_number(v, vv))
+                            .map(|v| Evaluated::Number(-v)),
+                    );
+                }
+                _ => {
+                    // 二項演算子の評価
+                    let v1 = stack
+                        .pop_back()
+                        .ok_or(ProcessorError::new(ErrorCode::Syntax))?;
+                    let v2 = stack
+                        .pop_back()
+                        .ok_or(ProcessorError::new(ErrorCode::Syntax))?;
+
+                    stack.push_back(
+                        v2.and_then(|v2| v1.and_then(|v1| self.calc_binary_operator(v2, v1, vv))),
+                    );
+                }
+            }
+
+            self.next();
+        }
+
+        Ok(stack)
+    }
+
+    /// 条件分岐の関数の呼び出しについて、各引数が占める範囲を求める
+    ///
+    /// 配列変数を展開して呼び出す場合や、逆ポーランド記法が正しくない場合は含めない (すべての引数を評価してから呼び出す)
+    fn find_conditional_calls(&self) -> HashMap<usize, Vec<ConditionalCall>> {
+        let mut calls: HashMap<usize, Vec<ConditionalCall>> = HashMap::new();
+        for (index, value) in self.values.iter().enumerate() {
+            let Value::Function(f, arity) = value else {
+                continue;
+            };
+            let is_conditional = matches!(
+                self.find_function(f),
+                Some(Function {
+                    handler: Handler::Conditional(_),
+                    ..
+                })
+            );
+            let is_array_argument = matches!(
+                &self.values[index.saturating_sub(1)],
+                Value::Variable(v) if self.expand_array_argument(index - 1, v).is_some()
+            );
+            if !is_conditional || is_array_argument || *arity == 0 {
+                continue;
+            }
+            if let Some(spans) = self.argument_spans(index, *arity) {
+                calls
+                    .entry(spans[0].start)
+                    .or_default()
+                    .push((index, spans));
+            }
+        }
+        calls
+    }
+
+    /// `index` の位置の関数の各引数が占める範囲 (逆ポーランド記法が正しくない場合は None)
+    fn argument_spans(&self, index: usize, arity: usize) -> Option<Vec<Range<usize>>> {
+        let mut spans = Vec::with_capacity(arity);
+        let mut end = index;
+        for _ in 0..arity {
+            // 後ろから、値を 1 つ積むまでに必要な値の数を数える
+            let mut needed = 1;
+            let mut start = end;
+            while needed > 0 {
+                start = start.checked_sub(1)?;
+                needed = needed - 1 + Processor::operand_count(&self.values[start]);
+            }
+            spans.push(start..end);
+            end = start;
+        }
+        spans.reverse();
+        Some(spans)
+    }
+
+    /// 値がスタックから取り出す値の数 (いずれの値も 1 つの値を積む)
+    fn operand_count(value: &Value) -> usize {
+        match value {
+            Value::Number(_) | Value::Str(_) | Value::Variable(_) => 0,
+            Value::Function(_, arity) => *arity,
+            Value::KeywordFunction(_, names) => names.len(),
+            Value::Negate => 1,
+            _ => 2,
+        }
+    }
+
+    /// `index` から始まり `end` の手前で終わる、条件分岐の関数の呼び出しのうち最も外側のもの
+    fn conditional_call_at(&self, index: usize, end: usize) -> Option<ConditionalCall> {
+        self.conditional_calls
+            .get(&index)?
+            .iter()
+            .rev()
+            .find(|(call, _)| *call < end)
+            .cloned()
+    }
+
+    /// `call` の位置の条件分岐の関数を、関数が参照した引数のみを評価して実行する
+    fn call_conditional(
+        &mut self,
+        call: usize,
+        spans: &[Range<usize>],
+    ) -> Result<Result<Evaluated, ProcessorError>, ProcessorError> {
+        let Value::Function(f, arity) = &self.values[call] else {
+            return Err(ProcessorError::new(ErrorCode::Syntax));
+        };
+        let func = self.function_at(f, call)?;
+        func.check_args_count(*arity)
+            .map_err(|code| ProcessorError::at(code, call))?;
+        let Handler::Conditional(selector) = &func.handler else {
+            return Err(ProcessorError::new(ErrorCode::Syntax));
+        };
+
+        // 評価した引数は、選ばれた場合にそのまま返すため保持する
+        let mut evaluated: Vec<Option<Result<Evaluated, ProcessorError>>> =
+            spans.iter().map(|_| None).collect();
+        let mut fatal = None;
+        let selected = {
+            let mut evaluate = |index: usize| {
+                if evaluated[index].is_none() {
+                    match self.evaluate_span(spans[index].clone()) {
+                        Ok(value) => evaluated[index] = Some(value),
+                        Err(e) => {
+                            fatal.get_or_insert(e);
+                            return None;
+                        }
+                    }
+                }
+                evaluated[index].as_ref().and_then(ConditionalArgs::number)
+            };
+            selector(&mut ConditionalArgs::new(spans.len(), &mut evaluate))
+        };
+        // 関数の呼び出しに失敗するなど、評価を続けられないエラーはそのまま返す
+        if let Some(e) = fatal {
+            return Err(e);
+        }
+        let index = match selected {
+            Ok(index) => index,
+            Err(e) => return Ok(Err(e.or_at(call))),
+        };
+
+        let result = match (evaluated.get_mut(index), spans.get(index)) {
+            (Some(Some(_)), _) => evaluated[index].take().unwrap(),
+            (_, Some(span)) => self.evaluate_span(span.clone())?,
+            _ => Err(ProcessorError::at(
+                ErrorCode::OutOfRangeArgument(index),
+                call,
+            )),
+        };
+        if let (Some(trace), true) = (self.branch_trace.as_mut(), result.is_ok()) {
+            trace.push(BranchTrace {
+                function: func.name.clone(),
+                branch: func.branch(index),
+            });
+        }
+        Ok(result)
+    }
+
+    /// `span` の範囲 (1 つの値を積む部分式) を評価する
+    fn evaluate_span(
+        &mut self,
+        span: Range<usize>,
+    ) -> Result<Result<Evaluated, ProcessorError>, ProcessorError> {
+        let index = self.index;
+        self.index = span.start;
+        let stack = self.execute_until(span.end);
+        self.index = index;
+
+        let mut stack = stack?;
+        if stack.len() != 1 {
+            return Err(ProcessorError::new(ErrorCode::Syntax));
+        }
+        Ok(stack.pop_back().unwrap())
+    }
+
+    /// 中間表現中の `index` の位置で呼び出された関数を探す (見つからない場合はエラー)
+    fn function_at(&self, name: &str, index: usize) -> Result<&'a Function, ProcessorError> {
+        self.find_function(name)
+            .ok_or(ProcessorError::at(ErrorCode::UnknownFunction, index))
+    }
+
+    fn find_function(&self, name: &str) -> Option<&'a Function> {
+        self.functions.iter().find(|f| f.name == name).or_else(|| {
+            if self.options.case_insensitive_functions {
+                self.functions
+                    .iter()
+                    .find(|f| f.name.eq_ignore_ascii_case(name))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// 関数を実行し、分岐の追跡を行っている場合は選ばれた分岐を記録する
+    ///
+    /// `index` は関数を呼び出した中間表現中の位置 (関数が返したエラーの位置とする)
+    fn call(
+        &mut self,
+        func: &Function,
+        args: Vec<Result<Evaluated, ProcessorError>>,
+        index: usize,
+    ) -> Result<Evaluated, ProcessorError> {
+        if let Handler::Eval = func.handler {
+            return self.eval(
+                args.into_iter()
+                    .next()
+                    .unwrap_or_else(|| Err(ProcessorError::new(ErrorCode::Syntax))),
+                index,
+            );
+        }
+        if let Handler::MapSum = func.handler {
+            return self.map_sum(args, index);
+        }
+
+        let (result, branch) = func.calc(args);
+        let mut result = result.map_err(|e| e.or_at(index));
+        // 条件分岐の関数は選んだ引数をそのまま返すため、確認しない
+        if let (Ok(Evaluated::Number(num)), None) = (&result, branch) {
+            if self.options.non_finite_error && !num.is_finite() {
+                result = Err(ProcessorError::at(
+                    ErrorCode::NonFiniteFunctionResult(*num),
+                    index,
+                ));
+            }
+        }
+        if let (Some(trace), Some(branch)) = (self.branch_trace.as_mut(), branch) {
+            trace.push(BranchTrace {
+                function: func.name.clone(),
+                branch,
+            });
+        }
+        result
+    }
+
+    /// `MapSum` の第 1 引数で指定した関数を残りの引数それぞれに適用し、結果の合計を求める
+    ///
+    /// 関数は呼び出し元と同じ関数の一覧から探す (第 1 引数以外がない場合は 0 とする)
+    fn map_sum(
+        &mut self,
+        args: Vec<Result<Evaluated, ProcessorError>>,
+        index: usize,
+    ) -> Result<Evaluated, ProcessorError> {
+        let mut args = args.into_iter();
+        let name = match args.next() {
+            Some(Ok(Evaluated::Str(name))) => name,
+            Some(Err(e)) => return Err(e),
+            _ => return Err(ProcessorError::at(ErrorCode::MapSumFunctionName, index)),
+        };
+        // 関数名は評価中に生成した文字列のため、メッセージに含める名前として保持する
+        let Some(func) = self.find_function(&name) else {
+            return Err(ProcessorError::with_text(ErrorCode::UnknownFunction, name));
+        };
+        if let Err(code) = func.check_args_count(1) {
+            return Err(ProcessorError::with_text(code, name));
+        }
+
+        // 空の場合に -0.0 とならないよう、0.0 から足し合わせる
+        args.try_fold(0.0, |acc, arg| {
+            let result = self.call(func, vec![arg], index).map_err(|e| {
+                // 適用した関数のエラーは、MapSum ではなくその関数の名前で報告する
+                if e.source == Source::At(index) {
+                    ProcessorError::with_text(e.code, name.clone())
+                } else {
+                    e
+                }
+            });
+            Ok(acc + result.and_then(Evaluated::into_number)?)
+        })
+        .map(Evaluated::Number)
+    }
+
+    /// `Eval` に渡された数式を、同じ関数・変数・定数・オプションを用いて評価する
+    ///
+    /// 入れ子で呼び出すたびに深さの上限を 1 つ消費し、使い切った場合はエラーとする
+    ///
+    /// 数式中のエラーは、その数式からメッセージを生成して返す
+    fn eval(
+        &self,
+        arg: Result<Evaluated, ProcessorError>,
+        index: usize,
+    ) -> Result<Evaluated, ProcessorError> {
+        let formula = match arg? {
+            Evaluated::Str(formula) => formula,
+            other => {
+                return Err(ProcessorError::at(
+                    ErrorCode::EvalArgument(other.type_name()),
+                    index,
+                ))
+            }
+        };
+        if self.eval_budget == 0 {
+            return Err(ProcessorError::with_text(
+                ErrorCode::EvalBudgetExhausted,
+                formula,
+            ));
+        }
+
+        let options = self.lexer_options.cloned().unwrap_or_default();
+        let tokens = Lexer::with_options(&formula, options)
+            .tokenize()
+            .map_err(|e| ProcessorError {
+                code: e.code,
+                source: Source::Message(e.message(&formula)),
+            })?;
+        let mut parser = Parser::new(tokens);
+        let values = parser.parse().map_err(|e| ProcessorError {
+            code: e.code,
+            source: Source::Message(e.message(parser.tokens())),
+        })?;
+        Processor {
+            values: &values,
+            functions: self.functions,
+            variables: self.variables,
+            constants: self.constants,
+            resolver: self.resolver,
+            arrays: self.arrays,
+            options: self.options.clone(),
+            operators: self.operators,
+            warnings: vec![],
+            branch_trace: None,
+            eval_budget: self.eval_budget - 1,
+            lexer_options: self.lexer_options,
+            approx_tolerance: self.approx_tolerance,
+            conditional_calls: HashMap::new(),
             index: 0,
         }
+        .execute_evaluated()
+        .map_err(|e| match e.source {
+            Source::None | Source::At(_) => ProcessorError {
+                code: e.code,
+                source: Source::Message(e.into_message(&values)),
+            },
+            _ => e,
+        })
+    }
+
+    /// 呼び出し時に渡された引数の数だけスタックからポップし、関数の引数とする
+    fn pop_args(
+        stack: &mut LinkedList<Result<Evaluated, ProcessorError>>,
+        count: usize,
+    ) -> Result<Vec<Result<Evaluated, ProcessorError>>, ProcessorError> {
+        let mut args = vec![];
+        for _ in 0..count {
+            args.push(
+                stack
+                    .pop_back()
+                    .ok_or(ProcessorError::new(ErrorCode::Syntax))?,
+            )
+        }
+        // 後ろの値からポップされるので、順番を入れ替える
+        // e.g. 2 3 Add の場合、3 → 2 の順でスタックからポップされる
+        args.reverse();
+
+        Ok(args)
+    }
+
+    /// `index` の位置の変数が配列変数であり、直後の可変長引数の関数のただ 1 つの引数である場合、その関数と配列の要素を返す
+    fn expand_array_argument(&self, index: usize, name: &str) -> Option<(&'a Function, &'a [f64])> {
+        let array = self.arrays?.get(name)?;
+        let Some(Value::Function(f, 1)) = self.values.get(index + 1) else {
+            return None;
+        };
+        let func = self.find_function(f)?;
+        func.args_count
+            .is_none()
+            .then_some((func, array.as_slice()))
+    }
+
+    /// 定数 → 構築済みの変数 (resolver) → 変数の順に名前を探索し、宣言された型に応じた評価中の値を返す (定数は数値とする)
+    ///
+    /// 計算される変数の計算に失敗した場合はエラーを返す
+    fn resolve_evaluated(&self, name: &str) -> Option<Result<Evaluated, ProcessorError>> {
+        self.resolve_at_depth(name, COMPUTED_VARIABLE_DEPTH)
+    }
+
+    /// `depth` は、計算される変数をあと何段まで入れ子で参照できるか
+    fn resolve_at_depth(
+        &self,
+        name: &str,
+        depth: usize,
+    ) -> Option<Result<Evaluated, ProcessorError>> {
+        self.constants
+            .and_then(|constants| constants.get(name).copied())
+            .or_else(|| self.resolver.and_then(|resolver| resolver.resolve(name)))
+            .map(|num| Ok(Evaluated::Number(num)))
+            .or_else(|| {
+                self.variables
+                    .iter()
+                    .find(|v| v.name == name)
+                    .map(|v| self.evaluate_variable(v, depth))
+            })
+            .or_else(|| crate::reserved_constant(name).map(|num| Ok(Evaluated::Number(num))))
+    }
+
+    /// 変数の値を返す (計算される変数の場合は、参照のたびに計算する)
+    fn evaluate_variable(
+        &self,
+        variable: &Variable,
+        depth: usize,
+    ) -> Result<Evaluated, ProcessorError> {
+        let compute = match &variable.computed {
+            Some(compute) => compute,
+            None => return Ok(variable.evaluated()),
+        };
+        if depth == 0 {
+            return Err(ProcessorError::with_text(
+                ErrorCode::ComputedVariableBudgetExhausted,
+                variable.name.clone(),
+            ));
+        }
+
+        let env = ComputedEnv {
+            processor: self,
+            depth: depth - 1,
+            error: RefCell::new(None),
+        };
+        let value = compute(&env);
+        // 参照した変数の計算に失敗した場合は、そのエラーを優先する
+        match env.error.into_inner() {
+            Some(e) => Err(e),
+            None => value.map(Evaluated::Number).ok_or_else(|| {
+                ProcessorError::with_text(ErrorCode::ComputedVariableFailed, variable.name.clone())
+            }),
+        }
+    }
+
+    /// 評価中に検出した警告を取り出す
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// 被演算子の型に応じて二項演算子を評価する
+    ///
+    /// 演算子の表に登録されている場合はその挙動を、数値どうしの場合は通常の計算を行う
+    fn calc_binary_operator(
+        &mut self,
+        v1: Evaluated,
+        v2: Evaluated,
+        operator: &Value,
+    ) -> Result<Evaluated, ProcessorError> {
+        if let Some(handler) = self
+            .operators
+            .and_then(|table| table.find(operator, v1.type_name(), v2.type_name()))
+        {
+            return handler(&v1, &v2).map_err(ProcessorError::custom);
+        }
+
+        match (v1, v2) {
+            (
+                v1 @ (Evaluated::Number(_) | Evaluated::Bool(_)),
+                v2 @ (Evaluated::Number(_) | Evaluated::Bool(_)),
+            ) => {
+                let v1 = self.coerce_number(v1, operator)?;
+                let v2 = self.coerce_number(v2, operator)?;
+                self.calc_number_operator(v1, v2, operator)
+                    .map(Evaluated::Number)
+            }
+            (v1, v2) => Err(self.error(ErrorCode::OperatorNotDefined {
+                lhs: v1.type_name(),
+                rhs: v2.type_name(),
+            })),
+        }
+    }
+
+    /// 演算子の被演算子を数値に変換する
+    ///
+    /// `strict_types` が有効な場合、真理値を算術演算子の被演算子とするとエラーとなる
+    fn coerce_number(&self, value: Evaluated, operator: &Value) -> Result<f64, ProcessorError> {
+        let is_arithmetic = matches!(
+            operator,
+            Value::Plus
+                | Value::Minus
+                | Value::Asterisk
+                | Value::Slash
+                | Value::Percent
+                | Value::Backslash
+                | Value::Power
+                | Value::Negate
+        );
+        match value {
+            Evaluated::Bool(b) if self.options.strict_types && is_arithmetic => {
+                Err(self.error(ErrorCode::BoolInStrictMode(b)))
+            }
+            value => value.into_number(),
+        }
+    }
+
+    /// 数値どうしの二項演算子を評価する (オプションの影響を受ける)
+    fn calc_number_operator(
+        &mut self,
+        v1: f64,
+        v2: f64,
+        operator: &Value,
+    ) -> Result<f64, ProcessorError> {
+        let (v1, v2) = match self.options.nan_policy {
+            NanPolicy::Propagate => (v1, v2),
+            NanPolicy::Strict if v1.is_nan() || v2.is_nan() => {
+                return Err(self.error(ErrorCode::NanOperand(v1, v2)))
+            }
+            NanPolicy::Strict => (v1, v2),
+            NanPolicy::Absorb => Processor::absorb_nan(v1, v2, operator),
+        };
+        let is_division_by_zero =
+            matches!(operator, Value::Slash | Value::Percent | Value::Backslash) && v2 == 0.0;
+        if self.options.division_by_zero_error && is_division_by_zero {
+            return Err(self.error(ErrorCode::DivisionByZero(v1, v2)));
+        }
+
+        let result = match operator {
+            // 許容誤差は Processor ごとに指定されるため、calc では扱わない
+            Value::ApproxEqual => {
+                if (v1 - v2).abs() <= self.approx_tolerance {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            _ => Processor::calc(v1, v2, operator).map_err(|code| self.error(code))?,
+        };
+
+        // 有限の値同士の演算で無限大となった場合はオーバーフローとする (ゼロ除算は除く)
+        if self.options.overflow_error
+            && v1.is_finite()
+            && v2.is_finite()
+            && result.is_infinite()
+            && !is_division_by_zero
+        {
+            return Err(self.error(ErrorCode::Overflow));
+        }
+        if self.options.non_finite_error && !result.is_finite() {
+            return Err(self.error(ErrorCode::NonFiniteOperation(v1, v2)));
+        }
+
+        if matches!(operator, Value::Plus | Value::Minus) {
+            self.check_precision_loss(v1, v2, result, operator);
+        }
+
+        Ok(result)
     }
 
-    /// 逆ポーランド記法に変換された数式を評価する
-    pub fn execute(&mut self) -> Result<f64, ProcessorError> {
-        let mut stack = LinkedList::new();
-
-        loop {
-            match self.values.get(self.index) {
-                Some(vv) => match vv {
-                    // 値をスタックにプッシュする
-                    Value::Number(num) => stack.push_back(*num),
-                    Value::Function(f) => {
-                        // 関数の一覧から関数名を元に関数を取得し、実行する
-                        match self.functions.iter().find(|ff| ff.name == f.to_string()) {
-                            Some(func) => {
-                                let mut args = vec![];
-                                // 引数の数だけスタックからポップし、関数の引数に指定する
-                                for _ in 0..func.args_count {
-                                    args.push(
-                                        stack
-                                            .pop_back()
-                                            .ok_or(ProcessorError::new("error: syntax error"))?,
-                                    )
-                                }
-                                // 後ろの値からポップされるので、順番を入れ替える
-                                // e.g. 2 3 Add の場合、3 → 2 の順でスタックからポップされる
-                                args.reverse();
-
-                                let result = func.calc(args)?;
-                                stack.push_back(result);
-                            }
-                            None => {
-                                return Err(ProcessorError::new(&format!(
-                                    "error: unknown function, {:?}",
-                                    f
-                                )))
-                            }
-                        }
-                    }
-                    Value::Variable(v) => {
-                        // 変数の一覧から変数名を元に変数を取得し、評価する
-                        match self.variables.iter().find(|vv| vv.name == v.to_string()) {
-                            Some(vv) => {
-                                // 引数の値をスタックにプッシュする
-                                stack.push_back(vv.value);
-                            }
-                            None => {
-                                return Err(ProcessorError::new(&format!(
-                                    "error: unknown variable, {:?}",
-                                    v
-                                )))
-                            }
-                        }
-                    }
-                    _ => {
-                        // 二項演算子の評価
-                        let v1 = stack
-                            .pop_back()
-                            .ok_or(ProcessorError::new("error: syntax error"))?;
-                        let v2 = stack
-                            .pop_back()
-                            .ok_or(ProcessorError::new("error: syntax error"))?;
-
-                        stack.push_back(Processor::calc_binary_operator(v2, v1, vv)?);
-                    }
-                },
-                None => break,
-            }
+    /// 片方の被演算子のみが NaN の場合に、演算の単位元に置き換える
+    fn absorb_nan(v1: f64, v2: f64, operator: &Value) -> (f64, f64) {
+        let identity = match operator {
+            Value::Plus | Value::Minus => 0.0,
+            Value::Asterisk | Value::Slash | Value::Backslash | Value::Power => 1.0,
+            _ => return (v1, v2),
+        };
+        match (v1.is_nan(), v2.is_nan()) {
+            (true, false) => (identity, v2),
+            (false, true) => (v1, identity),
+            _ => (v1, v2),
+        }
+    }
 
-            self.next();
+    /// 加減算で有効数字が大きく失われていないかを調べ、失われている場合は警告を記録する
+    ///
+    /// - 情報落ち: 絶対値の小さい方の値が 2^-53 倍未満であり、結果に全く反映されない
+    /// - 桁落ち: 結果の絶対値が元の値の 2^-26 倍未満となり、有効数字の半分以上が失われる (結果が 0 の場合を除く)
+    fn check_precision_loss(&mut self, v1: f64, v2: f64, result: f64, operator: &Value) {
+        if !v1.is_finite() || !v2.is_finite() || v1 == 0.0 || v2 == 0.0 {
+            return;
         }
 
-        if stack.len() == 1 {
-            Ok(stack.pop_back().unwrap())
+        let (larger, smaller) = (v1.abs().max(v2.abs()), v1.abs().min(v2.abs()));
+        let msg = if smaller < larger * f64::EPSILON / 2.0 {
+            format!(
+                "{:?} is absorbed in {:?} {:?} {:?}",
+                smaller, v1, operator, v2
+            )
+        } else if result != 0.0 && result.abs() < larger * 2f64.powi(-26) {
+            format!("cancellation in {:?} {:?} {:?}", v1, operator, v2)
         } else {
-            Err(ProcessorError::new("error: syntax error"))
-        }
+            return;
+        };
+
+        self.warnings.push(Warning {
+            kind: WarningKind::PrecisionLoss,
+            msg: format!("warning: precision loss, {}", msg),
+        });
     }
 
-    fn calc_binary_operator(v1: f64, v2: f64, operator: &Value) -> Result<f64, ProcessorError> {
+    /// 二項演算子を評価する (オプションの影響を受けない)
+    pub(crate) fn calc(v1: f64, v2: f64, operator: &Value) -> Result<f64, ErrorCode> {
         match operator {
             Value::Plus => Ok(v1 + v2),
             Value::Minus => Ok(v1 - v2),
             Value::Asterisk => Ok(v1 * v2),
             Value::Slash => Ok(v1 / v2),
             Value::Percent => Ok(v1 % v2),
+            // 負の無限大方向に丸める (e.g. -7 \ 2 = -4)
+            Value::Backslash => Ok((v1 / v2).floor()),
+            Value::Power => Ok(v1.powf(v2)),
             Value::Equal => Ok(if v1 == v2 { 1.0 } else { 0.0 }),
             Value::NotEqual => Ok(if v1 != v2 { 1.0 } else { 0.0 }),
             Value::GreaterThan => Ok(if v1 > v2 { 1.0 } else { 0.0 }),
             Value::GreaterThanOrEqual => Ok(if v1 >= v2 { 1.0 } else { 0.0 }),
             Value::LessThan => Ok(if v1 < v2 { 1.0 } else { 0.0 }),
             Value::LessThanOrEqual => Ok(if v1 <= v2 { 1.0 } else { 0.0 }),
-            _ => Err(ProcessorError::new(&format!(
-                "error: unexpected token, {:?}",
-                operator
-            ))),
+            Value::LogicalXor => Ok(if (v1 != 0.0) != (v2 != 0.0) { 1.0 } else { 0.0 }),
+            Value::LogicalAnd => Ok(if v1 != 0.0 && v2 != 0.0 { 1.0 } else { 0.0 }),
+            Value::LogicalOr => Ok(if v1 != 0.0 || v2 != 0.0 { 1.0 } else { 0.0 }),
+            _ => Err(ErrorCode::UnexpectedToken),
         }
     }
 
+    /// 現在評価している値 (演算子など) の位置のエラー
+    fn error(&self, code: ErrorCode) -> ProcessorError {
+        ProcessorError::at(code, self.index)
+    }
+
     fn next(&mut self) -> Option<&Value> {
         self.index += 1;
         self.values.get(self.index - 1)
@@ -187,15 +1739,38 @@ impl Processor {
 mod tests {
     use super::*;
 
+    fn sqrt() -> Function {
+        Function::new_fallible("Sqrt", 1, |args| {
+            if args[0] < 0.0 {
+                Err("error: negative".to_string())
+            } else {
+                Ok(args[0].sqrt())
+            }
+        })
+    }
+
     #[test]
     fn test_execute() {
         let success_data = [
             (
                 // Minus(-1.0)
-                vec![Value::Number(1.0), Value::Function("Minus".to_string())],
-                vec![Function::new("Minus", 1, |args| -1.0 * args[0])],
+                vec![Value::Number(1.0), Value::Function("Minus".to_string(), 1)],
+                vec![Function::new("Minus", 1, |args| -args[0])],
                 Ok(-1.0),
             ),
+            (
+                // 3 - -(3 + 2)
+                vec![
+                    Value::Number(3.0),
+                    Value::Number(3.0),
+                    Value::Number(2.0),
+                    Value::Plus,
+                    Value::Negate,
+                    Value::Minus,
+                ],
+                vec![],
+                Ok(8.0),
+            ),
             (
                 // Add((2 + 3) + 4, 5) + Sub(2, 3)
                 vec![
@@ -205,10 +1780,10 @@ mod tests {
                     Value::Number(4.0),
                     Value::Plus,
                     Value::Number(5.0),
-                    Value::Function("Add".to_string()),
+                    Value::Function("Add".to_string(), 2),
                     Value::Number(2.0),
                     Value::Number(3.0),
-                    Value::Function("Sub".to_string()),
+                    Value::Function("Sub".to_string(), 2),
                     Value::Plus,
                 ],
                 vec![
@@ -260,14 +1835,59 @@ mod tests {
                 vec![],
                 Ok(0.0),
             ),
+            (
+                // 2 ^ 3 ^ 2 * 2 → 2 3 2 ^ ^ 2 *
+                vec![
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Number(2.0),
+                    Value::Power,
+                    Value::Power,
+                    Value::Number(2.0),
+                    Value::Asterisk,
+                ],
+                vec![],
+                Ok(1024.0),
+            ),
+            (
+                // Sum(1, 2, 3)
+                vec![
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Function("Sum".to_string(), 3),
+                ],
+                vec![Function::variadic("Sum", |args| args.iter().sum())],
+                Ok(6.0),
+            ),
+            (
+                // First(Sqrt(-1), 2) + 1 → 選ばれなかった引数のエラーは無視される
+                vec![
+                    Value::Number(-1.0),
+                    Value::Function("Sqrt".to_string(), 1),
+                    Value::Number(2.0),
+                    Value::Function("First".to_string(), 2),
+                    Value::Number(1.0),
+                    Value::Plus,
+                ],
+                vec![
+                    sqrt(),
+                    Function::conditional("First", None, |args| {
+                        (0..args.len())
+                            .find(|&i| args.get(i).is_some())
+                            .ok_or("error: no value".to_string())
+                    }),
+                ],
+                Ok(3.0),
+            ),
         ];
 
-        success_data.map(|(input, functions, expected)| {
+        for (input, functions, expected) in success_data {
             assert_eq!(
-                Processor::new(input, functions, vec![Variable::new("hoge", 3.0)]).execute(),
+                Processor::new(&input, &functions, &[Variable::new("hoge", 3.0)]).execute(),
                 expected
             );
-        });
+        }
 
         let failure_data = [
             (
@@ -283,28 +1903,661 @@ mod tests {
                 vec![],
             ),
             (
-                vec![Value::Number(1.0), Value::Function("Add".to_string())],
+                vec![Value::Number(1.0), Value::Function("Add".to_string(), 2)],
                 vec![Function::new("Add", 2, |args| args[0] + args[1])],
                 vec![],
             ),
             (
                 vec![
                     Value::Number(1.0),
-                    Value::Function("add".to_string()),
+                    Value::Function("add".to_string(), 1),
                     Value::Number(2.0),
                 ],
                 vec![],
                 vec![Variable::new("not_add", 3.0)],
             ),
+            (
+                vec![Value::Number(-1.0), Value::Function("Sqrt".to_string(), 1)],
+                vec![sqrt()],
+                vec![],
+            ),
+            (
+                // Sqrt(-1) + 1 → 演算に使われた引数のエラーは伝播する
+                vec![
+                    Value::Number(-1.0),
+                    Value::Function("Sqrt".to_string(), 1),
+                    Value::Number(1.0),
+                    Value::Plus,
+                ],
+                vec![sqrt()],
+                vec![],
+            ),
+            (
+                vec![Value::Number(1.0), Value::Function("Sum".to_string(), 2)],
+                vec![Function::variadic("Sum", |args| args.iter().sum())],
+                vec![],
+            ),
+        ];
+
+        for (input, functions, variables) in failure_data {
+            assert!(Processor::new(&input, &functions, &variables)
+                .execute()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let num = Value::Number;
+        let strict = ProcessorOptions {
+            division_by_zero_error: true,
+            ..ProcessorOptions::default()
+        };
+        let data = [
+            // 1 / 0
+            (vec![num(1.0), num(0.0), Value::Slash], f64::INFINITY),
+            // -1 / 0
+            (vec![num(-1.0), num(0.0), Value::Slash], f64::NEG_INFINITY),
+            // 1 % 0
+            (vec![num(1.0), num(0.0), Value::Percent], f64::NAN),
+            // 0 / 0
+            (vec![num(0.0), num(0.0), Value::Slash], f64::NAN),
+            // 1 \ 0
+            (vec![num(1.0), num(0.0), Value::Backslash], f64::INFINITY),
+        ];
+        for (values, expected) in data {
+            // 通常はそのまま無限大・NaN を返す
+            let result = Processor::new(&values, &[], &[]).execute().unwrap();
+            assert!(
+                result == expected || (result.is_nan() && expected.is_nan()),
+                "{:?}",
+                values
+            );
+
+            let error = Processor::new(&values, &[], &[])
+                .with_options(strict.clone())
+                .execute()
+                .unwrap_err();
+            assert!(
+                error
+                    .into_message(&values)
+                    .starts_with("error: division by zero"),
+                "{:?}",
+                values
+            );
+        }
+
+        // 右辺が 0 でなければ通常通り計算する
+        let values = vec![num(7.0), num(2.0), Value::Slash];
+        assert_eq!(
+            Processor::new(&values, &[], &[])
+                .with_options(strict.clone())
+                .execute(),
+            Ok(3.5)
+        );
+        let values = vec![num(7.0), num(2.0), Value::Percent];
+        assert_eq!(
+            Processor::new(&values, &[], &[])
+                .with_options(strict.clone())
+                .execute(),
+            Ok(1.0)
+        );
+        let values = vec![num(1.0), num(0.0), Value::Percent];
+        assert_eq!(
+            Processor::new(&values, &[], &[])
+                .with_options(strict.clone())
+                .execute()
+                .map_err(|e| e.into_message(&values)),
+            Err("error: division by zero, 1.0 Percent 0.0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_non_finite_result() {
+        let num = Value::Number;
+        let strict = ProcessorOptions {
+            non_finite_result_error: true,
+            ..ProcessorOptions::default()
+        };
+        let variables = [Variable::new("x", f64::NAN)];
+
+        let success_data = [
+            // 1 + 2
+            (vec![num(1.0), num(2.0), Value::Plus], 3.0),
+            // 途中の値が無限大でも、結果が有限であればエラーとしない
+            // 1 / (1 / 0)
+            (
+                vec![num(1.0), num(1.0), num(0.0), Value::Slash, Value::Slash],
+                0.0,
+            ),
+        ];
+        for (values, expected) in success_data {
+            assert_eq!(
+                Processor::new(&values, &[], &variables)
+                    .with_options(strict.clone())
+                    .execute(),
+                Ok(expected),
+                "{:?}",
+                values
+            );
+        }
+
+        let failure_data = [
+            // 0 / 0
+            (
+                vec![num(0.0), num(0.0), Value::Slash],
+                "error: non-finite result, NaN produced by Slash",
+            ),
+            // 1e308 * 10
+            (
+                vec![num(1e308), num(10.0), Value::Asterisk],
+                "error: non-finite result, inf produced by Asterisk",
+            ),
+            // x
+            (
+                vec![Value::Variable("x".to_string())],
+                "error: non-finite result, NaN produced by Variable(\"x\")",
+            ),
+        ];
+        for (values, msg) in failure_data {
+            // デフォルトではそのまま返す
+            assert!(!Processor::new(&values, &[], &variables)
+                .execute()
+                .unwrap()
+                .is_finite());
+            assert_eq!(
+                Processor::new(&values, &[], &variables)
+                    .with_options(strict.clone())
+                    .execute()
+                    .map_err(|e| e.into_message(&values)),
+                Err(msg.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_floor_division() {
+        let num = Value::Number;
+        let data = [
+            // 7 \ 2
+            (vec![num(7.0), num(2.0), Value::Backslash], 3.0),
+            // 商は負の無限大方向に丸める
+            // -7 \ 2
+            (vec![num(-7.0), num(2.0), Value::Backslash], -4.0),
+            // 7 \ -2
+            (vec![num(7.0), num(-2.0), Value::Backslash], -4.0),
+            // -7 \ -2
+            (vec![num(-7.0), num(-2.0), Value::Backslash], 3.0),
+            // 7.5 \ 2.5
+            (vec![num(7.5), num(2.5), Value::Backslash], 3.0),
+            // -1 \ 0.5
+            (vec![num(-1.0), num(0.5), Value::Backslash], -2.0),
+        ];
+        for (values, expected) in data {
+            assert_eq!(
+                Processor::new(&values, &[], &[]).execute(),
+                Ok(expected),
+                "{:?}",
+                values
+            );
+        }
+    }
+
+    #[test]
+    fn test_approx_equal() {
+        let num = Value::Number;
+        // 0.1 + 0.2 ~= 0.3
+        let values = vec![
+            num(0.1),
+            num(0.2),
+            Value::Plus,
+            num(0.3),
+            Value::ApproxEqual,
+        ];
+        let data = [(1e-12, 1.0), (0.0, 0.0)];
+        for (tolerance, expected) in data {
+            assert_eq!(
+                Processor::new(&values, &[], &[])
+                    .with_approx_tolerance(tolerance)
+                    .execute(),
+                Ok(expected),
+                "{:?}",
+                tolerance
+            );
+        }
+
+        // 0.1 + 0.2 == 0.3 は常に厳密に比較する
+        let values = vec![num(0.1), num(0.2), Value::Plus, num(0.3), Value::Equal];
+        assert_eq!(
+            Processor::new(&values, &[], &[])
+                .with_approx_tolerance(1e-12)
+                .execute(),
+            Ok(0.0)
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let var = |name: &str| Value::Variable(name.to_string());
+        let functions = vec![sqrt()];
+        let variables = vec![Variable::new("x", 1.0)];
+
+        // Foo(a, Sqrt(x)) + b * a
+        let values = [
+            var("a"),
+            var("x"),
+            Value::Function("Sqrt".to_string(), 1),
+            Value::Function("Foo".to_string(), 2),
+            var("b"),
+            var("a"),
+            Value::Asterisk,
+            Value::Plus,
+        ];
+        let errors = Processor::new(&values, &functions, &variables)
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            errors
+                .into_iter()
+                .map(|e| e.into_message(&values))
+                .collect::<Vec<_>>(),
+            vec![
+                "error: unknown variable, \"a\"",
+                "error: unknown function, \"Foo\"",
+                "error: unknown variable, \"b\"",
+            ]
+        );
+
+        // Sqrt(x) + 1
+        let values = [
+            var("x"),
+            Value::Function("Sqrt".to_string(), 1),
+            Value::Number(1.0),
+            Value::Plus,
+        ];
+        assert_eq!(
+            Processor::new(&values, &functions, &variables).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_functions() {
+        let functions = [
+            Function::new("Add", 2, |args| args[0] + args[1]),
+            Function::new("Double", 1, |args| args[0] * 2.0),
+            // 大文字・小文字まで一致するものを優先する
+            Function::new("double", 1, |args| args[0] * 3.0),
+        ];
+        let options = ProcessorOptions {
+            case_insensitive_functions: true,
+            ..ProcessorOptions::default()
+        };
+        let call = |name: &str, args: &[f64]| {
+            let mut values: Vec<Value> = args.iter().map(|a| Value::Number(*a)).collect();
+            values.push(Value::Function(name.to_string(), args.len()));
+            values
+        };
+
+        let success_data = [
+            (call("Add", &[1.0, 2.0]), 3.0),
+            (call("add", &[1.0, 2.0]), 3.0),
+            (call("ADD", &[1.0, 2.0]), 3.0),
+            (call("aDd", &[1.0, 2.0]), 3.0),
+            (call("Double", &[1.0]), 2.0),
+            (call("double", &[1.0]), 3.0),
+            (call("DOUBLE", &[1.0]), 2.0),
+        ];
+        for (values, expected) in success_data {
+            assert_eq!(
+                Processor::new(&values, &functions, &[])
+                    .with_options(options.clone())
+                    .execute(),
+                Ok(expected),
+                "{:?}",
+                values
+            );
+        }
+
+        // 無効な場合は大文字・小文字まで一致する必要がある
+        let values = call("ADD", &[1.0, 2.0]);
+        assert_eq!(
+            Processor::new(&values, &functions, &[])
+                .execute()
+                .map_err(|e| e.into_message(&values)),
+            Err("error: unknown function, \"ADD\"".to_string())
+        );
+        assert!(Processor::new(&values, &functions, &[])
+            .with_options(options.clone())
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_computed_variables() {
+        let variables = vec![
+            Variable::new_computed("area", |env| {
+                Some(env.resolve("width")? * env.resolve("height")?)
+            }),
+            Variable::new("width", 2.0),
+            Variable::new("height", 3.0),
+            Variable::new_computed("volume", |env| Some(env.resolve("area")? * 4.0)),
+            Variable::new_computed("missing", |env| env.resolve("depth")),
+            // 互いに参照し合う
+            Variable::new_computed("a", |env| env.resolve("b")),
+            Variable::new_computed("b", |env| env.resolve("a")),
+        ];
+        let var = |name: &str| Value::Variable(name.to_string());
+
+        let success_data = [
+            // area
+            (vec![var("area")], 6.0),
+            // volume - area
+            (vec![var("volume"), var("area"), Value::Minus], 18.0),
         ];
+        for (values, expected) in success_data {
+            assert_eq!(
+                Processor::new(&values, &[], &variables).execute(),
+                Ok(expected),
+                "{:?}",
+                values
+            );
+        }
 
-        failure_data.map(|(input, functions, variables)| {
+        let failure_data = [
+            (
+                var("missing"),
+                "error: failed to compute variable, \"missing\"",
+            ),
+            (
+                var("a"),
+                "error: recursion budget of computed variable is exhausted, \"a\"",
+            ),
+        ];
+        for (value, msg) in failure_data {
+            let values = [value];
             assert_eq!(
-                (Processor::new(input, functions, variables)
+                Processor::new(&values, &[], &variables)
                     .execute()
-                    .is_err()),
-                (true)
+                    .map_err(|e| e.into_message(&values)),
+                Err(msg.to_string())
+            );
+        }
+        assert_eq!(
+            Processor::new(&[var("a")], &[], &variables).validate(),
+            Ok(())
+        );
+
+        // 参照されるたびに計算する
+        let count = Rc::new(RefCell::new(0));
+        let counter = Rc::clone(&count);
+        let variables = vec![Variable::new_computed("n", move |_| {
+            *counter.borrow_mut() += 1;
+            Some(*counter.borrow() as f64)
+        })];
+        let values = [var("n"), var("n"), Value::Plus];
+        assert_eq!(Processor::new(&values, &[], &variables).execute(), Ok(3.0));
+        assert_eq!(*count.borrow(), 2);
+    }
+
+    #[test]
+    fn test_typed_variables() {
+        let variables = vec![
+            Variable::new_bool("flag", true),
+            Variable::new_number("x", 2.0),
+        ];
+        let var = |name: &str| Value::Variable(name.to_string());
+        let strict = ProcessorOptions {
+            strict_types: true,
+            ..ProcessorOptions::default()
+        };
+
+        // flag + x
+        let plus = [var("flag"), var("x"), Value::Plus];
+        assert_eq!(Processor::new(&plus, &[], &variables).execute(), Ok(3.0));
+        assert!(Processor::new(&plus, &[], &variables)
+            .with_options(strict.clone())
+            .execute()
+            .is_err());
+
+        // -flag
+        let negate = [var("flag"), Value::Negate];
+        assert_eq!(Processor::new(&negate, &[], &variables).execute(), Ok(-1.0));
+        assert!(Processor::new(&negate, &[], &variables)
+            .with_options(strict.clone())
+            .execute()
+            .is_err());
+
+        // 算術演算子以外は strict_types が有効でも使用できる
+        let success_data = [
+            // flag
+            (vec![var("flag")], Evaluated::Bool(true)),
+            // flag == 1
+            (
+                vec![var("flag"), Value::Number(1.0), Value::Equal],
+                Evaluated::Number(1.0),
+            ),
+            // flag && x > 1
+            (
+                vec![
+                    var("flag"),
+                    var("x"),
+                    Value::Number(1.0),
+                    Value::GreaterThan,
+                    Value::LogicalAnd,
+                ],
+                Evaluated::Number(1.0),
+            ),
+            // x * 3
+            (
+                vec![var("x"), Value::Number(3.0), Value::Asterisk],
+                Evaluated::Number(6.0),
+            ),
+        ];
+        for (values, expected) in success_data {
+            assert_eq!(
+                Processor::new(&values, &[], &variables)
+                    .with_options(strict.clone())
+                    .execute_evaluated(),
+                Ok(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_operator_table() {
+        let mut operators = OperatorTable::default();
+        // 文字列の繰り返し ("ab" * 2 → "abab")
+        operators.register(Value::Asterisk, "string", "number", |v1, v2| {
+            match (v1, v2) {
+                (Evaluated::Str(s), Evaluated::Number(n)) if *n >= 0.0 => {
+                    Ok(Evaluated::Str(s.repeat(*n as usize)))
+                }
+                _ => Err("error: invalid repeat".to_string()),
+            }
+        });
+        // 数値どうしの演算も上書きできる
+        operators.register(Value::Percent, "number", "number", |v1, v2| {
+            match (v1, v2) {
+                (Evaluated::Number(n1), Evaluated::Number(n2)) => {
+                    Ok(Evaluated::Number(n1.rem_euclid(*n2)))
+                }
+                _ => unreachable!(),
+            }
+        });
+        let str = |s: &str| Value::Str(s.to_string());
+
+        let success_data = [
+            // 1 + 2
+            (
+                vec![Value::Number(1.0), Value::Number(2.0), Value::Plus],
+                Evaluated::Number(3.0),
+            ),
+            // "a" + "b"
+            (
+                vec![str("a"), str("b"), Value::Plus],
+                Evaluated::Str("ab".to_string()),
+            ),
+            // "a" + "b" == "ab"
+            (
+                vec![str("a"), str("b"), Value::Plus, str("ab"), Value::Equal],
+                Evaluated::Number(1.0),
+            ),
+            // "ab" * 2
+            (
+                vec![str("ab"), Value::Number(2.0), Value::Asterisk],
+                Evaluated::Str("abab".to_string()),
+            ),
+            // -7 % 3
+            (
+                vec![Value::Number(-7.0), Value::Number(3.0), Value::Percent],
+                Evaluated::Number(2.0),
+            ),
+        ];
+        for (values, expected) in success_data {
+            assert_eq!(
+                Processor::new(&values, &[], &[])
+                    .with_operators(&operators)
+                    .execute_evaluated(),
+                Ok(expected)
             );
+        }
+
+        let failure_data = [
+            // "a" - "b"
+            vec![str("a"), str("b"), Value::Minus],
+            // 1 + "a"
+            vec![Value::Number(1.0), str("a"), Value::Plus],
+            // "ab" * -1
+            vec![str("ab"), Value::Number(-1.0), Value::Asterisk],
+        ];
+        for values in failure_data {
+            assert!(Processor::new(&values, &[], &[])
+                .with_operators(&operators)
+                .execute_evaluated()
+                .is_err());
+        }
+
+        // 表を指定しない場合は数値どうしの演算のみ行うことができる
+        let values = vec![str("a"), str("b"), Value::Plus];
+        assert!(Processor::new(&values, &[], &[])
+            .execute_evaluated()
+            .is_err());
+        // 結果が数値でない場合、execute はエラーとなる
+        assert!(Processor::new(&values, &[], &[])
+            .with_operators(&operators)
+            .execute()
+            .is_err());
+    }
+
+    #[test]
+    fn test_typed_function() {
+        let lookup = Function::typed("Lookup", Some(2), |args| match (&args[0], &args[1]) {
+            (Evaluated::Str(key), Evaluated::Number(id)) => match (key.as_str(), *id as i64) {
+                ("region", 3) => Ok(Evaluated::Str("tokyo".to_string())),
+                ("rate", 3) => Ok(Evaluated::Number(0.1)),
+                _ => Err(format!("error: not found, {:?}", key)),
+            },
+            _ => Err("error: Lookup expects (string, number)".to_string()),
         });
+        let functions = vec![lookup, Function::new("Add", 2, |args| args[0] + args[1])];
+        let str = |s: &str| Value::Str(s.to_string());
+        let lookup = Value::Function("Lookup".to_string(), 2);
+
+        let success_data = [
+            // Lookup("region", 3)
+            (
+                vec![str("region"), Value::Number(3.0), lookup.clone()],
+                Evaluated::Str("tokyo".to_string()),
+            ),
+            // Add(Lookup("rate", 3), 1)
+            (
+                vec![
+                    str("rate"),
+                    Value::Number(3.0),
+                    lookup.clone(),
+                    Value::Number(1.0),
+                    Value::Function("Add".to_string(), 2),
+                ],
+                Evaluated::Number(1.1),
+            ),
+        ];
+        for (values, expected) in success_data {
+            assert_eq!(
+                Processor::new(&values, &functions, &[]).execute_evaluated(),
+                Ok(expected)
+            );
+        }
+
+        let failure_data = [
+            // Lookup("city", 3)
+            vec![str("city"), Value::Number(3.0), lookup.clone()],
+            // Lookup(3, "region")
+            vec![Value::Number(3.0), str("region"), lookup.clone()],
+            // Add(Lookup("region", 3), 1)
+            vec![
+                str("region"),
+                Value::Number(3.0),
+                lookup,
+                Value::Number(1.0),
+                Value::Function("Add".to_string(), 2),
+            ],
+        ];
+        for values in failure_data {
+            assert!(Processor::new(&values, &functions, &[])
+                .execute_evaluated()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_typed_result() {
+        let success_data = [
+            // 2 * 2
+            (
+                vec![Value::Number(2.0), Value::Number(2.0), Value::Asterisk],
+                TypedResult::Integer(4),
+            ),
+            // 1 / 3
+            (
+                vec![Value::Number(1.0), Value::Number(3.0), Value::Slash],
+                TypedResult::Float(1.0 / 3.0),
+            ),
+            // 0 - 0.5 * 4
+            (
+                vec![
+                    Value::Number(0.0),
+                    Value::Number(0.5),
+                    Value::Number(4.0),
+                    Value::Asterisk,
+                    Value::Minus,
+                ],
+                TypedResult::Integer(-2),
+            ),
+            // 1 / 0
+            (
+                vec![Value::Number(1.0), Value::Number(0.0), Value::Slash],
+                TypedResult::Float(f64::INFINITY),
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(
+                Processor::new(&input, &[], &[])
+                    .execute()
+                    .map(TypedResult::from),
+                Ok(expected)
+            );
+        }
+
+        // i64 の範囲外の整数は Float とする
+        assert_eq!(
+            TypedResult::from(-9223372036854775808.0),
+            TypedResult::Integer(i64::MIN)
+        );
+        assert_eq!(
+            TypedResult::from(9223372036854775808.0),
+            TypedResult::Float(9223372036854775808.0)
+        );
+        assert!(matches!(TypedResult::from(f64::NAN), TypedResult::Float(x) if x.is_nan()));
     }
 }
@@ -0,0 +1,378 @@
+// 木構造 (Expr) に対する記号的な操作
+
+use crate::error::{ErrorCode, Subject};
+use crate::parser::{Expr, Value};
+use crate::processor::Processor;
+
+#[derive(Debug, PartialEq)]
+pub struct SymbolicError<'e> {
+    code: ErrorCode,
+    subject: Subject<'e>,
+}
+
+impl<'e> SymbolicError<'e> {
+    fn new(code: ErrorCode, subject: Subject<'e>) -> SymbolicError<'e> {
+        SymbolicError { code, subject }
+    }
+
+    /// エラーメッセージを生成する
+    pub fn message(&self) -> String {
+        self.code.message(self.subject)
+    }
+}
+
+/// 式 `expr` を変数 `var` について微分する
+///
+/// 四則演算・べき乗と、予約関数 `Sin`, `Cos`, `Exp`, `Ln` に対応している。
+/// 微分できない演算子 (比較演算子など) や関数を含む場合はエラーとする
+pub fn differentiate<'e>(expr: &'e Expr, var: &str) -> Result<Expr, SymbolicError<'e>> {
+    match expr {
+        Expr::Num(_) => Ok(Expr::Num(0.0)),
+        Expr::Var(v) => Ok(Expr::Num(if v == var { 1.0 } else { 0.0 })),
+        Expr::UnOp(Value::Minus, u) => Ok(neg(differentiate(u, var)?)),
+        Expr::BinOp(operator, u, v) => {
+            let (u, v) = (u.as_ref(), v.as_ref());
+            let (du, dv) = (differentiate(u, var)?, differentiate(v, var)?);
+            match operator {
+                Value::Plus => Ok(add(du, dv)),
+                Value::Minus => Ok(sub(du, dv)),
+                // (uv)' = u'v + uv'
+                Value::Asterisk => Ok(add(mul(du, v.clone()), mul(u.clone(), dv))),
+                // (u/v)' = (u'v - uv') / v^2
+                Value::Slash => Ok(div(
+                    sub(mul(du, v.clone()), mul(u.clone(), dv)),
+                    mul(v.clone(), v.clone()),
+                )),
+                Value::Power => {
+                    if dv == Expr::Num(0.0) {
+                        // (u^c)' = c * u^(c - 1) * u'
+                        Ok(mul(
+                            mul(v.clone(), pow(u.clone(), sub(v.clone(), Expr::Num(1.0)))),
+                            du,
+                        ))
+                    } else {
+                        // (u^v)' = u^v * (v' * ln(u) + v * u' / u)
+                        Ok(mul(
+                            expr.clone(),
+                            add(
+                                mul(dv, call("Ln", u.clone())),
+                                div(mul(v.clone(), du), u.clone()),
+                            ),
+                        ))
+                    }
+                }
+                _ => Err(SymbolicError::new(
+                    ErrorCode::OperatorNotDifferentiable,
+                    Subject::Value(operator),
+                )),
+            }
+        }
+        Expr::Call(f, args) if args.len() == 1 => {
+            let u = &args[0];
+            // 合成関数の微分 f(u)' = f'(u) * u'
+            let df = match f.as_str() {
+                "Sin" => call("Cos", u.clone()),
+                "Cos" => neg(call("Sin", u.clone())),
+                "Exp" => call("Exp", u.clone()),
+                "Ln" => div(Expr::Num(1.0), u.clone()),
+                _ => {
+                    return Err(SymbolicError::new(
+                        ErrorCode::FunctionNotDifferentiable,
+                        Subject::Text(f),
+                    ))
+                }
+            };
+            Ok(mul(df, differentiate(u, var)?))
+        }
+        Expr::Call(f, _) | Expr::KeywordCall(f, _) => Err(SymbolicError::new(
+            ErrorCode::FunctionNotDifferentiable,
+            Subject::Text(f),
+        )),
+        Expr::UnOp(operator, _) => Err(SymbolicError::new(
+            ErrorCode::OperatorNotDifferentiable,
+            Subject::Value(operator),
+        )),
+    }
+}
+
+/// 式を簡約する
+///
+/// 部分式ごとに以下の書き換えを行う (式全体の正規化は行わない)
+///
+/// - 数値のみからなる二項演算の計算 (結果が有限の値となる場合のみ)
+/// - `x + 0 = x`, `x - 0 = x`, `x * 1 = x`, `x * 0 = 0`, `x / 1 = x`, `x ** 1 = x`, `x ** 0 = 1`
+/// - 同じ項の結合 (`x + x = 2 * x`, `2 * x + 3 * x = 5 * x`, `x - x = 0`)
+pub fn simplify(expr: Expr) -> Expr {
+    match expr {
+        Expr::BinOp(operator, lhs, rhs) => {
+            simplify_binary_operator(operator, simplify(*lhs), simplify(*rhs))
+        }
+        Expr::UnOp(Value::Minus, expr) => neg(simplify(*expr)),
+        Expr::UnOp(operator, expr) => Expr::UnOp(operator, Box::new(simplify(*expr))),
+        Expr::Call(f, args) => Expr::Call(f, args.into_iter().map(simplify).collect()),
+        Expr::KeywordCall(f, args) => Expr::KeywordCall(
+            f,
+            args.into_iter()
+                .map(|(name, arg)| (name, simplify(arg)))
+                .collect(),
+        ),
+        expr => expr,
+    }
+}
+
+fn simplify_binary_operator(operator: Value, lhs: Expr, rhs: Expr) -> Expr {
+    // 数値同士の演算は計算する
+    if let (Expr::Num(x), Expr::Num(y)) = (&lhs, &rhs) {
+        if let Ok(result) = Processor::calc(*x, *y, &operator) {
+            if result.is_finite() {
+                return Expr::Num(result);
+            }
+        }
+    }
+
+    match operator {
+        Value::Plus | Value::Minus => {
+            let sign = if operator == Value::Plus { 1.0 } else { -1.0 };
+            let ((c1, x1), (c2, x2)) = (coefficient(&lhs), coefficient(&rhs));
+            if x1 == x2 && !matches!(x1, Expr::Num(_)) {
+                return mul(Expr::Num(c1 + sign * c2), x1.clone());
+            }
+
+            if operator == Value::Plus {
+                add(lhs, rhs)
+            } else {
+                sub(lhs, rhs)
+            }
+        }
+        Value::Asterisk => match (lhs, rhs) {
+            // c1 * (c2 * x) = (c1 * c2) * x
+            (Expr::Num(c1), Expr::BinOp(Value::Asterisk, l, r)) => match *l {
+                Expr::Num(c2) => mul(Expr::Num(c1 * c2), *r),
+                l => mul(Expr::Num(c1), Expr::BinOp(Value::Asterisk, Box::new(l), r)),
+            },
+            (lhs, rhs) => mul(lhs, rhs),
+        },
+        Value::Slash => div(lhs, rhs),
+        Value::Power => match rhs {
+            Expr::Num(0.0) => Expr::Num(1.0),
+            rhs => pow(lhs, rhs),
+        },
+        operator => Expr::BinOp(operator, Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+/// 式を係数とそれ以外に分ける (e.g. `3 * x` → (3, x), `-x` → (-1, x))
+fn coefficient(expr: &Expr) -> (f64, &Expr) {
+    match expr {
+        Expr::BinOp(Value::Asterisk, l, r) => match (l.as_ref(), r.as_ref()) {
+            (Expr::Num(c), x) | (x, Expr::Num(c)) => (*c, x),
+            _ => (1.0, expr),
+        },
+        Expr::UnOp(Value::Minus, x) => (-1.0, x),
+        _ => (1.0, expr),
+    }
+}
+
+// 以下は 0 と 1 を含む自明な式をその場で取り除きながら式を組み立てる
+
+fn add(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (Expr::Num(0.0), b) => b,
+        (a, Expr::Num(0.0)) => a,
+        (a, b) => Expr::BinOp(Value::Plus, Box::new(a), Box::new(b)),
+    }
+}
+
+fn sub(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (a, Expr::Num(0.0)) => a,
+        (Expr::Num(0.0), b) => neg(b),
+        (a, b) => Expr::BinOp(Value::Minus, Box::new(a), Box::new(b)),
+    }
+}
+
+fn mul(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (Expr::Num(0.0), _) | (_, Expr::Num(0.0)) => Expr::Num(0.0),
+        (Expr::Num(1.0), b) => b,
+        (a, Expr::Num(1.0)) => a,
+        (a, b) => Expr::BinOp(Value::Asterisk, Box::new(a), Box::new(b)),
+    }
+}
+
+fn div(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (a, Expr::Num(1.0)) => a,
+        (a, b) => Expr::BinOp(Value::Slash, Box::new(a), Box::new(b)),
+    }
+}
+
+fn pow(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (a, Expr::Num(1.0)) => a,
+        (a, b) => Expr::BinOp(Value::Power, Box::new(a), Box::new(b)),
+    }
+}
+
+fn neg(a: Expr) -> Expr {
+    match a {
+        Expr::Num(x) => Expr::Num(-x),
+        Expr::UnOp(Value::Minus, a) => *a,
+        a => Expr::UnOp(Value::Minus, Box::new(a)),
+    }
+}
+
+fn call(f: &str, arg: Expr) -> Expr {
+    Expr::Call(f.to_string(), vec![arg])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str) -> Expr {
+        Expr::Var(name.to_string())
+    }
+
+    #[test]
+    fn test_differentiate() {
+        let success_data = [
+            (Expr::Num(3.0), Expr::Num(0.0)),
+            (var("x"), Expr::Num(1.0)),
+            (var("y"), Expr::Num(0.0)),
+            // x * x → x + x
+            (mul(var("x"), var("x")), add(var("x"), var("x"))),
+            // Sin(x) → Cos(x)
+            (call("Sin", var("x")), call("Cos", var("x"))),
+            // Cos(2 * x) → -Sin(2 * x) * 2
+            (
+                call("Cos", mul(Expr::Num(2.0), var("x"))),
+                mul(
+                    neg(call("Sin", mul(Expr::Num(2.0), var("x")))),
+                    Expr::Num(2.0),
+                ),
+            ),
+            // x ** 3 → 3 * x ** (3 - 1)
+            (
+                pow(var("x"), Expr::Num(3.0)),
+                mul(
+                    Expr::Num(3.0),
+                    pow(var("x"), sub(Expr::Num(3.0), Expr::Num(1.0))),
+                ),
+            ),
+            // Ln(x) → 1 / x
+            (call("Ln", var("x")), div(Expr::Num(1.0), var("x"))),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(differentiate(&input, "x"), Ok(expected));
+        }
+
+        let failure_data = [
+            Expr::BinOp(
+                Value::LessThan,
+                Box::new(var("x")),
+                Box::new(Expr::Num(1.0)),
+            ),
+            Expr::BinOp(Value::Percent, Box::new(var("x")), Box::new(Expr::Num(2.0))),
+            call("Round", var("x")),
+            Expr::Call("Add".to_string(), vec![var("x"), Expr::Num(1.0)]),
+        ];
+        for input in failure_data {
+            assert!(differentiate(&input, "x").is_err());
+        }
+    }
+
+    #[test]
+    fn test_simplify() {
+        let x = || var("x");
+        let bin = |operator: Value, lhs: Expr, rhs: Expr| {
+            Expr::BinOp(operator, Box::new(lhs), Box::new(rhs))
+        };
+
+        let success_data = [
+            // 0 * x + x * 1 → x
+            (
+                bin(
+                    Value::Plus,
+                    bin(Value::Asterisk, Expr::Num(0.0), x()),
+                    bin(Value::Asterisk, x(), Expr::Num(1.0)),
+                ),
+                x(),
+            ),
+            // 2 + 3 * x → そのまま
+            (
+                bin(
+                    Value::Plus,
+                    Expr::Num(2.0),
+                    bin(Value::Asterisk, Expr::Num(3.0), x()),
+                ),
+                bin(
+                    Value::Plus,
+                    Expr::Num(2.0),
+                    bin(Value::Asterisk, Expr::Num(3.0), x()),
+                ),
+            ),
+            // (1 + 2) * x → 3 * x
+            (
+                bin(
+                    Value::Asterisk,
+                    bin(Value::Plus, Expr::Num(1.0), Expr::Num(2.0)),
+                    x(),
+                ),
+                bin(Value::Asterisk, Expr::Num(3.0), x()),
+            ),
+            // x + x → 2 * x
+            (
+                bin(Value::Plus, x(), x()),
+                bin(Value::Asterisk, Expr::Num(2.0), x()),
+            ),
+            // 2 * x - x → x
+            (
+                bin(Value::Minus, bin(Value::Asterisk, Expr::Num(2.0), x()), x()),
+                x(),
+            ),
+            // Sin(x) - Sin(x) → 0
+            (
+                bin(Value::Minus, call("Sin", x()), call("Sin", x())),
+                Expr::Num(0.0),
+            ),
+            // 2 * (3 * x) → 6 * x
+            (
+                bin(
+                    Value::Asterisk,
+                    Expr::Num(2.0),
+                    bin(Value::Asterisk, Expr::Num(3.0), x()),
+                ),
+                bin(Value::Asterisk, Expr::Num(6.0), x()),
+            ),
+            // x ** (3 - 3) → 1
+            (
+                bin(
+                    Value::Power,
+                    x(),
+                    bin(Value::Minus, Expr::Num(3.0), Expr::Num(3.0)),
+                ),
+                Expr::Num(1.0),
+            ),
+            // Cos(0 - x) → Cos(-x)
+            (
+                call("Cos", bin(Value::Minus, Expr::Num(0.0), x())),
+                call("Cos", neg(x())),
+            ),
+            // 1 / 0 は計算しない
+            (
+                bin(Value::Slash, Expr::Num(1.0), Expr::Num(0.0)),
+                bin(Value::Slash, Expr::Num(1.0), Expr::Num(0.0)),
+            ),
+        ];
+        for (input, expected) in success_data {
+            assert_eq!(simplify(input), expected);
+        }
+
+        // x * x を微分して簡約すると 2 * x になる
+        assert_eq!(
+            simplify(differentiate(&bin(Value::Asterisk, x(), x()), "x").unwrap()),
+            bin(Value::Asterisk, Expr::Num(2.0), x())
+        );
+    }
+}